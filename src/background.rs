@@ -0,0 +1,175 @@
+//! Keying of the flat background that image generators often put behind sprites.
+
+use crate::{PixelSnapperError, Result};
+use image::{Rgba, RgbaImage};
+
+/// Background color made transparent by [`Snapper::key_background`](crate::Snapper::key_background).
+/// Only pixels connected to the image border are keyed, so matching colors inside the sprite
+/// are kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackgroundKey {
+    /// The color shared by most of the image border. Nothing is keyed when no color
+    /// covers at least half of it, like on an already transparent image.
+    Auto,
+    /// A known background color.
+    Color([u8; 3]),
+}
+
+impl std::str::FromStr for BackgroundKey {
+    type Err = PixelSnapperError;
+
+    fn from_str(value: &str) -> Result<Self> {
+        if value == "auto" {
+            return Ok(BackgroundKey::Auto);
+        }
+        match crate::parse_palette_hex(value).as_deref() {
+            Ok([color]) => Ok(BackgroundKey::Color(*color)),
+            _ => Err(PixelSnapperError::InvalidInput(format!(
+                "invalid background key '{}': expected auto or #RRGGBB",
+                value
+            ))),
+        }
+    }
+}
+
+/// Makes the pixels of `img` within `tolerance` of the `key` color, and connected to the
+/// border through such pixels, fully transparent. Returns `None` when there is no
+/// background to key.
+pub(crate) fn key_background(
+    img: &RgbaImage,
+    key: BackgroundKey,
+    tolerance: f32,
+) -> Option<RgbaImage> {
+    let (width, height) = img.dimensions();
+    if width == 0 || height == 0 {
+        return None;
+    }
+    let color = match key {
+        BackgroundKey::Color(color) => color,
+        BackgroundKey::Auto => border_color(img, tolerance)?,
+    };
+    // Already transparent pixels let the background flow around them
+    let is_background = |p: &Rgba<u8>| p[3] == 0 || distance(p, color) <= tolerance;
+
+    let mut keyed = img.clone();
+    let mut visited = vec![false; width as usize * height as usize];
+    let mut stack: Vec<(u32, u32)> = border(width, height).collect();
+    while let Some((x, y)) = stack.pop() {
+        let index = y as usize * width as usize + x as usize;
+        if visited[index] || !is_background(img.get_pixel(x, y)) {
+            continue;
+        }
+        visited[index] = true;
+        keyed.put_pixel(x, y, Rgba([0, 0, 0, 0]));
+
+        if x > 0 {
+            stack.push((x - 1, y));
+        }
+        if x + 1 < width {
+            stack.push((x + 1, y));
+        }
+        if y > 0 {
+            stack.push((x, y - 1));
+        }
+        if y + 1 < height {
+            stack.push((x, y + 1));
+        }
+    }
+    Some(keyed)
+}
+
+/// Dominant color of the border of `img`: the mean of the most common coarse color, kept
+/// when at least half of the border is within `tolerance` of it.
+fn border_color(img: &RgbaImage, tolerance: f32) -> Option<[u8; 3]> {
+    let (width, height) = img.dimensions();
+    let pixels: Vec<&Rgba<u8>> = border(width, height)
+        .map(|(x, y)| img.get_pixel(x, y))
+        .collect();
+
+    // Coarse buckets absorb the noise of compressed or generated backgrounds
+    let bucket = |p: &Rgba<u8>| [p[0] >> 4, p[1] >> 4, p[2] >> 4];
+    let mut counts = std::collections::HashMap::new();
+    for p in pixels.iter().filter(|p| p[3] != 0) {
+        *counts.entry(bucket(p)).or_insert(0usize) += 1;
+    }
+    let (&dominant, _) = counts.iter().max_by_key(|&(b, &count)| (count, *b))?;
+
+    let mut sum = [0u64; 3];
+    let mut members = 0;
+    for p in pixels.iter().filter(|p| p[3] != 0 && bucket(p) == dominant) {
+        for c in 0..3 {
+            sum[c] += p[c] as u64;
+        }
+        members += 1;
+    }
+    let color = sum.map(|s| ((s + members / 2) / members) as u8);
+
+    let matching = pixels
+        .iter()
+        .filter(|p| p[3] != 0 && distance(p, color) <= tolerance)
+        .count();
+    (matching * 2 >= pixels.len()).then_some(color)
+}
+
+fn distance(p: &Rgba<u8>, color: [u8; 3]) -> f32 {
+    (0..3)
+        .map(|c| (p[c] as f32 - color[c] as f32).powi(2))
+        .sum::<f32>()
+        .sqrt()
+}
+
+/// Coordinates of the border pixels of a `width` x `height` image, corners included once.
+fn border(width: u32, height: u32) -> impl Iterator<Item = (u32, u32)> {
+    let rows = (0..width).flat_map(move |x| {
+        let bottom = (height > 1).then_some((x, height - 1));
+        std::iter::once((x, 0)).chain(bottom)
+    });
+    let cols = (1..height.saturating_sub(1)).flat_map(move |y| {
+        let right = (width > 1).then_some((width - 1, y));
+        std::iter::once((0, y)).chain(right)
+    });
+    rows.chain(cols)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: Rgba<u8> = Rgba([250, 252, 248, 255]);
+    const INK: Rgba<u8> = Rgba([30, 20, 60, 255]);
+
+    /// White background around an ink ring that encloses more white.
+    fn ring() -> RgbaImage {
+        RgbaImage::from_fn(9, 9, |x, y| {
+            let (dx, dy) = (x.abs_diff(4), y.abs_diff(4));
+            if dx.max(dy) == 2 {
+                INK
+            } else {
+                WHITE
+            }
+        })
+    }
+
+    #[test]
+    fn keys_the_border_connected_background() {
+        let keyed = key_background(&ring(), BackgroundKey::Auto, 32.0).unwrap();
+        assert_eq!(keyed.get_pixel(0, 0).0, [0, 0, 0, 0]);
+        assert_eq!(*keyed.get_pixel(2, 4), INK);
+        // White enclosed by the ring is part of the sprite
+        assert_eq!(*keyed.get_pixel(4, 4), WHITE);
+
+        let explicit = key_background(&ring(), BackgroundKey::Color([255; 3]), 32.0).unwrap();
+        assert_eq!(explicit, keyed);
+        let mismatch = key_background(&ring(), BackgroundKey::Color([255, 0, 255]), 32.0).unwrap();
+        assert_eq!(mismatch, ring());
+    }
+
+    #[test]
+    fn finds_no_background_on_a_busy_border() {
+        let colors = [WHITE, INK, Rgba([200, 40, 40, 255])];
+        let img = RgbaImage::from_fn(6, 6, |x, y| colors[((x + y) % 3) as usize]);
+        assert!(key_background(&img, BackgroundKey::Auto, 32.0).is_none());
+        assert_eq!(border(6, 6).count(), 20);
+        assert_eq!(border(1, 3).count(), 3);
+    }
+}
@@ -0,0 +1,205 @@
+//! Color spaces used to measure distances between colors during quantization and palette
+//! matching.
+
+use crate::{PixelSnapperError, Result};
+
+/// Color space in which colors are clustered and compared.
+///
+/// Every space is scaled so its components span roughly 0 to 255 like sRGB, which keeps the
+/// k-means convergence threshold meaningful.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorSpace {
+    /// Raw gamma-encoded sRGB values.
+    #[default]
+    Srgb,
+    /// sRGB with the gamma curve removed. Blends like light does, but still spends most of its
+    /// precision on bright colors.
+    LinearRgb,
+    /// CIE L*a*b* with a D65 white point.
+    Lab,
+    /// Oklab, a more uniform perceptual space that keeps hue steady across shading ramps.
+    Oklab,
+}
+
+impl std::str::FromStr for ColorSpace {
+    type Err = PixelSnapperError;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "srgb" => Ok(ColorSpace::Srgb),
+            "linear" => Ok(ColorSpace::LinearRgb),
+            "lab" => Ok(ColorSpace::Lab),
+            "oklab" => Ok(ColorSpace::Oklab),
+            _ => Err(PixelSnapperError::InvalidInput(format!(
+                "invalid color space '{}': expected srgb, linear, lab or oklab",
+                value
+            ))),
+        }
+    }
+}
+
+impl ColorSpace {
+    /// Converts an sRGB color to a point of this space.
+    pub(crate) fn encode(self, rgb: [u8; 3]) -> [f32; 3] {
+        match self {
+            ColorSpace::Srgb => [rgb[0] as f32, rgb[1] as f32, rgb[2] as f32],
+            ColorSpace::LinearRgb => linear_rgb(rgb).map(|c| c * 255.0),
+            ColorSpace::Lab => {
+                let [x, y, z] = xyz(linear_rgb(rgb));
+                let f = |t: f32| {
+                    if t > LAB_EPSILON {
+                        t.cbrt()
+                    } else {
+                        (LAB_KAPPA * t + 16.0) / 116.0
+                    }
+                };
+                let (fx, fy, fz) = (f(x / WHITE[0]), f(y / WHITE[1]), f(z / WHITE[2]));
+                // L* spans 0 to 100
+                [
+                    (116.0 * fy - 16.0) * 2.55,
+                    500.0 * (fx - fy) * 2.55,
+                    200.0 * (fy - fz) * 2.55,
+                ]
+            }
+            ColorSpace::Oklab => {
+                let [r, g, b] = linear_rgb(rgb);
+                let l = (0.412_221_46 * r + 0.536_332_55 * g + 0.051_445_995 * b).cbrt();
+                let m = (0.211_903_5 * r + 0.680_699_5 * g + 0.107_396_96 * b).cbrt();
+                let s = (0.088_302_46 * r + 0.281_718_85 * g + 0.629_978_7 * b).cbrt();
+                [
+                    (0.210_454_26 * l + 0.793_617_8 * m - 0.004_072_047 * s) * 255.0,
+                    (1.977_998_5 * l - 2.428_592_2 * m + 0.450_593_7 * s) * 255.0,
+                    (0.025_904_037 * l + 0.782_771_77 * m - 0.808_675_77 * s) * 255.0,
+                ]
+            }
+        }
+    }
+
+    /// Converts a point of this space back to the nearest sRGB color.
+    pub(crate) fn decode(self, point: [f32; 3]) -> [u8; 3] {
+        let linear = match self {
+            ColorSpace::Srgb => return point.map(|c| c.round() as u8),
+            ColorSpace::LinearRgb => point.map(|c| c / 255.0),
+            ColorSpace::Lab => {
+                let [l, a, b] = point.map(|c| c / 2.55);
+                let fy = (l + 16.0) / 116.0;
+                let fx = fy + a / 500.0;
+                let fz = fy - b / 200.0;
+                let f_inv = |t: f32| {
+                    if t.powi(3) > LAB_EPSILON {
+                        t.powi(3)
+                    } else {
+                        (116.0 * t - 16.0) / LAB_KAPPA
+                    }
+                };
+                let xyz = [
+                    f_inv(fx) * WHITE[0],
+                    f_inv(fy) * WHITE[1],
+                    f_inv(fz) * WHITE[2],
+                ];
+                [
+                    3.240_454_2 * xyz[0] - 1.537_138_5 * xyz[1] - 0.498_531_4 * xyz[2],
+                    -0.969_266 * xyz[0] + 1.876_010_8 * xyz[1] + 0.041_556 * xyz[2],
+                    0.055_643_4 * xyz[0] - 0.204_025_9 * xyz[1] + 1.057_225_2 * xyz[2],
+                ]
+            }
+            ColorSpace::Oklab => {
+                let [l, a, b] = point.map(|c| c / 255.0);
+                let l_ = (l + 0.396_337_78 * a + 0.215_803_76 * b).powi(3);
+                let m_ = (l - 0.105_561_346 * a - 0.063_854_17 * b).powi(3);
+                let s_ = (l - 0.089_484_18 * a - 1.291_485_5 * b).powi(3);
+                [
+                    4.076_741_7 * l_ - 3.307_711_6 * m_ + 0.230_969_94 * s_,
+                    -1.268_438 * l_ + 2.609_757_4 * m_ - 0.341_319_38 * s_,
+                    -0.004_196_086_3 * l_ - 0.703_418_6 * m_ + 1.707_614_7 * s_,
+                ]
+            }
+        };
+        linear.map(|c| (encode_gamma(c.clamp(0.0, 1.0)) * 255.0).round() as u8)
+    }
+}
+
+/// Squared Euclidean distance between two points of the same space.
+pub(crate) fn dist_sq(p: &[f32; 3], c: &[f32; 3]) -> f32 {
+    let dr = p[0] - c[0];
+    let dg = p[1] - c[1];
+    let db = p[2] - c[2];
+    dr * dr + dg * dg + db * db
+}
+
+/// D65 reference white in XYZ.
+const WHITE: [f32; 3] = [0.950_47, 1.0, 1.088_83];
+const LAB_EPSILON: f32 = 216.0 / 24389.0;
+const LAB_KAPPA: f32 = 24389.0 / 27.0;
+
+fn linear_rgb(rgb: [u8; 3]) -> [f32; 3] {
+    rgb.map(|c| decode_gamma(c as f32 / 255.0))
+}
+
+fn decode_gamma(c: f32) -> f32 {
+    if c <= 0.040_45 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn encode_gamma(c: f32) -> f32 {
+    if c <= 0.003_130_8 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+fn xyz([r, g, b]: [f32; 3]) -> [f32; 3] {
+    [
+        0.412_456_4 * r + 0.357_576_1 * g + 0.180_437_5 * b,
+        0.212_672_9 * r + 0.715_152_2 * g + 0.072_175 * b,
+        0.019_333_9 * r + 0.119_192 * g + 0.950_304_1 * b,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conversions_round_trip() {
+        let spaces = [
+            ColorSpace::Srgb,
+            ColorSpace::LinearRgb,
+            ColorSpace::Lab,
+            ColorSpace::Oklab,
+        ];
+        for space in spaces {
+            for rgb in [
+                [0, 0, 0],
+                [255, 255, 255],
+                [13, 43, 69],
+                [255, 236, 214],
+                [200, 60, 60],
+            ] {
+                assert_eq!(space.decode(space.encode(rgb)), rgb, "{:?}", space);
+            }
+        }
+    }
+
+    #[test]
+    fn perceptual_spaces_weigh_dark_shades_more() {
+        // Two dark blues a shading ramp needs apart, and two light grays that look alike
+        let ratio = |space: ColorSpace| {
+            let dark = dist_sq(&space.encode([10, 10, 30]), &space.encode([25, 25, 55]));
+            let light = dist_sq(
+                &space.encode([225, 225, 225]),
+                &space.encode([250, 250, 250]),
+            );
+            dark / light
+        };
+        let srgb = ratio(ColorSpace::Srgb);
+        assert!(srgb < 1.0);
+        for space in [ColorSpace::Lab, ColorSpace::Oklab] {
+            assert!(ratio(space) > srgb * 1.5, "{:?}", space);
+        }
+    }
+}
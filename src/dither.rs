@@ -0,0 +1,247 @@
+//! Ordered and error-diffusion dithering used when mapping an image onto a fixed palette.
+
+use crate::{color::dist_sq, ColorSpace, PixelSnapperError, Result};
+use image::{Rgba, RgbaImage};
+
+/// Dithering applied by [`Snapper::recolor`](crate::Snapper::recolor) when mapping the
+/// snapped image onto the configured palette.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Dither {
+    /// Every pixel takes its nearest palette color.
+    #[default]
+    None,
+    /// Ordered dithering with a 2x2 Bayer matrix.
+    Bayer2,
+    /// Ordered dithering with a 4x4 Bayer matrix.
+    Bayer4,
+    /// Ordered dithering with an 8x8 Bayer matrix.
+    Bayer8,
+    /// Error diffusion to four neighbors.
+    FloydSteinberg,
+    /// Error diffusion of 3/4 of the error to six neighbors, keeping more contrast.
+    Atkinson,
+    /// Error diffusion to ten neighbors over three rows.
+    Sierra,
+}
+
+impl std::str::FromStr for Dither {
+    type Err = PixelSnapperError;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "none" => Ok(Dither::None),
+            "bayer2" => Ok(Dither::Bayer2),
+            "bayer4" => Ok(Dither::Bayer4),
+            "bayer8" => Ok(Dither::Bayer8),
+            "floyd-steinberg" => Ok(Dither::FloydSteinberg),
+            "atkinson" => Ok(Dither::Atkinson),
+            "sierra" => Ok(Dither::Sierra),
+            _ => Err(PixelSnapperError::InvalidInput(format!(
+                "invalid dither '{}': expected none, bayer2, bayer4, bayer8, floyd-steinberg, atkinson or sierra",
+                value
+            ))),
+        }
+    }
+}
+
+/// Error diffusion kernel as `(dx, dy, weight)` entries, along with the weights divisor.
+type Kernel = (&'static [(i32, u32, f32)], f32);
+
+const FLOYD_STEINBERG: Kernel = (&[(1, 0, 7.0), (-1, 1, 3.0), (0, 1, 5.0), (1, 1, 1.0)], 16.0);
+const ATKINSON: Kernel = (
+    &[
+        (1, 0, 1.0),
+        (2, 0, 1.0),
+        (-1, 1, 1.0),
+        (0, 1, 1.0),
+        (1, 1, 1.0),
+        (0, 2, 1.0),
+    ],
+    8.0,
+);
+const SIERRA: Kernel = (
+    &[
+        (1, 0, 5.0),
+        (2, 0, 3.0),
+        (-2, 1, 2.0),
+        (-1, 1, 4.0),
+        (0, 1, 5.0),
+        (1, 1, 4.0),
+        (2, 1, 2.0),
+        (-1, 2, 2.0),
+        (0, 2, 3.0),
+        (1, 2, 2.0),
+    ],
+    32.0,
+);
+
+/// Maps the opaque pixels of `img` onto `palette` with `dither`, comparing and diffusing
+/// colors in `space`. Transparent pixels are kept and neither take nor spread any error.
+pub(crate) fn dither_palette(
+    img: &RgbaImage,
+    palette: &[[u8; 3]],
+    space: ColorSpace,
+    dither: Dither,
+) -> RgbaImage {
+    let points: Vec<[f32; 3]> = palette.iter().map(|c| space.encode(*c)).collect();
+    let nearest = |point: &[f32; 3]| {
+        (0..points.len())
+            .min_by(|&a, &b| dist_sq(point, &points[a]).total_cmp(&dist_sq(point, &points[b])))
+            .unwrap()
+    };
+
+    match dither {
+        Dither::Bayer2 => ordered(img, &points, palette, space, 2, nearest),
+        Dither::Bayer4 => ordered(img, &points, palette, space, 4, nearest),
+        Dither::Bayer8 => ordered(img, &points, palette, space, 8, nearest),
+        Dither::FloydSteinberg => diffuse(img, &points, palette, space, FLOYD_STEINBERG, nearest),
+        Dither::Atkinson => diffuse(img, &points, palette, space, ATKINSON, nearest),
+        Dither::Sierra => diffuse(img, &points, palette, space, SIERRA, nearest),
+        Dither::None => map_pixels(img, |_, _, rgb| palette[nearest(&space.encode(rgb))]),
+    }
+}
+
+fn map_pixels(img: &RgbaImage, mut f: impl FnMut(u32, u32, [u8; 3]) -> [u8; 3]) -> RgbaImage {
+    let mut out = img.clone();
+    for (x, y, pixel) in out.enumerate_pixels_mut() {
+        if pixel[3] != 0 {
+            let [r, g, b] = f(x, y, [pixel[0], pixel[1], pixel[2]]);
+            *pixel = Rgba([r, g, b, pixel[3]]);
+        }
+    }
+    out
+}
+
+fn ordered(
+    img: &RgbaImage,
+    points: &[[f32; 3]],
+    palette: &[[u8; 3]],
+    space: ColorSpace,
+    size: u32,
+    nearest: impl Fn(&[f32; 3]) -> usize,
+) -> RgbaImage {
+    let matrix = bayer_matrix(size);
+    let spread = palette_spread(points);
+    let cells = (size * size) as f32;
+    map_pixels(img, |x, y, rgb| {
+        let rank = matrix[((y % size) * size + x % size) as usize] as f32;
+        let offset = ((rank + 0.5) / cells - 0.5) * spread;
+        let point = space.encode(rgb).map(|c| c + offset);
+        palette[nearest(&point)]
+    })
+}
+
+/// Bayer threshold ranks of a `size` x `size` matrix, `size` being a power of two.
+fn bayer_matrix(size: u32) -> Vec<u32> {
+    if size == 1 {
+        return vec![0];
+    }
+    let half = size / 2;
+    let inner = bayer_matrix(half);
+    let mut matrix = vec![0; (size * size) as usize];
+    for y in 0..size {
+        for x in 0..size {
+            let quadrant = [0, 2, 3, 1][((y / half) * 2 + x / half) as usize];
+            let rank = inner[((y % half) * half + x % half) as usize];
+            matrix[(y * size + x) as usize] = 4 * rank + quadrant;
+        }
+    }
+    matrix
+}
+
+/// Mean distance from each palette color to its closest other color, the step that ordered
+/// dithering has to bridge, per channel since the threshold is added to all three.
+fn palette_spread(points: &[[f32; 3]]) -> f32 {
+    if points.len() < 2 {
+        return 0.0;
+    }
+    let total: f32 = points
+        .iter()
+        .enumerate()
+        .map(|(i, a)| {
+            points
+                .iter()
+                .enumerate()
+                .filter(|&(j, _)| j != i)
+                .map(|(_, b)| dist_sq(a, b))
+                .fold(f32::MAX, f32::min)
+                .sqrt()
+        })
+        .sum();
+    total / points.len() as f32 / 3f32.sqrt()
+}
+
+fn diffuse(
+    img: &RgbaImage,
+    points: &[[f32; 3]],
+    palette: &[[u8; 3]],
+    space: ColorSpace,
+    (kernel, divisor): Kernel,
+    nearest: impl Fn(&[f32; 3]) -> usize,
+) -> RgbaImage {
+    let (width, height) = img.dimensions();
+    let mut errors = vec![[0.0f32; 3]; (width * height) as usize];
+    map_pixels(img, |x, y, rgb| {
+        let error = errors[(y * width + x) as usize];
+        let encoded = space.encode(rgb);
+        let point = std::array::from_fn(|c| encoded[c] + error[c]);
+        let index = nearest(&point);
+        let residual: [f32; 3] = std::array::from_fn(|c| point[c] - points[index][c]);
+
+        for &(dx, dy, weight) in kernel {
+            let (nx, ny) = (x as i32 + dx, y + dy);
+            if nx < 0 || nx >= width as i32 || ny >= height {
+                continue;
+            }
+            // Transparent neighbors do not carry error further
+            if img.get_pixel(nx as u32, ny)[3] == 0 {
+                continue;
+            }
+            let target = &mut errors[(ny * width + nx as u32) as usize];
+            for c in 0..3 {
+                target[c] += residual[c] * weight / divisor;
+            }
+        }
+        palette[index]
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bayer_matrices_rank_every_cell_once() {
+        assert_eq!(bayer_matrix(2), [0, 2, 3, 1]);
+        for size in [4, 8] {
+            let mut ranks = bayer_matrix(size);
+            ranks.sort_unstable();
+            assert_eq!(ranks, (0..size * size).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn dithering_keeps_the_average_shade() {
+        let palette = [[0, 0, 0], [255, 255, 255]];
+        let img = RgbaImage::from_pixel(16, 16, Rgba([64, 64, 64, 255]));
+        let modes = [
+            Dither::Bayer2,
+            Dither::Bayer4,
+            Dither::Bayer8,
+            Dither::FloydSteinberg,
+            Dither::Sierra,
+        ];
+        for dither in modes {
+            let out = dither_palette(&img, &palette, ColorSpace::Srgb, dither);
+            let white = out.pixels().filter(|p| p[0] == 255).count() as f32;
+            let coverage = white / (16.0 * 16.0);
+            assert!((coverage - 0.25).abs() < 0.04, "{:?}: {}", dither, coverage);
+        }
+
+        // Atkinson drops a quarter of the error, which pushes shades towards the palette
+        let out = dither_palette(&img, &palette, ColorSpace::Srgb, Dither::Atkinson);
+        assert!(out.pixels().any(|p| p[0] == 255));
+        let flat = dither_palette(&img, &palette, ColorSpace::Srgb, Dither::None);
+        assert!(flat.pixels().all(|p| p[0] == 0));
+    }
+}
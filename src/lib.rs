@@ -1,8 +1,4 @@
-use image::{GenericImageView, ImageBuffer, Rgba, RgbaImage};
-use rand::prelude::*;
-use rand::SeedableRng;
-use rand_chacha::ChaCha8Rng;
-use rand_distr::{Distribution, WeightedIndex};
+use image::{DynamicImage, ImageBuffer, Rgba, RgbaImage};
 #[cfg(not(target_arch = "wasm32"))]
 use rayon::prelude::*;
 use std::cmp::Ordering;
@@ -11,20 +7,49 @@ use std::collections::HashMap;
 use std::env;
 use std::error::Error;
 use std::fmt;
+use std::hash::{BuildHasherDefault, Hasher};
 #[cfg(not(target_arch = "wasm32"))]
 use std::path::{Path, PathBuf};
 
 #[cfg(target_arch = "wasm32")]
 use wasm_bindgen::prelude::*;
 
+mod background;
+mod color;
+mod dither;
+mod json;
+mod named_palettes;
+mod overlay;
+mod palette;
+mod quantize;
+
+pub use background::BackgroundKey;
+use color::dist_sq;
+pub use color::ColorSpace;
+pub use dither::Dither;
+pub use named_palettes::{named_palette, NamedPalette, NAMED_PALETTES};
+pub use palette::{parse_palette, sorted_palette, write_palette, PaletteFormat, PaletteOrder};
+pub use quantize::{KMeans, MedianCut, Octree, Quantizer, QuantizerKind, Wu};
+
 const MAX_PALETTE_COLORS: usize = 256;
+const DEFAULT_MAX_AUTO_COLORS: usize = 32;
+/// Opaque pixels sampled from each image to build a shared palette.
+const SHARED_PALETTE_SAMPLES: usize = 16384;
+const DEFAULT_ALPHA_THRESHOLD: u8 = 128;
+const DEFAULT_ALPHA_LEVELS: u8 = 4;
+const DEFAULT_BACKGROUND_TOLERANCE: f32 = 32.0;
+#[cfg(not(target_arch = "wasm32"))]
+const LOW_CONFIDENCE_WARNING: f64 = 0.5;
 
 #[derive(Debug, Clone)]
 #[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
 pub struct Config {
     pub k_colors: usize,
     pub pixel_size_override: Option<f64>,
+    /// Row pixel size when it differs from `pixel_size_override`. Ignored without it.
+    pub pixel_size_override_y: Option<f64>,
     palette: Option<Vec<[u8; 3]>>,
+    dither: Dither,
     k_seed: u64,
     /// Input image path only used for CLI use
     #[allow(dead_code)]
@@ -32,15 +57,267 @@ pub struct Config {
     /// Output image path only used for CLI use
     #[allow(dead_code)]
     output_path: String,
+    /// Explicit cuts JSON path only used for CLI use
+    #[allow(dead_code)]
+    cuts_path: Option<String>,
+    /// Detected cuts JSON path only used for CLI use
+    #[allow(dead_code)]
+    cuts_export_path: Option<String>,
+    /// Debug overlay image path only used for CLI use
+    #[allow(dead_code)]
+    debug_overlay_path: Option<String>,
+    /// Palette export path only used for CLI use
+    #[allow(dead_code)]
+    palette_export_path: Option<String>,
+    /// Order of the exported palette only used for CLI use
+    #[allow(dead_code)]
+    palette_order: PaletteOrder,
+    /// Batch-wide palette only used for CLI use
+    #[allow(dead_code)]
+    shared_palette: bool,
+    cuts: Option<(Vec<usize>, Vec<usize>)>,
     max_kmeans_iterations: usize,
+    auto_colors: bool,
+    max_auto_colors: Option<usize>,
+    auto_color_tolerance: f64,
+    auto_color_min_gain: f64,
+    accent_min_cells: Option<usize>,
+    accent_distance: f32,
+    quantizer: QuantizerKind,
+    color_space: ColorSpace,
+    profile_mode: ProfileMode,
+    pipeline_order: PipelineOrder,
+    resample_mode: ResampleMode,
+    alpha_mode: AlphaMode,
+    background_key: Option<BackgroundKey>,
+    background_tolerance: f32,
+    step_estimator: StepEstimator,
     peak_threshold_multiplier: f64,
     peak_distance_filter: usize,
+    autocorrelation_peak_ratio: f64,
+    subpixel: bool,
     walker_search_window_ratio: f64,
     walker_min_search_window: f64,
     walker_strength_threshold: f64,
     min_cuts_per_axis: usize,
     fallback_target_segments: usize,
     max_step_ratio: f64,
+    grid_offset: Option<(f64, f64)>,
+    allow_non_square: bool,
+    non_square_min_ratio: f64,
+    non_square_max_peak_fraction: f64,
+    min_confidence: Option<f64>,
+}
+
+impl Config {
+    /// Constrains the output to `palette` after resampling.
+    pub fn with_palette(mut self, palette: Vec<[u8; 3]>) -> Self {
+        self.palette = Some(palette);
+        self
+    }
+
+    pub fn palette(&self) -> Option<&[[u8; 3]]> {
+        self.palette.as_deref()
+    }
+
+    /// Dithers the snapped image while mapping it onto the palette. Has no effect without
+    /// a palette.
+    pub fn with_dither(mut self, dither: Dither) -> Self {
+        self.dither = dither;
+        self
+    }
+
+    pub fn dither(&self) -> Dither {
+        self.dither
+    }
+
+    /// Picks the color count from the image instead of `k_colors`, up to `max` colors.
+    pub fn with_auto_colors(mut self, max: usize) -> Self {
+        self.auto_colors = true;
+        self.max_auto_colors = Some(max);
+        self
+    }
+
+    /// Maximum color count when the count is picked from the image.
+    pub fn auto_colors(&self) -> Option<usize> {
+        self.auto_colors
+            .then(|| self.max_auto_colors.unwrap_or(DEFAULT_MAX_AUTO_COLORS))
+    }
+
+    /// Keeps colors that cover at least `min_cells` grid cells even when quantization would
+    /// merge them into a bigger cluster, like a small eye or highlight.
+    pub fn with_accent_cells(mut self, min_cells: usize) -> Self {
+        self.accent_min_cells = Some(min_cells);
+        self
+    }
+
+    pub fn accent_cells(&self) -> Option<usize> {
+        self.accent_min_cells
+    }
+
+    pub fn with_quantizer(mut self, quantizer: QuantizerKind) -> Self {
+        self.quantizer = quantizer;
+        self
+    }
+
+    pub fn quantizer(&self) -> QuantizerKind {
+        self.quantizer
+    }
+
+    /// Color space used by the quantizer and by palette matching.
+    pub fn with_color_space(mut self, space: ColorSpace) -> Self {
+        self.color_space = space;
+        self
+    }
+
+    pub fn color_space(&self) -> ColorSpace {
+        self.color_space
+    }
+
+    pub fn with_profile_mode(mut self, mode: ProfileMode) -> Self {
+        self.profile_mode = mode;
+        self
+    }
+
+    pub fn profile_mode(&self) -> ProfileMode {
+        self.profile_mode
+    }
+
+    pub fn with_resample_mode(mut self, mode: ResampleMode) -> Self {
+        self.resample_mode = mode;
+        self
+    }
+
+    pub fn resample_mode(&self) -> ResampleMode {
+        self.resample_mode
+    }
+
+    /// Cleans up partly transparent pixels, like anti-aliased sprite edges, before snapping.
+    pub fn with_alpha_mode(mut self, mode: AlphaMode) -> Self {
+        self.alpha_mode = mode;
+        self
+    }
+
+    pub fn alpha_mode(&self) -> AlphaMode {
+        self.alpha_mode
+    }
+
+    /// Makes the flat background around the sprite transparent before snapping, so it takes
+    /// no palette slot and adds no edges to grid detection.
+    pub fn with_background_key(mut self, key: BackgroundKey) -> Self {
+        self.background_key = Some(key);
+        self
+    }
+
+    pub fn background_key(&self) -> Option<BackgroundKey> {
+        self.background_key
+    }
+
+    /// Largest RGB distance from the background color that is still keyed. Raise it for
+    /// noisy or gradient backgrounds.
+    pub fn with_background_tolerance(mut self, tolerance: f32) -> Result<Self> {
+        if !tolerance.is_finite() || tolerance < 0.0 {
+            return Err(PixelSnapperError::InvalidInput(format!(
+                "invalid background tolerance {}: expected a non-negative color distance",
+                tolerance
+            )));
+        }
+        self.background_tolerance = tolerance;
+        Ok(self)
+    }
+
+    pub fn background_tolerance(&self) -> f32 {
+        self.background_tolerance
+    }
+
+    pub fn with_pipeline_order(mut self, order: PipelineOrder) -> Self {
+        self.pipeline_order = order;
+        self
+    }
+
+    pub fn pipeline_order(&self) -> PipelineOrder {
+        self.pipeline_order
+    }
+
+    pub fn with_step_estimator(mut self, estimator: StepEstimator) -> Self {
+        self.step_estimator = estimator;
+        self
+    }
+
+    pub fn step_estimator(&self) -> StepEstimator {
+        self.step_estimator
+    }
+
+    /// Refines detected steps to fractional sizes and places cuts on a fixed lattice, so
+    /// images resized by non-integer factors do not drift.
+    pub fn with_subpixel(mut self, subpixel: bool) -> Self {
+        self.subpixel = subpixel;
+        self
+    }
+
+    pub fn subpixel(&self) -> bool {
+        self.subpixel
+    }
+
+    /// Lets grid detection keep different column and row steps when both profiles agree on
+    /// them, for art drawn with non-square pixels.
+    pub fn with_non_square(mut self, allow: bool) -> Self {
+        self.allow_non_square = allow;
+        self
+    }
+
+    pub fn allows_non_square(&self) -> bool {
+        self.allow_non_square
+    }
+
+    /// Starts the grid at `x` and `y` instead of 0. Offsets larger than a cell wrap around to
+    /// the step, so an offset of 10 with 8 pixel cells starts the grid at 2. Without it, the
+    /// offset is estimated from the image when a pixel size override is set, and left at 0
+    /// otherwise.
+    pub fn with_grid_offset(mut self, x: f64, y: f64) -> Self {
+        self.grid_offset = Some((x, y));
+        self
+    }
+
+    pub fn grid_offset(&self) -> Option<(f64, f64)> {
+        self.grid_offset
+    }
+
+    /// Uses these column and row cuts instead of detecting the grid. Cuts are clamped to the
+    /// image, sorted and completed with both borders.
+    pub fn with_cuts(mut self, col_cuts: Vec<usize>, row_cuts: Vec<usize>) -> Self {
+        self.cuts = Some((col_cuts, row_cuts));
+        self
+    }
+
+    pub fn cuts(&self) -> Option<(&[usize], &[usize])> {
+        self.cuts
+            .as_ref()
+            .map(|(cols, rows)| (cols.as_slice(), rows.as_slice()))
+    }
+
+    /// Column and row pixel size overrides, if any.
+    pub fn pixel_size_overrides(&self) -> Option<(f64, f64)> {
+        self.pixel_size_override
+            .map(|px| (px, self.pixel_size_override_y.unwrap_or(px)))
+    }
+
+    /// Makes [`Snapper::snap`] fail with [`PixelSnapperError::LowConfidence`] when the overall
+    /// grid confidence is below `threshold`, a value from 0 to 1.
+    pub fn with_min_confidence(mut self, threshold: f64) -> Result<Self> {
+        if !(0.0..=1.0).contains(&threshold) {
+            return Err(PixelSnapperError::InvalidInput(format!(
+                "invalid minimum confidence {}: expected a number between 0 and 1",
+                threshold
+            )));
+        }
+        self.min_confidence = Some(threshold);
+        Ok(self)
+    }
+
+    pub fn min_confidence(&self) -> Option<f64> {
+        self.min_confidence
+    }
 }
 
 impl Default for Config {
@@ -50,17 +327,48 @@ impl Default for Config {
             k_seed: 42,
             input_path: "samples/2/skeleton.png".to_string(),
             output_path: "samples/2/skeleton_fixed_clean2.png".to_string(),
+            cuts_path: None,
+            cuts_export_path: None,
+            debug_overlay_path: None,
+            palette_export_path: None,
+            palette_order: PaletteOrder::Frequency,
+            shared_palette: false,
+            cuts: None,
             max_kmeans_iterations: 15,
+            auto_colors: false,
+            max_auto_colors: None,
+            auto_color_tolerance: 8.0,
+            auto_color_min_gain: 0.1,
+            accent_min_cells: None,
+            accent_distance: 48.0,
+            quantizer: QuantizerKind::KMeans,
+            color_space: ColorSpace::Srgb,
+            profile_mode: ProfileMode::Luminance,
+            pipeline_order: PipelineOrder::QuantizeFirst,
+            resample_mode: ResampleMode::Majority,
+            alpha_mode: AlphaMode::Keep,
+            background_key: None,
+            background_tolerance: DEFAULT_BACKGROUND_TOLERANCE,
+            step_estimator: StepEstimator::Peaks,
             peak_threshold_multiplier: 0.2,
             peak_distance_filter: 4,
+            autocorrelation_peak_ratio: 0.5,
+            subpixel: false,
             walker_search_window_ratio: 0.35,
             walker_min_search_window: 2.0,
             walker_strength_threshold: 0.5,
             min_cuts_per_axis: 4,
             fallback_target_segments: 64,
             max_step_ratio: 1.8, // Lowered from 3.0 to catch more skew cases
+            grid_offset: None,
+            allow_non_square: false,
+            non_square_min_ratio: 1.25,
+            non_square_max_peak_fraction: 0.6,
             pixel_size_override: None,
+            pixel_size_override_y: None,
             palette: None,
+            dither: Dither::None,
+            min_confidence: None,
         }
     }
 }
@@ -70,6 +378,7 @@ pub enum PixelSnapperError {
     ImageError(image::ImageError),
     InvalidInput(String),
     ProcessingError(String),
+    LowConfidence { confidence: f64, threshold: f64 },
 }
 
 impl fmt::Display for PixelSnapperError {
@@ -78,6 +387,14 @@ impl fmt::Display for PixelSnapperError {
             PixelSnapperError::ImageError(e) => write!(f, "Image error: {}", e),
             PixelSnapperError::InvalidInput(msg) => write!(f, "Invalid input: {}", msg),
             PixelSnapperError::ProcessingError(msg) => write!(f, "Processing error: {}", msg),
+            PixelSnapperError::LowConfidence {
+                confidence,
+                threshold,
+            } => write!(
+                f,
+                "Low grid confidence: {:.2} is below the {:.2} threshold",
+                confidence, threshold
+            ),
         }
     }
 }
@@ -99,13 +416,12 @@ impl From<PixelSnapperError> for wasm_bindgen::JsValue {
 
 pub type Result<T> = std::result::Result<T, PixelSnapperError>;
 
-#[cfg_attr(target_arch = "wasm32", allow(dead_code))]
 struct ProcessedImage {
     output_bytes: Vec<u8>,
-    pixel_size: f64,
-    pixel_size_override: bool,
-    output_width: u32,
-    output_height: u32,
+    result: SnapResult,
+    /// Decoded input image only used for CLI use
+    #[allow(dead_code)]
+    input: RgbaImage,
 }
 
 #[cfg(not(target_arch = "wasm32"))]
@@ -115,7 +431,28 @@ pub struct BatchConfig {
     pub output_dir: PathBuf,
     pub k_colors: usize,
     pub pixel_size_override: Option<f64>,
+    pub pixel_size_override_y: Option<f64>,
+    pub grid_offset: Option<(f64, f64)>,
+    pub cuts: Option<(Vec<usize>, Vec<usize>)>,
+    pub auto_colors: Option<usize>,
+    pub accent_cells: Option<usize>,
+    pub quantizer: QuantizerKind,
+    pub color_space: ColorSpace,
+    pub profile_mode: ProfileMode,
+    pub pipeline_order: PipelineOrder,
+    pub resample_mode: ResampleMode,
+    pub alpha_mode: AlphaMode,
+    pub background_key: Option<BackgroundKey>,
+    pub background_tolerance: f32,
+    pub step_estimator: StepEstimator,
+    pub subpixel: bool,
+    pub allow_non_square: bool,
     pub palette: Option<Vec<[u8; 3]>>,
+    pub dither: Dither,
+    /// Builds one palette from all inputs and applies it to every image. Ignored when
+    /// `palette` is set.
+    pub shared_palette: bool,
+    pub min_confidence: Option<f64>,
 }
 
 #[cfg(not(target_arch = "wasm32"))]
@@ -126,7 +463,26 @@ impl From<&Config> for BatchConfig {
             output_dir: PathBuf::from(&config.output_path),
             k_colors: config.k_colors,
             pixel_size_override: config.pixel_size_override,
+            pixel_size_override_y: config.pixel_size_override_y,
+            grid_offset: config.grid_offset,
+            cuts: config.cuts.clone(),
+            auto_colors: config.auto_colors(),
+            accent_cells: config.accent_min_cells,
+            quantizer: config.quantizer,
+            color_space: config.color_space,
+            profile_mode: config.profile_mode,
+            pipeline_order: config.pipeline_order,
+            resample_mode: config.resample_mode,
+            alpha_mode: config.alpha_mode,
+            background_key: config.background_key,
+            background_tolerance: config.background_tolerance,
+            step_estimator: config.step_estimator,
+            subpixel: config.subpixel,
+            allow_non_square: config.allow_non_square,
             palette: config.palette.clone(),
+            dither: config.dither,
+            shared_palette: config.shared_palette,
+            min_confidence: config.min_confidence,
         }
     }
 }
@@ -137,7 +493,27 @@ impl From<&BatchConfig> for Config {
         Self {
             k_colors: config.k_colors,
             pixel_size_override: config.pixel_size_override,
+            pixel_size_override_y: config.pixel_size_override_y,
+            grid_offset: config.grid_offset,
+            cuts: config.cuts.clone(),
+            auto_colors: config.auto_colors.is_some(),
+            max_auto_colors: config.auto_colors,
+            accent_min_cells: config.accent_cells,
+            quantizer: config.quantizer,
+            color_space: config.color_space,
+            profile_mode: config.profile_mode,
+            pipeline_order: config.pipeline_order,
+            resample_mode: config.resample_mode,
+            alpha_mode: config.alpha_mode,
+            background_key: config.background_key,
+            background_tolerance: config.background_tolerance,
+            step_estimator: config.step_estimator,
+            subpixel: config.subpixel,
+            allow_non_square: config.allow_non_square,
             palette: config.palette.clone(),
+            dither: config.dither,
+            shared_palette: config.shared_palette,
+            min_confidence: config.min_confidence,
             ..Default::default()
         }
     }
@@ -158,6 +534,7 @@ pub enum BatchEvent {
     Finished {
         input: PathBuf,
         output: PathBuf,
+        confidence: f64,
         index: usize,
         total: usize,
     },
@@ -178,6 +555,8 @@ pub enum BatchEvent {
 #[derive(Debug)]
 enum CliCommand {
     Run(Config),
+    Analyze { config: Config, json: bool },
+    Palettes,
     Help,
     Version,
 }
@@ -197,6 +576,10 @@ pub fn run_cli() -> std::process::ExitCode {
             println!("spritefusion-pixel-snapper {}", env!("CARGO_PKG_VERSION"));
             std::process::ExitCode::SUCCESS
         }
+        Ok(CliCommand::Palettes) => {
+            print_named_palettes();
+            std::process::ExitCode::SUCCESS
+        }
         Ok(CliCommand::Run(config)) => match process(&config) {
             Ok(()) => std::process::ExitCode::SUCCESS,
             Err(error) => {
@@ -204,6 +587,13 @@ pub fn run_cli() -> std::process::ExitCode {
                 std::process::ExitCode::from(1)
             }
         },
+        Ok(CliCommand::Analyze { config, json }) => match analyze(&config, json) {
+            Ok(()) => std::process::ExitCode::SUCCESS,
+            Err(error) => {
+                eprintln!("Error: {error}");
+                std::process::ExitCode::from(1)
+            }
+        },
         Err(error) => {
             eprintln!("Error: {error}");
             eprintln!("Run 'spritefusion-pixel-snapper --help' for usage.");
@@ -212,6 +602,25 @@ pub fn run_cli() -> std::process::ExitCode {
     }
 }
 
+#[cfg(not(target_arch = "wasm32"))]
+fn print_named_palettes() {
+    println!("Named palettes for --palette:");
+    let width = NAMED_PALETTES
+        .iter()
+        .map(|p| p.name.len())
+        .max()
+        .unwrap_or(0);
+    for palette in NAMED_PALETTES {
+        println!(
+            "  {:width$}  {:>2} colors  {}",
+            palette.name,
+            palette.colors().len(),
+            palette.description,
+            width = width
+        );
+    }
+}
+
 #[cfg(not(target_arch = "wasm32"))]
 fn print_cli_help() {
     println!(
@@ -219,234 +628,1778 @@ fn print_cli_help() {
             "Sprite Fusion Pixel Snapper {version}\n",
             "Fix inconsistent pixel art by detecting and snapping it to its implicit grid.\n\n",
             "USAGE:\n",
-            "  spritefusion-pixel-snapper <INPUT> <OUTPUT> [COLORS] [OPTIONS]\n\n",
+            "  spritefusion-pixel-snapper <INPUT> <OUTPUT> [COLORS] [OPTIONS]\n",
+            "  spritefusion-pixel-snapper analyze <INPUT> [COLORS] [OPTIONS]\n",
+            "  spritefusion-pixel-snapper palettes\n\n",
             "ARGUMENTS:\n",
             "  <INPUT>   Input PNG/JPEG file, or a directory for batch processing\n",
             "  <OUTPUT>  Output PNG file, or a different output directory for a batch\n",
-            "  [COLORS]  Number of palette colors [default: 16]\n\n",
+            "  [COLORS]  Number of palette colors, or 'auto' to pick it from the image [default: 16]\n\n",
+            "COMMANDS:\n",
+            "  analyze   Detect the grid of a single image without writing an output image\n",
+            "  palettes  List the named palettes accepted by --palette\n",
+            "  Pass an input file named like a command with a path, such as ./analyze\n\n",
             "OPTIONS:\n",
-            "  --pixel-size <PIXELS>  Override the auto-detected pixel size\n",
-            "  --palette <HEX,...>    Use comma-separated 6-digit hex palette colors\n",
-            "  -h, --help             Print help\n",
-            "  -V, --version          Print version\n\n",
+            "  --pixel-size <PIXELS>     Override the auto-detected pixel size (N or WxH)\n",
+            "  --grid-offset <X,Y>       Start the grid at this position, wrapped to the pixel size, instead of detecting it\n",
+            "  --max-colors <N>          Upper bound for an 'auto' color count [default: 32]\n",
+            "  --keep-accents <CELLS>    Keep colors covering at least this many cells\n",
+            "  --quantizer <NAME>        Palette algorithm: kmeans, median-cut, octree or wu [default: kmeans]\n",
+            "  --color-space <SPACE>     Color space for quantization and palettes: srgb, linear, lab or oklab [default: srgb]\n",
+            "  --resample <MODE>         Cell color: majority, center-weighted, center, median or mean [default: majority]\n",
+            "  --alpha <MODE>            Partial transparency: keep, threshold[:ALPHA], levels[:N] or alpha-weighted[:ALPHA] [default: keep]\n",
+            "  --key-background <COLOR>  Make the background around the sprite transparent: auto or #RRGGBB\n",
+            "  --key-tolerance <DIST>    Largest RGB distance from the background color to key [default: 32]\n",
+            "  --pipeline <ORDER>        Stage order: quantize-first or snap-first [default: quantize-first]\n",
+            "  --profile <MODE>          Edge profile for grid detection: luminance or color [default: luminance]\n",
+            "  --step-estimator <NAME>   Pixel size detector: peaks, autocorrelation or auto [default: peaks]\n",
+            "  --subpixel                Detect fractional pixel sizes without drifting cuts\n",
+            "  --non-square              Detect different column and row pixel sizes\n",
+            "  --cuts-file <PATH>        Use the grid cuts of a JSON file instead of detecting them\n",
+            "  --dither <NAME>           Dither onto the palette: none, bayer2, bayer4, bayer8, floyd-steinberg, atkinson or sierra [default: none]\n",
+            "  --shared-palette          Build one palette from all inputs of a batch\n",
+            "  --export-palette <PATH>   Write the output colors to a palette file (single image only)\n",
+            "  --palette-order <ORDER>   Exported palette order: frequency or luminance [default: frequency]\n",
+            "  --export-cuts <PATH>      Write the final cuts to a JSON file (single image only)\n",
+            "  --debug-overlay <PATH>    Write the grid and profiles drawn over the input (single image only)\n",
+            "  --palette <NAME|HEX,...>  Use a named palette or comma-separated 6-digit hex colors\n",
+            "  --palette-file <PATH>     Use the colors of a GPL, ASE, PAL, HEX, ACT or PNG palette file\n",
+            "  --min-confidence <0-1>    Fail below this grid detection confidence\n",
+            "  --json                    Print the analysis as JSON (analyze only)\n",
+            "  -h, --help                Print help\n",
+            "  -V, --version             Print version\n\n",
             "EXAMPLES:\n",
             "  spritefusion-pixel-snapper input.png output.png\n",
             "  spritefusion-pixel-snapper input.png output.png 16 --pixel-size 8\n",
-            "  spritefusion-pixel-snapper inputs outputs --palette 0d2b45,ffecd6"
+            "  spritefusion-pixel-snapper inputs outputs --palette 0d2b45,ffecd6\n",
+            "  spritefusion-pixel-snapper input.png output.png --palette pico8\n",
+            "  spritefusion-pixel-snapper analyze input.png --json"
         ),
         version = env!("CARGO_PKG_VERSION")
     );
 }
 
-fn process_image_common(input_bytes: &[u8], config: Option<Config>) -> Result<ProcessedImage> {
-    let config = config.unwrap_or_default();
-
-    let img = image::load_from_memory(input_bytes)?;
-    let (width, height) = img.dimensions();
-
-    validate_image_dimensions(width, height)?;
+/// How [`Snapper::resample`] reduces every grid cell to a single pixel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ResampleMode {
+    /// Most frequent exact color of the cell, ties going to the lowest RGBA value.
+    #[default]
+    Majority,
+    /// Most frequent color with pixels counting less towards the cell borders, so blended
+    /// edges do not flip cells that straddle them. Best for soft AI outputs.
+    CenterWeighted,
+    /// Color of the center pixel of the cell. Best for crisp upscales.
+    Center,
+    /// Color of the cell closest to all the others in the configured [`ColorSpace`], a median
+    /// that always picks a color present in the cell.
+    Median,
+    /// Mean color of the cell, snapped to the nearest color of the palette, or of the
+    /// quantized image without one.
+    Mean,
+}
 
-    if let Some(px) = config.pixel_size_override {
-        if !px.is_finite() || px < 1.0 || px > (width.min(height) as f64 / 2.0) {
-            return Err(PixelSnapperError::InvalidInput(format!(
-                "pixel_size_override {:.1} is out of valid range [1, {}]",
-                px,
-                width.min(height) / 2
-            )));
+impl std::str::FromStr for ResampleMode {
+    type Err = PixelSnapperError;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "majority" => Ok(ResampleMode::Majority),
+            "center-weighted" => Ok(ResampleMode::CenterWeighted),
+            "center" => Ok(ResampleMode::Center),
+            "median" => Ok(ResampleMode::Median),
+            "mean" => Ok(ResampleMode::Mean),
+            _ => Err(PixelSnapperError::InvalidInput(format!(
+                "invalid resample mode '{}': expected majority, center-weighted, center, median or mean",
+                value
+            ))),
         }
     }
+}
 
-    let rgba_img = img.to_rgba8();
+/// Order of the quantization and grid snapping stages of [`Snapper::snap`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PipelineOrder {
+    /// Quantize the full image, detect the grid on the quantized image, then keep the most
+    /// frequent color of every cell.
+    #[default]
+    QuantizeFirst,
+    /// Detect the grid on the input image, reduce every cell to its median color, then
+    /// quantize the small snapped image. Faster, and blurred edges do not leak into the
+    /// palette, but grid detection sees the noise of the input.
+    SnapFirst,
+}
 
-    let analysis_img = quantize_image(&rgba_img, &config)?;
-    let (profile_x, profile_y) = compute_profiles(&analysis_img)?;
+impl std::str::FromStr for PipelineOrder {
+    type Err = PixelSnapperError;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "quantize-first" => Ok(PipelineOrder::QuantizeFirst),
+            "snap-first" => Ok(PipelineOrder::SnapFirst),
+            _ => Err(PixelSnapperError::InvalidInput(format!(
+                "invalid pipeline order '{}': expected quantize-first or snap-first",
+                value
+            ))),
+        }
+    }
+}
 
-    // Estimate step sizes
-    let step_x_opt = estimate_step_size(&profile_x, &config);
-    let step_y_opt = estimate_step_size(&profile_y, &config);
+/// How partly transparent pixels are handled. Only fully transparent pixels are left out of
+/// the palette, so without a policy anti-aliased edges keep every alpha value they come with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AlphaMode {
+    /// Alpha values are kept as they are.
+    #[default]
+    Keep,
+    /// Pixels below this alpha become transparent and all others opaque.
+    Threshold(u8),
+    /// Alpha is rounded to this many evenly spaced levels, the lowest being transparent.
+    Levels(u8),
+    /// Like [`AlphaMode::Threshold`], but partly transparent pixels weigh on the palette in
+    /// proportion to their alpha. Faint edge pixels then no longer pull palette colors
+    /// towards their blend with the background.
+    AlphaWeighted(u8),
+}
 
-    // Resolve step sizes. Some instabilities so use sibling axis if one fails, or fallback if both fail
-    let (step_x, step_y) = resolve_step_sizes(step_x_opt, step_y_opt, width, height, &config);
+impl AlphaMode {
+    /// Applies the policy to the alpha of a single pixel. Pixels that end up transparent
+    /// lose their color too, so they all count as the same color.
+    fn apply(self, pixel: Rgba<u8>) -> Rgba<u8> {
+        let alpha = match self {
+            AlphaMode::Keep => return pixel,
+            AlphaMode::Threshold(threshold) | AlphaMode::AlphaWeighted(threshold) => {
+                if pixel[3] >= threshold {
+                    255
+                } else {
+                    0
+                }
+            }
+            AlphaMode::Levels(levels) => {
+                let steps = levels.max(2) as u32 - 1;
+                let level = (pixel[3] as u32 * steps + 127) / 255;
+                ((level * 255 + steps / 2) / steps) as u8
+            }
+        };
+        if alpha == 0 {
+            Rgba([0, 0, 0, 0])
+        } else {
+            Rgba([pixel[0], pixel[1], pixel[2], alpha])
+        }
+    }
+}
 
-    let raw_col_cuts = walk(&profile_x, step_x, width as usize, &config)?;
-    let raw_row_cuts = walk(&profile_y, step_y, height as usize, &config)?;
+impl std::str::FromStr for AlphaMode {
+    type Err = PixelSnapperError;
 
-    // Two-pass stabilization: first pass with raw cuts, then cross-validate
-    let (col_cuts, row_cuts) = stabilize_both_axes(
-        &profile_x,
-        &profile_y,
-        raw_col_cuts,
-        raw_row_cuts,
-        width as usize,
-        height as usize,
-        &config,
-    );
+    fn from_str(value: &str) -> Result<Self> {
+        let (name, amount) = match value.split_once(':') {
+            Some((name, amount)) => (name, Some(amount)),
+            None => (value, None),
+        };
+        let amount_or = |default: u8, min: u8| match amount {
+            Some(amount) => amount.parse::<u8>().ok().filter(|&amount| amount >= min),
+            None => Some(default),
+        };
+        let mode = match name {
+            "keep" if amount.is_none() => Some(AlphaMode::Keep),
+            "threshold" => amount_or(DEFAULT_ALPHA_THRESHOLD, 1).map(AlphaMode::Threshold),
+            "levels" => amount_or(DEFAULT_ALPHA_LEVELS, 2).map(AlphaMode::Levels),
+            "alpha-weighted" => amount_or(DEFAULT_ALPHA_THRESHOLD, 1).map(AlphaMode::AlphaWeighted),
+            _ => None,
+        };
+        mode.ok_or_else(|| {
+            PixelSnapperError::InvalidInput(format!(
+                "invalid alpha mode '{}': expected keep, threshold[:1-255], levels[:2-255] or alpha-weighted[:1-255]",
+                value
+            ))
+        })
+    }
+}
 
-    let snapped_img = resample(&analysis_img, &col_cuts, &row_cuts)?;
-    let output_img = match config.palette.as_deref() {
-        Some(palette) => apply_palette(&snapped_img, palette)?,
-        None => snapped_img,
-    };
+/// Pixel difference accumulated into the edge profiles used for grid detection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProfileMode {
+    /// Gradient of the grayscale luminance. Transparent pixels count as black.
+    #[default]
+    Luminance,
+    /// Euclidean distance between alpha-premultiplied RGBA values, so edges between colors of
+    /// the same luminance and between transparent and dark pixels still count.
+    Color,
+}
 
-    // Returns bytes for both implementations
-    let mut output_bytes = Vec::new();
-    let mut cursor = std::io::Cursor::new(&mut output_bytes);
-    output_img
-        .write_to(&mut cursor, image::ImageFormat::Png)
-        .map_err(PixelSnapperError::ImageError)?;
+impl std::str::FromStr for ProfileMode {
+    type Err = PixelSnapperError;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "luminance" => Ok(ProfileMode::Luminance),
+            "color" => Ok(ProfileMode::Color),
+            _ => Err(PixelSnapperError::InvalidInput(format!(
+                "invalid profile mode '{}': expected luminance or color",
+                value
+            ))),
+        }
+    }
+}
 
-    Ok(ProcessedImage {
-        output_bytes,
-        pixel_size: step_x,
-        pixel_size_override: config.pixel_size_override.is_some(),
-        output_width: (col_cuts.len() - 1) as u32,
-        output_height: (row_cuts.len() - 1) as u32,
-    })
+/// Algorithm used to estimate the step size of each axis from its gradient profile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StepEstimator {
+    /// Median distance between strong local maxima of the profile.
+    #[default]
+    Peaks,
+    /// Strongest periodicity of the profile autocorrelation. More robust to noise and to
+    /// large flat areas.
+    Autocorrelation,
+    /// Runs both and keeps the step the profile autocorrelation is stronger at, the peaks
+    /// step when they tie.
+    Auto,
 }
 
-/// WASM entry point
-/// `palette_hex` is a comma-separated list of hex colors: `"0d2b45,ffecd6"`.
-#[cfg(target_arch = "wasm32")]
-#[wasm_bindgen]
-pub fn process_image(
-    input_bytes: &[u8],
-    k_colors: Option<u32>,
-    pixel_size_override: Option<f64>,
-    palette_hex: Option<String>,
-) -> std::result::Result<Vec<u8>, wasm_bindgen::JsValue> {
-    let mut config = Config::default();
-    if let Some(k) = k_colors {
-        if k == 0 {
-            return Err(wasm_bindgen::JsValue::from_str(
-                "k_colors must be greater than 0",
-            ));
+impl std::str::FromStr for StepEstimator {
+    type Err = PixelSnapperError;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "peaks" => Ok(StepEstimator::Peaks),
+            "autocorrelation" => Ok(StepEstimator::Autocorrelation),
+            "auto" => Ok(StepEstimator::Auto),
+            _ => Err(PixelSnapperError::InvalidInput(format!(
+                "invalid step estimator '{}': expected peaks, autocorrelation or auto",
+                value
+            ))),
         }
-        config.k_colors = k as usize;
     }
+}
 
-    config.pixel_size_override = pixel_size_override;
-    config.palette = palette_hex
-        .as_deref()
-        .map(parse_palette_hex)
-        .transpose()
-        .map_err(wasm_bindgen::JsValue::from)?;
+/// Output of the quantization stage.
+#[derive(Debug, Clone)]
+pub struct QuantizedImage {
+    /// Full-resolution image with every opaque pixel replaced by its cluster color.
+    pub image: RgbaImage,
+    /// Cluster colors, in centroid order. Empty when the image has no opaque pixels.
+    pub centroids: Vec<[u8; 3]>,
+    /// Number of colors asked from the quantizer, picked from the image when
+    /// [`Config::with_auto_colors`] is set.
+    pub k_colors: usize,
+}
 
-    process_image_common(input_bytes, Some(config))
-        .map(|processed| processed.output_bytes)
-        .map_err(wasm_bindgen::JsValue::from)
+/// Output of the grid detection stage.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Grid {
+    /// Column gradient profile of the analyzed image.
+    pub profile_x: Vec<f64>,
+    /// Row gradient profile of the analyzed image.
+    pub profile_y: Vec<f64>,
+    /// Step size used to walk the columns.
+    pub step_x: f64,
+    /// Step size used to walk the rows.
+    pub step_y: f64,
+    /// Position of the first column line the walker started from. 0 unless the grid was offset.
+    pub offset_x: f64,
+    /// Position of the first row line the walker started from. 0 unless the grid was offset.
+    pub offset_y: f64,
+    /// Column boundaries, starting at 0 and ending at the image width.
+    pub col_cuts: Vec<usize>,
+    /// Row boundaries, starting at 0 and ending at the image height.
+    pub row_cuts: Vec<usize>,
+    /// Where `step_x` and `step_y` come from.
+    pub step_source: StepSource,
+    /// Where `col_cuts` come from.
+    pub col_source: CutSource,
+    /// Where `row_cuts` come from.
+    pub row_source: CutSource,
+    /// How much the detected grid can be trusted.
+    pub confidence: GridConfidence,
 }
 
-#[cfg(not(target_arch = "wasm32"))]
-fn parse_cli_args(args: &[String]) -> Result<CliCommand> {
-    if args.is_empty()
-        || args
-            .iter()
-            .any(|arg| matches!(arg.as_str(), "-h" | "--help"))
-    {
-        return Ok(CliCommand::Help);
-    }
-    if args
-        .iter()
-        .any(|arg| matches!(arg.as_str(), "-V" | "--version"))
-    {
-        return Ok(CliCommand::Version);
+/// Detection confidence of one axis. Every metric ranges from 0 to 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AxisConfidence {
+    /// Share of interior cuts that sit on a strong profile peak rather than on a fallback target.
+    pub peak_fraction: f64,
+    /// How evenly sized the inner cells are: one minus the coefficient of variation of their widths.
+    pub regularity: f64,
+    /// Ratio between the step estimated from this axis profile alone and the step actually used.
+    /// 1 when the profile yielded no estimate, so a step forced by the caller is not penalized.
+    pub step_agreement: f64,
+    /// Product of the three metrics above.
+    pub score: f64,
+}
+
+/// Detection confidence of a [`Grid`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GridConfidence {
+    pub x: AxisConfidence,
+    pub y: AxisConfidence,
+    /// Mean of both axis scores, from 0 to 1.
+    pub overall: f64,
+}
+
+impl fmt::Display for GridConfidence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:.2} (columns {:.2}, rows {:.2})",
+            self.overall, self.x.score, self.y.score
+        )
     }
-    if args.len() < 2 {
-        return Err(PixelSnapperError::InvalidInput(
-            "missing output path".to_string(),
-        ));
+}
+
+impl GridConfidence {
+    fn new(x: AxisConfidence, y: AxisConfidence) -> Self {
+        Self {
+            x,
+            y,
+            overall: (x.score + y.score) / 2.0,
+        }
     }
+}
 
-    let mut config = Config {
-        input_path: args[0].clone(),
-        output_path: args[1].clone(),
-        ..Default::default()
-    };
+/// Branch taken when resolving the step sizes of a [`Grid`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepSource {
+    /// Set by `pixel_size_override`.
+    Override,
+    /// Average of two agreeing axis estimates.
+    BothAxes,
+    /// Axis estimates disagreed, so the smaller one was used for both axes.
+    SmallerAxis,
+    /// Only the column profile yielded a step.
+    ColumnsOnly,
+    /// Only the row profile yielded a step.
+    RowsOnly,
+    /// Neither profile yielded a step, so the image was split into `fallback_target_segments`.
+    Fallback,
+    /// Both axes agreed on different steps, kept as-is for non-square pixels.
+    NonSquare,
+    /// Average cell size of user supplied cuts. No profile is computed for them, and their
+    /// confidence is always 1.
+    Explicit,
+}
 
-    let mut i = 2;
-    while i < args.len() {
-        match args[i].as_str() {
-            "--pixel-size" => {
-                let Some(val) = args.get(i + 1) else {
-                    return Err(PixelSnapperError::InvalidInput(
-                        "--pixel-size requires a value".to_string(),
-                    ));
-                };
-
-                match val.parse::<f64>() {
-                    Ok(px) if px.is_finite() && px > 0.0 => config.pixel_size_override = Some(px),
-                    _ => {
-                        return Err(PixelSnapperError::InvalidInput(format!(
-                            "invalid --pixel-size '{}': expected a positive number",
-                            val
-                        )))
-                    }
-                }
-                i += 2;
-            }
-            "--palette" => {
-                let Some(val) = args.get(i + 1) else {
-                    return Err(PixelSnapperError::InvalidInput(
-                        "--palette requires a value".to_string(),
-                    ));
-                };
-
-                config.palette = Some(parse_palette_hex(val)?);
-                i += 2;
-            }
-            arg if arg.starts_with("--") => {
-                return Err(PixelSnapperError::InvalidInput(format!(
-                    "unknown argument '{}'",
-                    arg
-                )));
-            }
-            k_arg => {
-                match k_arg.parse::<usize>() {
-                    Ok(k) if k > 0 => config.k_colors = k,
-                    _ => {
-                        return Err(PixelSnapperError::InvalidInput(format!(
-                            "invalid color count '{}': expected a positive integer",
-                            k_arg
-                        )))
-                    }
-                }
-                i += 1;
-            }
+impl StepSource {
+    /// Stable identifier used in JSON output.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StepSource::Override => "override",
+            StepSource::BothAxes => "both_axes",
+            StepSource::SmallerAxis => "smaller_axis",
+            StepSource::ColumnsOnly => "columns_only",
+            StepSource::RowsOnly => "rows_only",
+            StepSource::Fallback => "fallback",
+            StepSource::NonSquare => "non_square",
+            StepSource::Explicit => "explicit",
         }
     }
+}
 
-    Ok(CliCommand::Run(config))
+impl fmt::Display for StepSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            StepSource::Override => "override",
+            StepSource::BothAxes => "both axes",
+            StepSource::SmallerAxis => "smaller axis",
+            StepSource::ColumnsOnly => "columns only",
+            StepSource::RowsOnly => "rows only",
+            StepSource::Fallback => "fallback",
+            StepSource::NonSquare => "non-square",
+            StepSource::Explicit => "explicit cuts",
+        })
+    }
 }
 
-#[cfg(not(target_arch = "wasm32"))]
-#[allow(dead_code)]
-fn process(config: &Config) -> Result<()> {
-    let input_path = Path::new(&config.input_path);
-    if input_path.is_dir() {
-        process_batch(config)
-    } else {
-        process_single(config)
+/// How the cuts of one axis of a [`Grid`] were placed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CutSource {
+    /// Cuts found by walking the gradient profile.
+    Walker,
+    /// Walker cuts were too few or skewed against the other axis and got replaced by
+    /// evenly spaced cuts snapped to nearby profile peaks.
+    Uniform,
+    /// User supplied cuts.
+    Explicit,
+}
+
+impl fmt::Display for CutSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CutSource::Walker => "walker",
+            CutSource::Uniform => "uniform",
+            CutSource::Explicit => "explicit",
+        })
     }
 }
 
-#[cfg(not(target_arch = "wasm32"))]
-#[allow(dead_code)]
-fn process_single(config: &Config) -> Result<()> {
-    let input_path = Path::new(&config.input_path);
-    let output_path = Path::new(&config.output_path);
-    let processed = process_file(input_path, output_path, config)?;
-    println!("Processing: {}", config.input_path);
-    print_processed_image(
-        processed.pixel_size,
-        processed.pixel_size_override,
-        processed.output_width,
-        processed.output_height,
-    );
-    println!("Saved to: {}", config.output_path);
-    Ok(())
+/// Output of [`Snapper::analyze`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct GridAnalysis {
+    pub input_width: u32,
+    pub input_height: u32,
+    /// Number of colors asked from the quantizer before detection.
+    pub k_colors: usize,
+    pub grid: Grid,
 }
 
-#[cfg(not(target_arch = "wasm32"))]
+impl GridAnalysis {
+    /// Serializes everything but the profiles as a JSON object.
+    pub fn to_json(&self) -> String {
+        let grid = &self.grid;
+        format!(
+            concat!(
+                "{{\n",
+                "  \"input_width\": {},\n",
+                "  \"input_height\": {},\n",
+                "  \"k_colors\": {},\n",
+                "  \"output_width\": {},\n",
+                "  \"output_height\": {},\n",
+                "  \"step_x\": {},\n",
+                "  \"step_y\": {},\n",
+                "  \"offset_x\": {},\n",
+                "  \"offset_y\": {},\n",
+                "  \"step_source\": \"{}\",\n",
+                "  \"col_source\": \"{}\",\n",
+                "  \"row_source\": \"{}\",\n",
+                "  \"confidence\": {},\n",
+                "  \"col_cuts\": {},\n",
+                "  \"row_cuts\": {}\n",
+                "}}"
+            ),
+            self.input_width,
+            self.input_height,
+            self.k_colors,
+            grid.output_width(),
+            grid.output_height(),
+            grid.step_x,
+            grid.step_y,
+            grid.offset_x,
+            grid.offset_y,
+            grid.step_source.as_str(),
+            grid.col_source,
+            grid.row_source,
+            json_confidence(&grid.confidence),
+            json_usize_list(&grid.col_cuts),
+            json_usize_list(&grid.row_cuts),
+        )
+    }
+}
+
+fn json_confidence(confidence: &GridConfidence) -> String {
+    let axis = |c: &AxisConfidence| {
+        format!(
+            "{{ \"score\": {}, \"peak_fraction\": {}, \"regularity\": {}, \"step_agreement\": {} }}",
+            c.score, c.peak_fraction, c.regularity, c.step_agreement
+        )
+    };
+    format!(
+        "{{ \"overall\": {}, \"x\": {}, \"y\": {} }}",
+        confidence.overall,
+        axis(&confidence.x),
+        axis(&confidence.y)
+    )
+}
+
+fn json_usize_list(values: &[usize]) -> String {
+    let items: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+    format!("[{}]", items.join(", "))
+}
+
+/// Output of [`Snapper::snap`].
+#[derive(Debug, Clone)]
+pub struct SnapResult {
+    /// Snapped image, one pixel per grid cell.
+    pub image: RgbaImage,
+    /// Grid used to resample the image.
+    pub grid: Grid,
+    /// Distinct opaque colors of `image`, in order of first appearance.
+    pub palette: Vec<[u8; 3]>,
+    /// Number of colors asked from the quantizer.
+    pub k_colors: usize,
+}
+
+impl SnapResult {
+    /// Distinct opaque colors of `image` in `order`, unlike the first appearance order of
+    /// `palette`.
+    pub fn sorted_palette(&self, order: PaletteOrder) -> Vec<[u8; 3]> {
+        sorted_palette(&self.image, order)
+    }
+
+    /// Encodes the snapped image as PNG.
+    pub fn to_png_bytes(&self) -> Result<Vec<u8>> {
+        let mut output_bytes = Vec::new();
+        let mut cursor = std::io::Cursor::new(&mut output_bytes);
+        self.image
+            .write_to(&mut cursor, image::ImageFormat::Png)
+            .map_err(PixelSnapperError::ImageError)?;
+        Ok(output_bytes)
+    }
+}
+
+impl Grid {
+    /// Width of the snapped image, in cells.
+    pub fn output_width(&self) -> u32 {
+        self.col_cuts.len().saturating_sub(1) as u32
+    }
+
+    /// Height of the snapped image, in cells.
+    pub fn output_height(&self) -> u32 {
+        self.row_cuts.len().saturating_sub(1) as u32
+    }
+
+    /// Serializes the cuts in the format read by [`parse_cuts_json`].
+    pub fn cuts_json(&self) -> String {
+        format!(
+            "{{\n  \"col_cuts\": {},\n  \"row_cuts\": {}\n}}",
+            json_usize_list(&self.col_cuts),
+            json_usize_list(&self.row_cuts)
+        )
+    }
+}
+
+/// Reads column and row cuts from a JSON object with `col_cuts` and `row_cuts` arrays, as
+/// written by [`Grid::cuts_json`] or the `analyze --json` command. Other keys are ignored.
+pub fn parse_cuts_json(text: &str) -> Result<(Vec<usize>, Vec<usize>)> {
+    let document = json::parse(text)?;
+    let read_axis = |key: &str| -> Result<Vec<usize>> {
+        let values = document
+            .get(key)
+            .and_then(json::JsonValue::as_array)
+            .ok_or_else(|| {
+                PixelSnapperError::InvalidInput(format!("cuts JSON must contain a '{}' array", key))
+            })?;
+        values
+            .iter()
+            .map(|value| match value.as_f64() {
+                Some(cut) if cut >= 0.0 && cut.fract() == 0.0 && cut <= u32::MAX as f64 => {
+                    Ok(cut as usize)
+                }
+                _ => Err(PixelSnapperError::InvalidInput(format!(
+                    "'{}' must only contain non-negative integers",
+                    key
+                ))),
+            })
+            .collect()
+    };
+
+    Ok((read_axis("col_cuts")?, read_axis("row_cuts")?))
+}
+
+/// Staged pixel snapping pipeline working on in-memory images.
+///
+/// [`Snapper::snap`] runs every stage in order. Each stage is also exposed on its own so
+/// callers can inspect, cache or replace intermediate results:
+///
+/// 1. [`Snapper::quantize`] reduces the image to `k_colors` colors.
+/// 2. [`Snapper::detect_grid`] finds the column and row cuts on the quantized image.
+/// 3. [`Snapper::resample`] collapses every grid cell into a single pixel.
+/// 4. [`Snapper::recolor`] maps the result onto the configured palette, if any.
+///
+/// With [`PipelineOrder::SnapFirst`] the grid is detected on the input image instead and
+/// [`Snapper::quantize_cells`] replaces the quantize and resample stages.
+#[derive(Debug, Clone, Default)]
+pub struct Snapper {
+    config: Config,
+}
+
+impl Snapper {
+    pub fn new(config: Config) -> Self {
+        Self { config }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Runs the whole pipeline on a decoded image.
+    pub fn snap(&self, img: &RgbaImage) -> Result<SnapResult> {
+        self.validate_dimensions(img.width(), img.height())?;
+        let prepared = self.prepare(img);
+        let img = prepared.as_ref().unwrap_or(img);
+        let (snapped, grid, k_colors) = match self.config.pipeline_order {
+            PipelineOrder::QuantizeFirst => {
+                let mut quantized = self.quantize(img)?;
+                let grid = self.detect_grid(&quantized.image)?;
+                if self.config.accent_min_cells.is_some() {
+                    quantized = self.preserve_accents(img, &quantized, &grid)?;
+                }
+                self.check_confidence(&grid)?;
+                let snapped = self.resample(&quantized, &grid)?;
+                (snapped, grid, quantized.k_colors)
+            }
+            PipelineOrder::SnapFirst => {
+                let grid = self.detect_grid(img)?;
+                self.check_confidence(&grid)?;
+                let quantized = self.quantize_cells(img, &grid)?;
+                (quantized.image, grid, quantized.k_colors)
+            }
+        };
+        let image = self.recolor(&snapped)?;
+        let palette = image_palette(&image);
+
+        Ok(SnapResult {
+            image,
+            grid,
+            palette,
+            k_colors,
+        })
+    }
+
+    /// Keys the background and cleans up the alpha of `img` as configured. `None` when
+    /// neither changes anything.
+    fn prepare(&self, img: &RgbaImage) -> Option<RgbaImage> {
+        let keyed = self
+            .config
+            .background_key
+            .and_then(|key| background::key_background(img, key, self.config.background_tolerance));
+        if self.config.alpha_mode == AlphaMode::Keep {
+            return keyed;
+        }
+        Some(self.clean_alpha(keyed.as_ref().unwrap_or(img)))
+    }
+
+    /// Makes the configured [`BackgroundKey`] color transparent where it is connected to the
+    /// border of `img`, the first step of [`Snapper::snap`]. Returns an unchanged copy without
+    /// a key, or when [`BackgroundKey::Auto`] finds no background.
+    pub fn key_background(&self, img: &RgbaImage) -> RgbaImage {
+        self.config
+            .background_key
+            .and_then(|key| background::key_background(img, key, self.config.background_tolerance))
+            .unwrap_or_else(|| img.clone())
+    }
+
+    /// Applies the configured [`AlphaMode`] to `img`, run by [`Snapper::snap`] right after
+    /// [`Snapper::key_background`].
+    /// [`AlphaMode::AlphaWeighted`] keeps partial alpha for quantization to weigh colors
+    /// with, and quantization hardens it afterwards.
+    pub fn clean_alpha(&self, img: &RgbaImage) -> RgbaImage {
+        let mode = self.config.alpha_mode;
+        let mut cleaned = img.clone();
+        for pixel in cleaned.pixels_mut() {
+            *pixel = match mode {
+                AlphaMode::AlphaWeighted(threshold) if pixel[3] >= threshold => *pixel,
+                _ => mode.apply(*pixel),
+            };
+        }
+        cleaned
+    }
+
+    /// Same as [`Snapper::snap`] for any image type supported by the `image` crate.
+    pub fn snap_dynamic(&self, img: &DynamicImage) -> Result<SnapResult> {
+        self.snap(&img.to_rgba8())
+    }
+
+    /// Adds back colors that cover at least [`Config::with_accent_cells`] cells of `grid` in
+    /// `img` but were merged away by quantization, then quantizes `img` again. Cells are
+    /// judged by their per-channel median color, and the closest regular colors are merged to
+    /// keep at most `quantized.k_colors` colors in total.
+    pub fn preserve_accents(
+        &self,
+        img: &RgbaImage,
+        quantized: &QuantizedImage,
+        grid: &Grid,
+    ) -> Result<QuantizedImage> {
+        if self.config.accent_min_cells.is_none() {
+            return Ok(quantized.clone());
+        }
+
+        // Median color of every cell, so noise and blended edges do not decide the accents
+        let cells = cell_medians(img, &grid.col_cuts, &grid.row_cuts)?;
+        Ok(match self.accent_palette(&cells, quantized) {
+            Some(centroids) => self.requantize(img, &centroids, quantized.k_colors),
+            None => quantized.clone(),
+        })
+    }
+
+    /// Reduces every cell of `grid` in `img` to its per-channel median color and quantizes
+    /// the resulting snapped image, so blended pixels between cells never shape the palette.
+    /// Used instead of [`Snapper::quantize`] and [`Snapper::resample`] by
+    /// [`PipelineOrder::SnapFirst`].
+    pub fn quantize_cells(&self, img: &RgbaImage, grid: &Grid) -> Result<QuantizedImage> {
+        self.validate_dimensions(img.width(), img.height())?;
+        let cells = cell_medians(img, &grid.col_cuts, &grid.row_cuts)?;
+        let quantized = quantize_image(&cells, &self.config)?;
+        if self.config.accent_min_cells.is_none() {
+            return Ok(quantized);
+        }
+
+        Ok(match self.accent_palette(&cells, &quantized) {
+            Some(centroids) => self.requantize(&cells, &centroids, quantized.k_colors),
+            None => quantized,
+        })
+    }
+
+    /// Palette of `quantized` extended with the accent colors of `cells`, which holds one
+    /// pixel per grid cell. `None` when there is no accent to add.
+    fn accent_palette(
+        &self,
+        cells: &RgbaImage,
+        quantized: &QuantizedImage,
+    ) -> Option<Vec<[f32; 3]>> {
+        let config = &self.config;
+        let min_cells = config.accent_min_cells?;
+        let space = config.color_space;
+
+        let mut counts: HashMap<[u8; 3], usize> = HashMap::new();
+        for p in quantized.image.pixels().filter(|p| p[3] != 0) {
+            *counts.entry([p[0], p[1], p[2]]).or_insert(0) += 1;
+        }
+        let mut colors: Vec<[u8; 3]> = counts.keys().copied().collect();
+        colors.sort_unstable();
+        let points: Vec<[f32; 3]> = colors.iter().map(|c| space.encode(*c)).collect();
+
+        let accents =
+            quantize::accent_colors(cells, &points, space, min_cells, config.accent_distance);
+        if accents.is_empty() {
+            return None;
+        }
+
+        let weighted = colors
+            .iter()
+            .zip(points)
+            .map(|(color, point)| (point, counts[color] as f64))
+            .collect();
+        let k = quantized.k_colors.min(colors.len() + accents.len());
+        Some(quantize::merge_with_accents(weighted, &accents, k))
+    }
+
+    fn requantize(
+        &self,
+        img: &RgbaImage,
+        centroids: &[[f32; 3]],
+        k_colors: usize,
+    ) -> QuantizedImage {
+        let space = self.config.color_space;
+        QuantizedImage {
+            image: assign_to_palette(img, centroids, space, self.config.alpha_mode),
+            centroids: centroids.iter().map(|c| space.decode(*c)).collect(),
+            k_colors,
+        }
+    }
+
+    /// Builds a single palette for a set of images, like the frames of a sprite set, from an
+    /// even sample of the opaque pixels of each. Use it with [`Config::with_palette`] so
+    /// every image ends up with the same colors.
+    pub fn shared_palette(&self, images: &[RgbaImage]) -> Result<Vec<[u8; 3]>> {
+        let space = self.config.color_space;
+        let samples = images
+            .iter()
+            .map(|img| {
+                let prepared = self.prepare(img);
+                let img = prepared.as_ref().unwrap_or(img);
+                sample_opaque_points(img, space, self.config.alpha_mode)
+            })
+            .collect();
+        shared_palette_from_points(&PalettePoints::concat(samples), &self.config)
+    }
+
+    pub fn quantize(&self, img: &RgbaImage) -> Result<QuantizedImage> {
+        self.validate_dimensions(img.width(), img.height())?;
+        quantize_image(img, &self.config)
+    }
+
+    /// Same as [`Snapper::quantize`] with a custom palette builder instead of the configured
+    /// [`QuantizerKind`].
+    pub fn quantize_with(
+        &self,
+        img: &RgbaImage,
+        quantizer: &dyn Quantizer,
+    ) -> Result<QuantizedImage> {
+        self.validate_dimensions(img.width(), img.height())?;
+        quantize_image_with(img, &self.config, quantizer)
+    }
+
+    /// Detects the pixel grid. `img` is usually the output of [`Snapper::quantize`].
+    pub fn detect_grid(&self, img: &RgbaImage) -> Result<Grid> {
+        let (width, height) = img.dimensions();
+        self.validate_dimensions(width, height)?;
+
+        let config = &self.config;
+        if let Some((col_cuts, row_cuts)) = &config.cuts {
+            if config.pixel_size_override.is_some() || config.grid_offset.is_some() {
+                return Err(PixelSnapperError::InvalidInput(
+                    "explicit cuts cannot be combined with a pixel size or grid offset".to_string(),
+                ));
+            }
+            let col_cuts = sanitize_cuts(col_cuts.clone(), width as usize);
+            let row_cuts = sanitize_cuts(row_cuts.clone(), height as usize);
+            let step_x = width as f64 / col_cuts.len().saturating_sub(1).max(1) as f64;
+            let step_y = height as f64 / row_cuts.len().saturating_sub(1).max(1) as f64;
+            // Cuts given by the caller go straight to resampling and are trusted as they are
+            let forced = AxisConfidence {
+                peak_fraction: 1.0,
+                regularity: 1.0,
+                step_agreement: 1.0,
+                score: 1.0,
+            };
+
+            return Ok(Grid {
+                profile_x: Vec::new(),
+                profile_y: Vec::new(),
+                step_x,
+                step_y,
+                offset_x: 0.0,
+                offset_y: 0.0,
+                col_cuts,
+                row_cuts,
+                step_source: StepSource::Explicit,
+                col_source: CutSource::Explicit,
+                row_source: CutSource::Explicit,
+                confidence: GridConfidence::new(forced, forced),
+            });
+        }
+
+        let (profile_x, profile_y) = compute_profiles(img, config.profile_mode)?;
+
+        // Estimate step sizes
+        let step_x_opt = estimate_step_size(&profile_x, config);
+        let step_y_opt = estimate_step_size(&profile_y, config);
+
+        // Resolve step sizes. Some instabilities so use sibling axis if one fails, or fallback if both fail
+        let (step_x, step_y, step_source) =
+            resolve_step_sizes(&profile_x, &profile_y, step_x_opt, step_y_opt, config);
+
+        // A known step says nothing about where the grid starts, so look for the phase too
+        let (offset_x, offset_y) = match config.grid_offset {
+            Some((ox, oy)) => (ox.rem_euclid(step_x), oy.rem_euclid(step_y)),
+            // Subpixel cuts stay on a lattice, so it has to start on an edge
+            None if step_source == StepSource::Override || config.subpixel => (
+                estimate_phase(&profile_x, step_x),
+                estimate_phase(&profile_y, step_y),
+            ),
+            None => (0.0, 0.0),
+        };
+
+        let raw_col_cuts = walk(&profile_x, step_x, offset_x, width as usize, config)?;
+        let raw_row_cuts = walk(&profile_y, step_y, offset_y, height as usize, config)?;
+
+        // Two-pass stabilization: first pass with raw cuts, then cross-validate
+        let (col_cuts, row_cuts) = stabilize_both_axes(
+            &profile_x,
+            &profile_y,
+            raw_col_cuts,
+            raw_row_cuts,
+            step_x / step_y,
+            config,
+        );
+
+        let confidence = GridConfidence::new(
+            axis_confidence(&profile_x, &col_cuts.cuts, step_x_opt, step_x, config),
+            axis_confidence(&profile_y, &row_cuts.cuts, step_y_opt, step_y, config),
+        );
+
+        Ok(Grid {
+            profile_x,
+            profile_y,
+            step_x,
+            step_y,
+            offset_x,
+            offset_y,
+            col_cuts: col_cuts.cuts,
+            row_cuts: row_cuts.cuts,
+            step_source,
+            col_source: col_cuts.source,
+            row_source: row_cuts.source,
+            confidence,
+        })
+    }
+
+    /// Quantizes the image and detects its grid without resampling it.
+    pub fn analyze(&self, img: &RgbaImage) -> Result<GridAnalysis> {
+        self.validate_dimensions(img.width(), img.height())?;
+        let prepared = self.prepare(img);
+        let img = prepared.as_ref().unwrap_or(img);
+        let (k_colors, grid) = match self.config.pipeline_order {
+            PipelineOrder::QuantizeFirst => {
+                let quantized = self.quantize(img)?;
+                let grid = self.detect_grid(&quantized.image)?;
+                (quantized.k_colors, grid)
+            }
+            PipelineOrder::SnapFirst => {
+                let grid = self.detect_grid(img)?;
+                // Only the color count is reported, so the cells are not quantized
+                let cells = cell_medians(img, &grid.col_cuts, &grid.row_cuts)?;
+                let config = &self.config;
+                let points = palette_points(&cells, config.color_space, config.alpha_mode);
+                let quantizer = configured_quantizer(config);
+                (color_count(&points, config, quantizer.as_ref())?, grid)
+            }
+        };
+
+        Ok(GridAnalysis {
+            input_width: img.width(),
+            input_height: img.height(),
+            k_colors,
+            grid,
+        })
+    }
+
+    /// Renders `img` upscaled with the cuts of `grid` drawn over it, along with charts of both
+    /// gradient profiles and, unless [`StepEstimator::Autocorrelation`] is used, their peak
+    /// threshold. `img` is keyed and cleaned like [`Snapper::snap`] does first, and `grid` must
+    /// have been detected on an image of the same size.
+    ///
+    /// Green cuts were found by the walker on a profile peak, blue cuts come from the uniform
+    /// fallback and red cuts landed on a fallback target away from any peak.
+    pub fn debug_overlay(&self, img: &RgbaImage, grid: &Grid) -> Result<RgbaImage> {
+        let (width, height) = img.dimensions();
+        let fits = |cuts: &[usize], profile: &[f64], limit: u32| {
+            cuts.iter().max() == Some(&(limit as usize))
+                && (profile.is_empty() || profile.len() == limit as usize)
+        };
+        if !fits(&grid.col_cuts, &grid.profile_x, width)
+            || !fits(&grid.row_cuts, &grid.profile_y, height)
+        {
+            return Err(PixelSnapperError::InvalidInput(format!(
+                "grid does not match the {}x{} image",
+                width, height
+            )));
+        }
+        let prepared = self.prepare(img);
+        Ok(overlay::render(
+            prepared.as_ref().unwrap_or(img),
+            grid,
+            &self.config,
+        ))
+    }
+
+    /// Fails with [`PixelSnapperError::LowConfidence`] when `grid` is less confident than the
+    /// configured `min_confidence`.
+    pub fn check_confidence(&self, grid: &Grid) -> Result<()> {
+        match self.config.min_confidence {
+            Some(threshold) if grid.confidence.overall < threshold => {
+                Err(PixelSnapperError::LowConfidence {
+                    confidence: grid.confidence.overall,
+                    threshold,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Collapses every cell of `grid` in the quantized image into a single pixel as set by
+    /// [`ResampleMode`]. [`ResampleMode::Mean`] snaps cell means to the configured palette,
+    /// or else to the quantizer colors.
+    pub fn resample(&self, quantized: &QuantizedImage, grid: &Grid) -> Result<RgbaImage> {
+        let config = &self.config;
+        let space = config.color_space;
+        let palette: Vec<[f32; 3]> = match config.resample_mode {
+            ResampleMode::Mean => config
+                .palette
+                .as_deref()
+                .unwrap_or(&quantized.centroids)
+                .iter()
+                .map(|c| space.encode(*c))
+                .collect(),
+            _ => Vec::new(),
+        };
+        resample_with(
+            &quantized.image,
+            &grid.col_cuts,
+            &grid.row_cuts,
+            config.resample_mode,
+            &palette,
+            space,
+        )
+    }
+
+    /// Maps `img` onto the configured palette with the configured [`Dither`]. Returns an
+    /// unchanged copy without a palette.
+    pub fn recolor(&self, img: &RgbaImage) -> Result<RgbaImage> {
+        let space = self.config.color_space;
+        match self.config.palette.as_deref() {
+            // Empty palettes are reported by `apply_palette`
+            Some(palette) if self.config.dither != Dither::None && !palette.is_empty() => Ok(
+                dither::dither_palette(img, palette, space, self.config.dither),
+            ),
+            Some(palette) => apply_palette(img, palette, space),
+            None => Ok(img.clone()),
+        }
+    }
+
+    fn validate_dimensions(&self, width: u32, height: u32) -> Result<()> {
+        validate_image_dimensions(width, height)?;
+
+        if let Some((ox, oy)) = self.config.grid_offset {
+            if !ox.is_finite() || !oy.is_finite() || ox < 0.0 || oy < 0.0 {
+                return Err(PixelSnapperError::InvalidInput(format!(
+                    "grid offset {:.1},{:.1} must be made of non-negative numbers",
+                    ox, oy
+                )));
+            }
+        }
+        if let Some((px, py)) = self.config.pixel_size_overrides() {
+            if px == py {
+                if !px.is_finite() || px < 1.0 || px > (width.min(height) as f64 / 2.0) {
+                    return Err(PixelSnapperError::InvalidInput(format!(
+                        "pixel_size_override {:.1} is out of valid range [1, {}]",
+                        px,
+                        width.min(height) / 2
+                    )));
+                }
+            } else {
+                for (name, value, limit) in [("x", px, width), ("y", py, height)] {
+                    if !value.is_finite() || value < 1.0 || value > (limit as f64 / 2.0) {
+                        return Err(PixelSnapperError::InvalidInput(format!(
+                            "pixel_size_override {} {:.1} is out of valid range [1, {}]",
+                            name,
+                            value,
+                            limit / 2
+                        )));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn process_image_common(input_bytes: &[u8], config: Option<Config>) -> Result<ProcessedImage> {
+    let snapper = Snapper::new(config.unwrap_or_default());
+
+    let img = image::load_from_memory(input_bytes)?.to_rgba8();
+    let result = snapper.snap(&img)?;
+
+    // Returns bytes for both implementations
+    let output_bytes = result.to_png_bytes()?;
+
+    Ok(ProcessedImage {
+        output_bytes,
+        result,
+        input: img,
+    })
+}
+
+/// WASM entry point
+/// `palette_hex` is a comma-separated list of hex colors: `"0d2b45,ffecd6"`.
+#[cfg(target_arch = "wasm32")]
+#[wasm_bindgen]
+pub fn process_image(
+    input_bytes: &[u8],
+    k_colors: Option<u32>,
+    pixel_size_override: Option<f64>,
+    palette_hex: Option<String>,
+) -> std::result::Result<Vec<u8>, wasm_bindgen::JsValue> {
+    let config = wasm_config(k_colors, pixel_size_override, palette_hex)?;
+
+    process_image_common(input_bytes, Some(config))
+        .map(|processed| processed.output_bytes)
+        .map_err(wasm_bindgen::JsValue::from)
+}
+
+/// Same as `process_image`, but also returns the detected grid and the output palette.
+#[cfg(target_arch = "wasm32")]
+#[wasm_bindgen]
+pub fn process_image_detailed(
+    input_bytes: &[u8],
+    k_colors: Option<u32>,
+    pixel_size_override: Option<f64>,
+    palette_hex: Option<String>,
+) -> std::result::Result<SnapOutput, wasm_bindgen::JsValue> {
+    let config = wasm_config(k_colors, pixel_size_override, palette_hex)?;
+
+    process_image_common(input_bytes, Some(config))
+        .map(|processed| SnapOutput {
+            output_bytes: processed.output_bytes,
+            result: processed.result,
+        })
+        .map_err(wasm_bindgen::JsValue::from)
+}
+
+/// Detects the grid without producing an image. Returns the analysis as a JSON string.
+#[cfg(target_arch = "wasm32")]
+#[wasm_bindgen]
+pub fn analyze_image(
+    input_bytes: &[u8],
+    k_colors: Option<u32>,
+    pixel_size_override: Option<f64>,
+) -> std::result::Result<String, wasm_bindgen::JsValue> {
+    let config = wasm_config(k_colors, pixel_size_override, None)?;
+    let img = image::load_from_memory(input_bytes)
+        .map_err(PixelSnapperError::from)?
+        .to_rgba8();
+
+    Snapper::new(config)
+        .analyze(&img)
+        .map(|analysis| analysis.to_json())
+        .map_err(wasm_bindgen::JsValue::from)
+}
+
+#[cfg(target_arch = "wasm32")]
+fn wasm_config(
+    k_colors: Option<u32>,
+    pixel_size_override: Option<f64>,
+    palette_hex: Option<String>,
+) -> std::result::Result<Config, wasm_bindgen::JsValue> {
+    let mut config = Config::default();
+    if let Some(k) = k_colors {
+        if k == 0 {
+            return Err(wasm_bindgen::JsValue::from_str(
+                "k_colors must be greater than 0",
+            ));
+        }
+        config.k_colors = k as usize;
+    }
+
+    config.pixel_size_override = pixel_size_override;
+    config.palette = palette_hex
+        .as_deref()
+        .map(parse_palette_hex)
+        .transpose()
+        .map_err(wasm_bindgen::JsValue::from)?;
+    Ok(config)
+}
+
+/// WASM view of a [`SnapResult`] along with its PNG encoding.
+#[cfg(target_arch = "wasm32")]
+#[wasm_bindgen]
+pub struct SnapOutput {
+    output_bytes: Vec<u8>,
+    result: SnapResult,
+}
+
+#[cfg(target_arch = "wasm32")]
+#[wasm_bindgen]
+impl SnapOutput {
+    #[wasm_bindgen(getter)]
+    pub fn output_bytes(&self) -> Vec<u8> {
+        self.output_bytes.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn col_cuts(&self) -> Vec<u32> {
+        self.result
+            .grid
+            .col_cuts
+            .iter()
+            .map(|&c| c as u32)
+            .collect()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn row_cuts(&self) -> Vec<u32> {
+        self.result
+            .grid
+            .row_cuts
+            .iter()
+            .map(|&c| c as u32)
+            .collect()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn step_x(&self) -> f64 {
+        self.result.grid.step_x
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn step_y(&self) -> f64 {
+        self.result.grid.step_y
+    }
+
+    /// Comma-separated hex colors, in the same format as the `palette_hex` argument.
+    #[wasm_bindgen(getter)]
+    pub fn palette_hex(&self) -> String {
+        format_palette_hex(&self.result.palette)
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn step_source(&self) -> String {
+        self.result.grid.step_source.to_string()
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn parse_cli_args(args: &[String]) -> Result<CliCommand> {
+    if args.is_empty()
+        || args
+            .iter()
+            .any(|arg| matches!(arg.as_str(), "-h" | "--help"))
+    {
+        return Ok(CliCommand::Help);
+    }
+    if args
+        .iter()
+        .any(|arg| matches!(arg.as_str(), "-V" | "--version"))
+    {
+        return Ok(CliCommand::Version);
+    }
+    if args[0] == "analyze" {
+        return parse_analyze_args(&args[1..]);
+    }
+    if args[0] == "palettes" {
+        return match args.get(1) {
+            Some(arg) => Err(PixelSnapperError::InvalidInput(format!(
+                "unexpected argument '{}' for palettes",
+                arg
+            ))),
+            None => Ok(CliCommand::Palettes),
+        };
+    }
+    if args.len() < 2 {
+        return Err(PixelSnapperError::InvalidInput(
+            "missing output path".to_string(),
+        ));
+    }
+
+    let mut config = Config {
+        input_path: args[0].clone(),
+        output_path: args[1].clone(),
+        ..Default::default()
+    };
+
+    let mut i = 2;
+    while i < args.len() {
+        if let Some(consumed) = parse_config_option(args, i, &mut config)? {
+            i += consumed;
+            continue;
+        }
+        match args[i].as_str() {
+            "--shared-palette" => {
+                config.shared_palette = true;
+                i += 1;
+            }
+            arg if arg.starts_with("--") => {
+                return Err(PixelSnapperError::InvalidInput(format!(
+                    "unknown argument '{}'",
+                    arg
+                )));
+            }
+            "auto" => {
+                config.auto_colors = true;
+                i += 1;
+            }
+            k_arg => {
+                config.k_colors = parse_color_count(k_arg)?;
+                i += 1;
+            }
+        }
+    }
+
+    check_auto_colors(&config)?;
+    check_cuts_file(&config)?;
+    if config.shared_palette && config.palette.is_some() {
+        return Err(PixelSnapperError::InvalidInput(
+            "--shared-palette cannot be combined with a fixed palette".to_string(),
+        ));
+    }
+    if config.dither != Dither::None && config.palette.is_none() && !config.shared_palette {
+        return Err(PixelSnapperError::InvalidInput(
+            "--dither requires --palette, --palette-file or --shared-palette".to_string(),
+        ));
+    }
+    Ok(CliCommand::Run(config))
+}
+
+/// Options that only change the output image, which `analyze` never writes.
+#[cfg(not(target_arch = "wasm32"))]
+const OUTPUT_ONLY_OPTIONS: &[&str] = &[
+    "--palette",
+    "--palette-file",
+    "--export-palette",
+    "--palette-order",
+    "--dither",
+    "--resample",
+    "--keep-accents",
+];
+
+#[cfg(not(target_arch = "wasm32"))]
+fn parse_analyze_args(args: &[String]) -> Result<CliCommand> {
+    let Some(input) = args.first() else {
+        return Err(PixelSnapperError::InvalidInput(
+            "missing input path".to_string(),
+        ));
+    };
+
+    let mut config = Config {
+        input_path: input.clone(),
+        ..Default::default()
+    };
+    let mut json = false;
+
+    let mut i = 1;
+    while i < args.len() {
+        if OUTPUT_ONLY_OPTIONS.contains(&args[i].as_str()) {
+            return Err(PixelSnapperError::InvalidInput(format!(
+                "'{}' only applies to the output image, which analyze does not write",
+                args[i]
+            )));
+        }
+        if let Some(consumed) = parse_config_option(args, i, &mut config)? {
+            i += consumed;
+            continue;
+        }
+        match args[i].as_str() {
+            "--json" => {
+                json = true;
+                i += 1;
+            }
+            arg if arg.starts_with("--") => {
+                return Err(PixelSnapperError::InvalidInput(format!(
+                    "unknown argument '{}'",
+                    arg
+                )));
+            }
+            "auto" => {
+                config.auto_colors = true;
+                i += 1;
+            }
+            k_arg => {
+                config.k_colors = parse_color_count(k_arg)?;
+                i += 1;
+            }
+        }
+    }
+
+    check_auto_colors(&config)?;
+    check_cuts_file(&config)?;
+    Ok(CliCommand::Analyze { config, json })
+}
+
+/// Copy of `config` with the cuts of its `--cuts-file`, if any.
+#[cfg(not(target_arch = "wasm32"))]
+fn load_cuts_file(config: &Config) -> Result<Config> {
+    let mut config = config.clone();
+    if let Some(path) = &config.cuts_path {
+        let text = std::fs::read_to_string(path).map_err(|e| {
+            PixelSnapperError::ProcessingError(format!(
+                "Failed to read cuts file '{}': {}",
+                path, e
+            ))
+        })?;
+        config.cuts = Some(parse_cuts_json(&text)?);
+    }
+    Ok(config)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn check_cuts_file(config: &Config) -> Result<()> {
+    if config.cuts_path.is_some()
+        && (config.pixel_size_override.is_some() || config.grid_offset.is_some())
+    {
+        return Err(PixelSnapperError::InvalidInput(
+            "--cuts-file cannot be combined with --pixel-size or --grid-offset".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn check_auto_colors(config: &Config) -> Result<()> {
+    if config.max_auto_colors.is_some() && !config.auto_colors {
+        return Err(PixelSnapperError::InvalidInput(
+            "--max-colors requires the color count to be 'auto'".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Parses an option shared by every command into `config`.
+/// Returns the number of consumed arguments, or `None` if `args[i]` is not a shared option.
+#[cfg(not(target_arch = "wasm32"))]
+fn parse_config_option(args: &[String], i: usize, config: &mut Config) -> Result<Option<usize>> {
+    match args[i].as_str() {
+        "--pixel-size" => {
+            let val = option_value(args, i)?;
+            let (px, py) = match val.split_once(['x', 'X']) {
+                Some((px, py)) => (px.parse::<f64>(), py.parse::<f64>().map(Some)),
+                None => (val.parse::<f64>(), Ok(None)),
+            };
+            match (px, py) {
+                (Ok(px), Ok(py))
+                    if px.is_finite()
+                        && px > 0.0
+                        && py.map_or(true, |py| py.is_finite() && py > 0.0) =>
+                {
+                    config.pixel_size_override = Some(px);
+                    config.pixel_size_override_y = py;
+                }
+                _ => {
+                    return Err(PixelSnapperError::InvalidInput(format!(
+                        "invalid --pixel-size '{}': expected a positive number or WxH",
+                        val
+                    )))
+                }
+            }
+            Ok(Some(2))
+        }
+        "--grid-offset" => {
+            let val = option_value(args, i)?;
+            let (ox, oy) = val.split_once(',').unwrap_or((val, ""));
+            match (ox.trim().parse::<f64>(), oy.trim().parse::<f64>()) {
+                (Ok(ox), Ok(oy)) if ox.is_finite() && oy.is_finite() && ox >= 0.0 && oy >= 0.0 => {
+                    config.grid_offset = Some((ox, oy));
+                }
+                _ => {
+                    return Err(PixelSnapperError::InvalidInput(format!(
+                        "invalid --grid-offset '{}': expected X,Y non-negative numbers",
+                        val
+                    )))
+                }
+            }
+            Ok(Some(2))
+        }
+        "--cuts-file" => {
+            config.cuts_path = Some(option_value(args, i)?.to_string());
+            Ok(Some(2))
+        }
+        "--export-palette" => {
+            let path = option_value(args, i)?;
+            if PaletteFormat::from_extension(path).is_none() {
+                return Err(PixelSnapperError::InvalidInput(format!(
+                    "unknown palette format of '{}': expected a .gpl, .ase, .pal, .hex, .act, .png or .json file",
+                    path
+                )));
+            }
+            config.palette_export_path = Some(path.to_string());
+            Ok(Some(2))
+        }
+        "--palette-order" => {
+            config.palette_order = option_value(args, i)?.parse()?;
+            Ok(Some(2))
+        }
+        "--export-cuts" => {
+            config.cuts_export_path = Some(option_value(args, i)?.to_string());
+            Ok(Some(2))
+        }
+        "--debug-overlay" => {
+            config.debug_overlay_path = Some(option_value(args, i)?.to_string());
+            Ok(Some(2))
+        }
+        "--max-colors" => {
+            let val = option_value(args, i)?;
+            config.max_auto_colors = Some(parse_color_count(val).map_err(|_| {
+                PixelSnapperError::InvalidInput(format!(
+                    "invalid --max-colors '{}': expected a positive integer",
+                    val
+                ))
+            })?);
+            Ok(Some(2))
+        }
+        "--keep-accents" => {
+            let val = option_value(args, i)?;
+            config.accent_min_cells = Some(parse_color_count(val).map_err(|_| {
+                PixelSnapperError::InvalidInput(format!(
+                    "invalid --keep-accents '{}': expected a positive number of cells",
+                    val
+                ))
+            })?);
+            Ok(Some(2))
+        }
+        "--quantizer" => {
+            config.quantizer = option_value(args, i)?.parse()?;
+            Ok(Some(2))
+        }
+        "--color-space" => {
+            config.color_space = option_value(args, i)?.parse()?;
+            Ok(Some(2))
+        }
+        "--profile" => {
+            config.profile_mode = option_value(args, i)?.parse()?;
+            Ok(Some(2))
+        }
+        "--resample" => {
+            config.resample_mode = option_value(args, i)?.parse()?;
+            Ok(Some(2))
+        }
+        "--pipeline" => {
+            config.pipeline_order = option_value(args, i)?.parse()?;
+            Ok(Some(2))
+        }
+        "--alpha" => {
+            config.alpha_mode = option_value(args, i)?.parse()?;
+            Ok(Some(2))
+        }
+        "--key-background" => {
+            config.background_key = Some(option_value(args, i)?.parse()?);
+            Ok(Some(2))
+        }
+        "--key-tolerance" => {
+            let val = option_value(args, i)?;
+            match val.parse::<f32>() {
+                Ok(tolerance) if tolerance.is_finite() && tolerance >= 0.0 => {
+                    config.background_tolerance = tolerance
+                }
+                _ => {
+                    return Err(PixelSnapperError::InvalidInput(format!(
+                        "invalid --key-tolerance '{}': expected a non-negative color distance",
+                        val
+                    )))
+                }
+            }
+            Ok(Some(2))
+        }
+        "--step-estimator" => {
+            config.step_estimator = option_value(args, i)?.parse()?;
+            Ok(Some(2))
+        }
+        "--subpixel" => {
+            config.subpixel = true;
+            Ok(Some(1))
+        }
+        "--non-square" => {
+            config.allow_non_square = true;
+            Ok(Some(1))
+        }
+        "--palette" => {
+            config.palette = Some(parse_cli_palette(option_value(args, i)?)?);
+            Ok(Some(2))
+        }
+        "--dither" => {
+            config.dither = option_value(args, i)?.parse()?;
+            Ok(Some(2))
+        }
+        "--palette-file" => {
+            let path = option_value(args, i)?;
+            let bytes = std::fs::read(path).map_err(|e| {
+                PixelSnapperError::InvalidInput(format!(
+                    "Failed to read palette file '{}': {}",
+                    path, e
+                ))
+            })?;
+            let format = PaletteFormat::detect(path, &bytes).ok_or_else(|| {
+                PixelSnapperError::InvalidInput(format!(
+                    "unknown palette format of '{}': expected a .gpl, .ase, .pal, .hex, .act, .png or .json file",
+                    path
+                ))
+            })?;
+            config.palette = Some(parse_palette(&bytes, format)?);
+            Ok(Some(2))
+        }
+        "--min-confidence" => {
+            let val = option_value(args, i)?;
+            match val.parse::<f64>() {
+                Ok(threshold) if (0.0..=1.0).contains(&threshold) => {
+                    config.min_confidence = Some(threshold)
+                }
+                _ => {
+                    return Err(PixelSnapperError::InvalidInput(format!(
+                        "invalid --min-confidence '{}': expected a number between 0 and 1",
+                        val
+                    )))
+                }
+            }
+            Ok(Some(2))
+        }
+        _ => Ok(None),
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn option_value(args: &[String], i: usize) -> Result<&str> {
+    args.get(i + 1)
+        .map(String::as_str)
+        .ok_or_else(|| PixelSnapperError::InvalidInput(format!("{} requires a value", args[i])))
+}
+
+/// Resolves `--palette`, either the name of a built-in palette or a hex list.
+#[cfg(not(target_arch = "wasm32"))]
+fn parse_cli_palette(value: &str) -> Result<Vec<[u8; 3]>> {
+    if let Some(palette) = named_palette(value) {
+        return Ok(palette.colors());
+    }
+    // A single word that is not a color was most likely meant as a palette name
+    let looks_like_name = !value.contains(',')
+        && value
+            .chars()
+            .any(|c| c.is_ascii_alphabetic() && !c.is_ascii_hexdigit());
+    if looks_like_name {
+        return Err(PixelSnapperError::InvalidInput(format!(
+            "unknown palette '{}': run 'spritefusion-pixel-snapper palettes' to list the named palettes",
+            value
+        )));
+    }
+    parse_palette_hex(value)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn parse_color_count(value: &str) -> Result<usize> {
+    match value.parse::<usize>() {
+        Ok(k) if k > 0 => Ok(k),
+        _ => Err(PixelSnapperError::InvalidInput(format!(
+            "invalid color count '{}': expected a positive integer",
+            value
+        ))),
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[allow(dead_code)]
+fn process(config: &Config) -> Result<()> {
+    let config = &load_cuts_file(config)?;
+    let input_path = Path::new(&config.input_path);
+    if input_path.is_dir() {
+        if config.cuts_export_path.is_some() {
+            return Err(PixelSnapperError::InvalidInput(
+                "--export-cuts only supports a single input image".to_string(),
+            ));
+        }
+        if config.debug_overlay_path.is_some() {
+            return Err(PixelSnapperError::InvalidInput(
+                "--debug-overlay only supports a single input image".to_string(),
+            ));
+        }
+        if config.palette_export_path.is_some() {
+            return Err(PixelSnapperError::InvalidInput(
+                "--export-palette only supports a single input image".to_string(),
+            ));
+        }
+        process_batch(config)
+    } else if config.shared_palette {
+        Err(PixelSnapperError::InvalidInput(
+            "--shared-palette requires an input directory".to_string(),
+        ))
+    } else {
+        process_single(config)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn analyze(config: &Config, json: bool) -> Result<()> {
+    let config = &load_cuts_file(config)?;
+    let input_path = Path::new(&config.input_path);
+    if input_path.is_dir() {
+        return Err(PixelSnapperError::InvalidInput(format!(
+            "analyze expects an image file, got a directory: {}",
+            input_path.display()
+        )));
+    }
+
+    let img_bytes = read_input_file(input_path)?;
+    let img = image::load_from_memory(&img_bytes)?.to_rgba8();
+    let snapper = Snapper::new(config.clone());
+    let analysis = snapper.analyze(&img)?;
+    export_cuts(&analysis.grid, config)?;
+    export_debug_overlay(&snapper, &img, &analysis.grid, config)?;
+
+    if json {
+        println!("{}", analysis.to_json());
+    } else {
+        let grid = &analysis.grid;
+        println!("Analyzing: {}", config.input_path);
+        println!(
+            "Input size: {}x{}",
+            analysis.input_width, analysis.input_height
+        );
+        if config.auto_colors {
+            println!("Colors: {} (auto)", analysis.k_colors);
+        }
+        println!(
+            "Pixel size: {} ({})",
+            format_pixel_size(grid.step_x, grid.step_y),
+            grid.step_source
+        );
+        println!(
+            "Output size: {}x{}",
+            grid.output_width(),
+            grid.output_height()
+        );
+        println!("Grid offset: {:.1},{:.1}", grid.offset_x, grid.offset_y);
+        println!("Confidence: {}", grid.confidence);
+        print_low_confidence_warning(grid);
+        println!(
+            "Column cuts ({}): {}",
+            grid.col_source,
+            format_cuts(&grid.col_cuts)
+        );
+        println!(
+            "Row cuts ({}): {}",
+            grid.row_source,
+            format_cuts(&grid.row_cuts)
+        );
+    }
+    snapper.check_confidence(&analysis.grid)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn export_cuts(grid: &Grid, config: &Config) -> Result<()> {
+    let Some(path) = &config.cuts_export_path else {
+        return Ok(());
+    };
+    std::fs::write(path, grid.cuts_json() + "\n").map_err(|e| {
+        PixelSnapperError::ProcessingError(format!("Failed to write cuts file '{}': {}", path, e))
+    })
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn export_palette(result: &SnapResult, config: &Config) -> Result<()> {
+    let Some(path) = &config.palette_export_path else {
+        return Ok(());
+    };
+    // The extension was checked while parsing the arguments
+    let format = PaletteFormat::from_extension(path).unwrap();
+    let bytes = write_palette(&result.sorted_palette(config.palette_order), format)?;
+    std::fs::write(path, bytes).map_err(|e| {
+        PixelSnapperError::ProcessingError(format!(
+            "Failed to write palette file '{}': {}",
+            path, e
+        ))
+    })
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn export_debug_overlay(
+    snapper: &Snapper,
+    img: &RgbaImage,
+    grid: &Grid,
+    config: &Config,
+) -> Result<()> {
+    let Some(path) = &config.debug_overlay_path else {
+        return Ok(());
+    };
+    snapper
+        .debug_overlay(img, grid)?
+        .save_with_format(path, image::ImageFormat::Png)
+        .map_err(|e| {
+            PixelSnapperError::ProcessingError(format!(
+                "Failed to write debug overlay '{}': {}",
+                path, e
+            ))
+        })
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn format_pixel_size(step_x: f64, step_y: f64) -> String {
+    if step_x == step_y {
+        format!("{}px", format_step(step_x))
+    } else {
+        format!("{}x{}px", format_step(step_x), format_step(step_y))
+    }
+}
+
+/// Keeps whole and half steps short but shows the second decimal of subpixel steps.
+#[cfg(not(target_arch = "wasm32"))]
+fn format_step(step: f64) -> String {
+    if (step * 10.0).fract() == 0.0 {
+        format!("{:.1}", step)
+    } else {
+        format!("{:.2}", step)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn format_cuts(cuts: &[usize]) -> String {
+    cuts.iter()
+        .map(|cut| cut.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[allow(dead_code)]
+fn process_single(config: &Config) -> Result<()> {
+    let input_path = Path::new(&config.input_path);
+    let output_path = Path::new(&config.output_path);
+    let processed = process_file(input_path, output_path, config)?;
+    println!("Processing: {}", config.input_path);
+    print_processed_image(&processed.result, config);
+    println!("Saved to: {}", config.output_path);
+    export_cuts(&processed.result.grid, config)?;
+    export_palette(&processed.result, config)?;
+    let snapper = Snapper::new(config.clone());
+    export_debug_overlay(&snapper, &processed.input, &processed.result.grid, config)?;
+    Ok(())
+}
+
+#[cfg(not(target_arch = "wasm32"))]
 #[allow(dead_code)]
 fn process_batch(config: &Config) -> Result<()> {
     process_batch_with_reporter(&BatchConfig::from(config), |event| match event {
@@ -458,1003 +2411,3132 @@ fn process_batch(config: &Config) -> Result<()> {
                 input_dir.display()
             );
         }
-        BatchEvent::Started {
-            input,
-            index,
-            total,
-        } => {
-            println!("Processing {}/{}: {}", index + 1, total, input.display());
+        BatchEvent::Started {
+            input,
+            index,
+            total,
+        } => {
+            println!("Processing {}/{}: {}", index + 1, total, input.display());
+        }
+        BatchEvent::Finished {
+            input,
+            output,
+            confidence,
+            index,
+            total,
+        } => {
+            println!(
+                "Done {}/{}: {} -> {}",
+                index + 1,
+                total,
+                input.display(),
+                output.display()
+            );
+            if confidence < LOW_CONFIDENCE_WARNING {
+                eprintln!(
+                    "Warning: low grid confidence ({:.2}) for {}",
+                    confidence,
+                    input.display()
+                );
+            }
+        }
+        BatchEvent::Failed {
+            input,
+            output,
+            error,
+            index,
+            total,
+        } => {
+            eprintln!(
+                "Failed {}/{}: {} -> {} ({})",
+                index + 1,
+                total,
+                input.display(),
+                output.display(),
+                error
+            );
+        }
+        BatchEvent::BatchFinished { input_dir, total } => {
+            println!(
+                "Processed {} image{} in: {}",
+                total,
+                if total == 1 { "" } else { "s" },
+                input_dir.display()
+            );
+        }
+    })
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn process_batch_with_reporter<F>(config: &BatchConfig, reporter: F) -> Result<()>
+where
+    F: Fn(BatchEvent) + Send + Sync,
+{
+    let input_dir = &config.input_dir;
+    let output_dir = &config.output_dir;
+
+    // Do not silently replace inputs; maybe that's ok though
+    if input_dir == output_dir {
+        return Err(PixelSnapperError::InvalidInput(
+            "Batch output directory must be different from the input directory".to_string(),
+        ));
+    }
+
+    if output_dir.exists() && !output_dir.is_dir() {
+        return Err(PixelSnapperError::InvalidInput(format!(
+            "Batch output path must be a directory: {}",
+            output_dir.display()
+        )));
+    }
+
+    std::fs::create_dir_all(output_dir).map_err(|e| {
+        PixelSnapperError::ProcessingError(format!(
+            "Failed to create output directory '{}': {}",
+            output_dir.display(),
+            e
+        ))
+    })?;
+
+    let mut inputs = collect_batch_inputs(input_dir)?;
+    inputs.sort();
+
+    if inputs.is_empty() {
+        return Err(PixelSnapperError::InvalidInput(format!(
+            "No supported images found in '{}'",
+            input_dir.display()
+        )));
+    }
+
+    let items: Vec<(PathBuf, PathBuf)> = inputs
+        .iter()
+        .map(|input| Ok((input.clone(), get_output_path(output_dir, input)?)))
+        .collect::<Result<_>>()?;
+
+    reporter(BatchEvent::BatchStarted {
+        input_dir: input_dir.clone(),
+        total: items.len(),
+    });
+
+    let mut base_config = Config::from(config);
+    if config.shared_palette && config.palette.is_none() {
+        base_config.palette = Some(batch_palette(&inputs, &base_config)?);
+    }
+
+    let results: Vec<(PathBuf, Result<f64>)> = items
+        .par_iter()
+        .enumerate()
+        .map(|(index, (input, output))| {
+            reporter(BatchEvent::Started {
+                input: input.clone(),
+                index,
+                total: items.len(),
+            });
+            let result = process_file(input, output, &base_config)
+                .map(|processed| processed.result.grid.confidence.overall);
+            match &result {
+                Ok(confidence) => reporter(BatchEvent::Finished {
+                    input: input.clone(),
+                    output: output.clone(),
+                    confidence: *confidence,
+                    index,
+                    total: items.len(),
+                }),
+                Err(err) => reporter(BatchEvent::Failed {
+                    input: input.clone(),
+                    output: output.clone(),
+                    error: err.to_string(),
+                    index,
+                    total: items.len(),
+                }),
+            }
+            (input.clone(), result)
+        })
+        .collect();
+
+    let mut failures = Vec::new();
+    for (input, result) in results {
+        match result {
+            Ok(_) => {}
+            Err(err) => failures.push(format!("{} ({})", input.display(), err)),
+        }
+    }
+
+    if failures.is_empty() {
+        reporter(BatchEvent::BatchFinished {
+            input_dir: input_dir.clone(),
+            total: items.len(),
+        });
+        Ok(())
+    } else {
+        Err(PixelSnapperError::ProcessingError(format!(
+            "Batch completed with {} failure{}: {}",
+            failures.len(),
+            if failures.len() == 1 { "" } else { "s" },
+            failures.join("; ")
+        )))
+    }
+}
+
+/// Palette of a batch built from a sample of every input. Unreadable inputs are skipped
+/// here and reported when they are processed.
+#[cfg(not(target_arch = "wasm32"))]
+fn batch_palette(inputs: &[PathBuf], config: &Config) -> Result<Vec<[u8; 3]>> {
+    let snapper = Snapper::new(config.clone());
+    let samples: Vec<PalettePoints> = inputs
+        .par_iter()
+        .filter_map(|input| {
+            let bytes = read_input_file(input).ok()?;
+            let img = image::load_from_memory(&bytes).ok()?.to_rgba8();
+            let prepared = snapper.prepare(&img);
+            Some(sample_opaque_points(
+                prepared.as_ref().unwrap_or(&img),
+                config.color_space,
+                config.alpha_mode,
+            ))
+        })
+        .collect();
+    shared_palette_from_points(&PalettePoints::concat(samples), config)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn process_file(input_path: &Path, output_path: &Path, config: &Config) -> Result<ProcessedImage> {
+    let img_bytes = read_input_file(input_path)?;
+
+    let processed = process_image_common(&img_bytes, Some(config.clone()))?;
+
+    std::fs::write(output_path, &processed.output_bytes).map_err(|e| {
+        PixelSnapperError::ProcessingError(format!(
+            "Failed to write output file '{}': {}",
+            output_path.display(),
+            e
+        ))
+    })?;
+
+    Ok(processed)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn read_input_file(input_path: &Path) -> Result<Vec<u8>> {
+    std::fs::read(input_path).map_err(|e| {
+        PixelSnapperError::ProcessingError(format!(
+            "Failed to read input file '{}': {}",
+            input_path.display(),
+            e
+        ))
+    })
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn print_processed_image(result: &SnapResult, config: &Config) {
+    let grid = &result.grid;
+    if config.auto_colors {
+        println!("Colors: {} (auto)", result.k_colors);
+    }
+    println!(
+        "Pixel size: {} ({})",
+        format_pixel_size(grid.step_x, grid.step_y),
+        match grid.step_source {
+            StepSource::Override => "override",
+            StepSource::Explicit => "explicit cuts",
+            _ => "auto-detected",
+        }
+    );
+    println!(
+        "Output size: {}x{}",
+        grid.output_width(),
+        grid.output_height()
+    );
+    println!("Confidence: {}", grid.confidence);
+    print_low_confidence_warning(grid);
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn print_low_confidence_warning(grid: &Grid) {
+    if grid.confidence.overall < LOW_CONFIDENCE_WARNING {
+        eprintln!(
+            "Warning: low grid confidence ({:.2}), the detected grid may be a guess",
+            grid.confidence.overall
+        );
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn collect_batch_inputs(input_dir: &Path) -> Result<Vec<PathBuf>> {
+    let entries = std::fs::read_dir(input_dir).map_err(|e| {
+        PixelSnapperError::ProcessingError(format!(
+            "Failed to read input directory '{}': {}",
+            input_dir.display(),
+            e
+        ))
+    })?;
+
+    let mut inputs = Vec::new();
+    for entry in entries {
+        let entry = entry.map_err(|e| {
+            PixelSnapperError::ProcessingError(format!(
+                "Failed to read an entry from '{}': {}",
+                input_dir.display(),
+                e
+            ))
+        })?;
+        let path = entry.path();
+        if path.is_file() && is_supported_image_path(&path) {
+            inputs.push(path);
+        }
+    }
+
+    Ok(inputs)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn is_supported_image_path(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| matches!(ext.to_ascii_lowercase().as_str(), "png" | "jpg" | "jpeg"))
+        .unwrap_or(false)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn get_output_path(output_dir: &Path, input_path: &Path) -> Result<PathBuf> {
+    let stem = input_path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .filter(|stem| !stem.is_empty())
+        .ok_or_else(|| {
+            PixelSnapperError::InvalidInput(format!(
+                "Input path has no file stem: {}",
+                input_path.display()
+            ))
+        })?;
+
+    Ok(output_dir.join(format!("{}.png", stem)))
+}
+
+fn validate_image_dimensions(width: u32, height: u32) -> Result<()> {
+    if width == 0 || height == 0 {
+        return Err(PixelSnapperError::InvalidInput(
+            "Image dimensions cannot be zero".to_string(),
+        ));
+    }
+    if width > 10000 || height > 10000 {
+        return Err(PixelSnapperError::InvalidInput(
+            "Image dimensions too large (max 10000x10000)".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn quantize_image(img: &RgbaImage, config: &Config) -> Result<QuantizedImage> {
+    quantize_image_with(img, config, configured_quantizer(config).as_ref())
+}
+
+fn configured_quantizer(config: &Config) -> Box<dyn Quantizer> {
+    match config.quantizer {
+        QuantizerKind::KMeans => Box::new(KMeans {
+            seed: config.k_seed,
+            max_iterations: config.max_kmeans_iterations,
+        }),
+        QuantizerKind::MedianCut => Box::new(MedianCut),
+        QuantizerKind::Octree => Box::new(Octree),
+        QuantizerKind::Wu => Box::new(Wu),
+    }
+}
+
+/// Palette points of `img` at an even stride that keeps at most [`SHARED_PALETTE_SAMPLES`]
+/// of them.
+fn sample_opaque_points(img: &RgbaImage, space: ColorSpace, alpha: AlphaMode) -> PalettePoints {
+    let points = palette_points(img, space, alpha);
+    let stride = points.colors.len().div_ceil(SHARED_PALETTE_SAMPLES).max(1);
+    PalettePoints {
+        colors: points.colors.into_iter().step_by(stride).collect(),
+        weights: points
+            .weights
+            .map(|weights| weights.into_iter().step_by(stride).collect()),
+    }
+}
+
+/// Colors of the visible pixels of an image, as points of the configured space.
+struct PalettePoints {
+    colors: Vec<[f32; 3]>,
+    /// How much each point counts, from its alpha under [`AlphaMode::AlphaWeighted`]. Every
+    /// point counts once without it.
+    weights: Option<Vec<f32>>,
+}
+
+impl PalettePoints {
+    fn concat(samples: Vec<PalettePoints>) -> PalettePoints {
+        let weights = samples
+            .iter()
+            .map(|sample| sample.weights.clone())
+            .collect::<Option<Vec<_>>>()
+            .map(|weights| weights.concat());
+        PalettePoints {
+            colors: samples
+                .into_iter()
+                .flat_map(|sample| sample.colors)
+                .collect(),
+            weights,
+        }
+    }
+}
+
+/// Pixels of `img` that stay visible under `alpha`, as points of `space`. With
+/// [`AlphaMode::AlphaWeighted`], every pixel is weighted by its alpha.
+fn palette_points(img: &RgbaImage, space: ColorSpace, alpha: AlphaMode) -> PalettePoints {
+    let weighted = matches!(alpha, AlphaMode::AlphaWeighted(_));
+    let mut colors = Vec::new();
+    let mut weights = Vec::new();
+    for p in img.pixels().filter(|p| alpha.apply(**p)[3] != 0) {
+        colors.push(space.encode([p[0], p[1], p[2]]));
+        if weighted {
+            weights.push(p[3] as f32 / 255.0);
+        }
+    }
+    PalettePoints {
+        colors,
+        weights: weighted.then_some(weights),
+    }
+}
+
+/// Quantizes the sampled `points` of several images into one palette.
+fn shared_palette_from_points(points: &PalettePoints, config: &Config) -> Result<Vec<[u8; 3]>> {
+    if points.colors.is_empty() {
+        return Err(PixelSnapperError::InvalidInput(
+            "Cannot build a shared palette without opaque pixels".to_string(),
+        ));
+    }
+    let (centroids, _) = palette_for_points(points, config, configured_quantizer(config).as_ref())?;
+    let colors = centroids
+        .iter()
+        .map(|c| config.color_space.decode(*c))
+        .collect();
+    palette::distinct_colors(colors)
+}
+
+/// Picks the color count and runs `quantizer` on the opaque `points` of an image.
+fn palette_for_points(
+    points: &PalettePoints,
+    config: &Config,
+    quantizer: &dyn Quantizer,
+) -> Result<(Vec<[f32; 3]>, usize)> {
+    let k = color_count(points, config, quantizer)?;
+    let centroids = quantizer.weighted_palette(&points.colors, points.weights.as_deref(), k)?;
+    if centroids.is_empty() {
+        return Err(PixelSnapperError::ProcessingError(
+            "Quantizer returned an empty palette".to_string(),
+        ));
+    }
+    Ok((centroids, k))
+}
+
+/// Number of colors the opaque `points` of an image are quantized to, 0 without points.
+fn color_count(
+    points: &PalettePoints,
+    config: &Config,
+    quantizer: &dyn Quantizer,
+) -> Result<usize> {
+    if config.k_colors == 0 && !config.auto_colors {
+        return Err(PixelSnapperError::InvalidInput(
+            "Number of colors must be greater than 0".to_string(),
+        ));
+    }
+    if points.colors.is_empty() {
+        return Ok(0);
+    }
+
+    let k = if config.auto_colors {
+        quantize::auto_color_count(
+            &points.colors,
+            points.weights.as_deref(),
+            quantizer,
+            config.max_auto_colors.unwrap_or(DEFAULT_MAX_AUTO_COLORS),
+            config.auto_color_tolerance,
+            config.auto_color_min_gain,
+        )?
+    } else {
+        config.k_colors
+    };
+    Ok(k.min(points.colors.len()))
+}
+
+fn quantize_image_with(
+    img: &RgbaImage,
+    config: &Config,
+    quantizer: &dyn Quantizer,
+) -> Result<QuantizedImage> {
+    if config.k_colors == 0 && !config.auto_colors {
+        return Err(PixelSnapperError::InvalidInput(
+            "Number of colors must be greater than 0".to_string(),
+        ));
+    }
+
+    let space = config.color_space;
+    let opaque_pixels = palette_points(img, space, config.alpha_mode);
+    let n_pixels = opaque_pixels.colors.len();
+    if n_pixels == 0 {
+        // Nothing to quantize, but the alpha policy still applies
+        return Ok(QuantizedImage {
+            image: assign_to_palette(img, &[], space, config.alpha_mode),
+            centroids: Vec::new(),
+            k_colors: 0,
+        });
+    }
+
+    let (centroids, k) = palette_for_points(&opaque_pixels, config, quantizer)?;
+
+    Ok(QuantizedImage {
+        image: assign_to_palette(img, &centroids, space, config.alpha_mode),
+        centroids: centroids.iter().map(|c| space.decode(*c)).collect(),
+        k_colors: k,
+    })
+}
+
+/// Replaces every opaque pixel of `img` with the nearest of `centroids`, points of `space`,
+/// with its alpha set by `alpha`.
+fn assign_to_palette(
+    img: &RgbaImage,
+    centroids: &[[f32; 3]],
+    space: ColorSpace,
+    alpha: AlphaMode,
+) -> RgbaImage {
+    let mut new_img = RgbaImage::new(img.width(), img.height());
+    for (x, y, pixel) in img.enumerate_pixels() {
+        let pixel = &alpha.apply(*pixel);
+        if pixel[3] == 0 {
+            new_img.put_pixel(x, y, *pixel);
+            continue;
+        }
+        let p = space.encode([pixel[0], pixel[1], pixel[2]]);
+        let mut min_dist = f32::MAX;
+        let mut best_c = [pixel[0], pixel[1], pixel[2]];
+
+        for c in centroids {
+            let d = dist_sq(&p, c);
+            if d < min_dist {
+                min_dist = d;
+                best_c = space.decode(*c);
+            }
+        }
+        new_img.put_pixel(x, y, Rgba([best_c[0], best_c[1], best_c[2], pixel[3]]));
+    }
+    new_img
+}
+
+/// Parses a comma-separated list of 6-digit hex colors, e.g. `"0d2b45,#ffecd6"`.
+pub fn parse_palette_hex(value: &str) -> Result<Vec<[u8; 3]>> {
+    if value.trim().is_empty() {
+        return Err(PixelSnapperError::InvalidInput(
+            "Palette must contain at least one color".to_string(),
+        ));
+    }
+
+    let mut colors = Vec::new();
+    for part in value.split(',') {
+        let hex = part.trim().trim_start_matches('#');
+        if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(PixelSnapperError::InvalidInput(format!(
+                "invalid palette color '{}', expected a 6-digit hex code",
+                part.trim()
+            )));
+        }
+        colors.push([
+            u8::from_str_radix(&hex[0..2], 16).unwrap(),
+            u8::from_str_radix(&hex[2..4], 16).unwrap(),
+            u8::from_str_radix(&hex[4..6], 16).unwrap(),
+        ]);
+    }
+    palette::distinct_colors(colors)
+}
+
+/// Formats colors as a comma-separated hex list accepted by [`parse_palette_hex`].
+pub fn format_palette_hex(palette: &[[u8; 3]]) -> String {
+    palette
+        .iter()
+        .map(|c| format!("{:02x}{:02x}{:02x}", c[0], c[1], c[2]))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn image_palette(img: &RgbaImage) -> Vec<[u8; 3]> {
+    let mut seen = std::collections::HashSet::new();
+    img.pixels()
+        .filter(|p| p[3] != 0)
+        .map(|p| [p[0], p[1], p[2]])
+        .filter(|color| seen.insert(*color))
+        .collect()
+}
+
+fn nearest_palette_color(rgb: [u8; 3], palette: &[[u8; 3]], space: ColorSpace) -> [u8; 3] {
+    let point = space.encode(rgb);
+    let mut best_color = palette[0];
+    let mut best_distance = f32::MAX;
+    for color in palette {
+        let distance = dist_sq(&point, &space.encode(*color));
+        if distance < best_distance {
+            best_distance = distance;
+            best_color = *color;
+        }
+    }
+    best_color
+}
+
+fn apply_palette(img: &RgbaImage, palette: &[[u8; 3]], space: ColorSpace) -> Result<RgbaImage> {
+    if palette.is_empty() {
+        return Err(PixelSnapperError::InvalidInput(
+            "Palette must contain at least one RGB color".to_string(),
+        ));
+    }
+
+    let mut cache: HashMap<[u8; 3], [u8; 3]> = HashMap::new();
+    let mut recolored_img = RgbaImage::new(img.width(), img.height());
+
+    for (x, y, pixel) in img.enumerate_pixels() {
+        if pixel[3] == 0 {
+            recolored_img.put_pixel(x, y, *pixel);
+            continue;
+        }
+
+        let key = [pixel[0], pixel[1], pixel[2]];
+        let color = *cache
+            .entry(key)
+            .or_insert_with(|| nearest_palette_color(key, palette, space));
+        recolored_img.put_pixel(x, y, Rgba([color[0], color[1], color[2], pixel[3]]));
+    }
+
+    Ok(recolored_img)
+}
+
+fn compute_profiles(img: &RgbaImage, mode: ProfileMode) -> Result<(Vec<f64>, Vec<f64>)> {
+    let (w, h) = img.dimensions();
+
+    if w < 3 || h < 3 {
+        return Err(PixelSnapperError::InvalidInput(
+            "Image too small (minimum 3x3)".to_string(),
+        ));
+    }
+
+    let mut col_proj = vec![0.0; w as usize];
+    let mut row_proj = vec![0.0; h as usize];
+
+    let gray = |x, y| {
+        let p = img.get_pixel(x, y);
+        if p[3] == 0 {
+            0.0
+        } else {
+            0.299 * p[0] as f64 + 0.587 * p[1] as f64 + 0.114 * p[2] as f64
+        }
+    };
+    let premultiplied = |x, y| {
+        let p: &Rgba<u8> = img.get_pixel(x, y);
+        let alpha = p[3] as f64 / 255.0;
+        [
+            p[0] as f64 * alpha,
+            p[1] as f64 * alpha,
+            p[2] as f64 * alpha,
+            p[3] as f64,
+        ]
+    };
+    let diff = |(x1, y1), (x2, y2)| match mode {
+        ProfileMode::Luminance => (gray(x2, y2) - gray(x1, y1)).abs(),
+        ProfileMode::Color => {
+            let (a, b) = (premultiplied(x1, y1), premultiplied(x2, y2));
+            a.iter()
+                .zip(&b)
+                .map(|(a, b)| (a - b).powi(2))
+                .sum::<f64>()
+                .sqrt()
+        }
+    };
+
+    // kernels: [-1, 0, 1]
+    for y in 0..h {
+        for x in 1..w - 1 {
+            let grad = diff((x - 1, y), (x + 1, y));
+            col_proj[x as usize] += grad;
+        }
+    }
+    for x in 0..w {
+        for y in 1..h - 1 {
+            let grad = diff((x, y - 1), (x, y + 1));
+            row_proj[y as usize] += grad;
+        }
+    }
+
+    Ok((col_proj, row_proj))
+}
+
+/// Step size estimated from one profile, with how much the estimator trusts it from 0 to 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct StepEstimate {
+    step: f64,
+    confidence: f64,
+}
+
+fn estimate_step_size(profile: &[f64], config: &Config) -> Option<f64> {
+    let estimate = match config.step_estimator {
+        StepEstimator::Peaks => estimate_step_from_peaks(profile, config),
+        StepEstimator::Autocorrelation => estimate_step_from_autocorrelation(profile, config),
+        StepEstimator::Auto => {
+            let peaks = estimate_step_from_peaks(profile, config);
+            let autocorrelation = estimate_step_from_autocorrelation(profile, config);
+            match (peaks, autocorrelation) {
+                // The two confidences are not comparable, so score both steps by how well
+                // the profile repeats at them
+                (Some(p), Some(a)) if autocorrelation_at(profile, p.step) < a.confidence => Some(a),
+                (Some(p), _) => Some(p),
+                (None, a) => a,
+            }
+        }
+    };
+    estimate.map(|estimate| {
+        if config.subpixel {
+            refine_step(profile, estimate.step, config)
+        } else {
+            estimate.step
+        }
+    })
+}
+
+/// Fits a line through the profile peaks to turn a whole `step` into a fractional one.
+/// Returns `step` unchanged when too few peaks line up with it.
+fn refine_step(profile: &[f64], step: f64, config: &Config) -> f64 {
+    if step < 2.0 || profile.len() < 3 {
+        return step;
+    }
+
+    let max_val = profile.iter().cloned().fold(0.0, f64::max);
+    let threshold = max_val * config.peak_threshold_multiplier;
+    // Sharp edges produce a two-sample plateau, keep its trailing sample
+    let peaks: Vec<f64> = (1..profile.len() - 1)
+        .filter(|&i| {
+            profile[i] > threshold && profile[i] >= profile[i - 1] && profile[i] > profile[i + 1]
+        })
+        .map(|i| i as f64)
+        .collect();
+
+    let mut refined = step;
+    // The second pass numbers the peaks again with the fitted step
+    for _ in 0..2 {
+        match fit_peak_lattice(&peaks, refined) {
+            Some(fitted) => refined = fitted,
+            None => return step,
+        }
+    }
+
+    if (refined - step).abs() <= step * 0.15 {
+        refined
+    } else {
+        step
+    }
+}
+
+/// Numbers `peaks` by the cells between consecutive ones and returns the least squares
+/// slope of position over cell number, ignoring peaks far from the first fit.
+fn fit_peak_lattice(peaks: &[f64], step: f64) -> Option<f64> {
+    let mut points = Vec::with_capacity(peaks.len());
+    let mut index = 0.0;
+    let mut previous: Option<f64> = None;
+    for &peak in peaks {
+        if let Some(prev) = previous {
+            // Counting from the previous peak keeps errors of the coarse step from piling up
+            let cells = ((peak - prev) / step).round();
+            if cells < 1.0 {
+                continue;
+            }
+            index += cells;
+        }
+        points.push((index, peak));
+        previous = Some(peak);
+    }
+
+    let (slope, intercept) = least_squares(&points)?;
+    let inliers: Vec<(f64, f64)> = points
+        .into_iter()
+        .filter(|&(k, p)| (p - (intercept + slope * k)).abs() <= step * 0.25)
+        .collect();
+    least_squares(&inliers).map(|(slope, _)| slope)
+}
+
+/// Slope and intercept of the line through `points`, if they span at least two cells.
+fn least_squares(points: &[(f64, f64)]) -> Option<(f64, f64)> {
+    if points.len() < 3 {
+        return None;
+    }
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|&(x, _)| x).sum::<f64>() / n;
+    let mean_y = points.iter().map(|&(_, y)| y).sum::<f64>() / n;
+    let var_x: f64 = points.iter().map(|&(x, _)| (x - mean_x).powi(2)).sum();
+    if var_x < 1.0 {
+        return None;
+    }
+    let cov: f64 = points
+        .iter()
+        .map(|&(x, y)| (x - mean_x) * (y - mean_y))
+        .sum();
+    let slope = cov / var_x;
+    (slope > 0.0).then_some((slope, mean_y - slope * mean_x))
+}
+
+/// Median distance between thresholded local maxima. Confidence is the share of distances
+/// that agree with the median.
+fn estimate_step_from_peaks(profile: &[f64], config: &Config) -> Option<StepEstimate> {
+    if profile.is_empty() {
+        return None;
+    }
+
+    let max_val = profile.iter().cloned().fold(f64::NAN, f64::max);
+    if max_val == 0.0 {
+        return None; // Decide later
+    }
+    let threshold = max_val * config.peak_threshold_multiplier;
+
+    let mut peaks = Vec::new();
+    for i in 1..profile.len() - 1 {
+        if profile[i] > threshold && profile[i] > profile[i - 1] && profile[i] > profile[i + 1] {
+            peaks.push(i);
+        }
+    }
+
+    if peaks.len() < 2 {
+        return None;
+    }
+
+    let mut clean_peaks = vec![peaks[0]];
+    for &p in peaks.iter().skip(1) {
+        if p - clean_peaks.last().unwrap() > (config.peak_distance_filter - 1) {
+            clean_peaks.push(p);
+        }
+    }
+
+    if clean_peaks.len() < 2 {
+        return None;
+    }
+
+    // Compute diffs
+    let mut diffs: Vec<f64> = clean_peaks
+        .windows(2)
+        .map(|w| (w[1] - w[0]) as f64)
+        .collect();
+
+    // Median
+    diffs.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+    let step = diffs[diffs.len() / 2];
+
+    let tolerance = (step * 0.15).max(1.0);
+    let agreeing = diffs
+        .iter()
+        .filter(|d| (*d - step).abs() <= tolerance)
+        .count();
+
+    Some(StepEstimate {
+        step,
+        confidence: agreeing as f64 / diffs.len() as f64,
+    })
+}
+
+/// Lag of the strongest periodicity of the mean-removed profile. Confidence is the
+/// normalized autocorrelation at that lag.
+fn estimate_step_from_autocorrelation(profile: &[f64], config: &Config) -> Option<StepEstimate> {
+    let autocorrelation = profile_autocorrelation(profile)?;
+
+    // Local maxima past lag 1, which only reflects the width of the edges themselves
+    let candidates: Vec<(usize, f64)> = (2..autocorrelation.len().saturating_sub(1))
+        .filter(|&lag| {
+            autocorrelation[lag] > 0.0
+                && autocorrelation[lag] > autocorrelation[lag - 1]
+                && autocorrelation[lag] >= autocorrelation[lag + 1]
+        })
+        .map(|lag| (lag, autocorrelation[lag]))
+        .collect();
+    let best = candidates
+        .iter()
+        .map(|&(_, value)| value)
+        .fold(0.0, f64::max);
+    if best <= 0.0 {
+        return None;
+    }
+
+    // Multiples of the step correlate almost as well as the step itself, so take the
+    // first lag that comes close to the best one
+    let (lag, value) = candidates
+        .into_iter()
+        .find(|&(_, value)| value >= best * config.autocorrelation_peak_ratio)?;
+
+    Some(StepEstimate {
+        step: lag as f64,
+        confidence: value.clamp(0.0, 1.0),
+    })
+}
+
+/// Normalized autocorrelation of `profile` at the lag closest to `step`, on the scale of the
+/// autocorrelation estimator confidence.
+fn autocorrelation_at(profile: &[f64], step: f64) -> f64 {
+    profile_autocorrelation(profile)
+        .and_then(|autocorrelation| autocorrelation.get(step.round() as usize).copied())
+        .unwrap_or(0.0)
+        .clamp(0.0, 1.0)
+}
+
+/// Normalized autocorrelation of the mean-removed profile, for lags up to half its length.
+fn profile_autocorrelation(profile: &[f64]) -> Option<Vec<f64>> {
+    if profile.len() < 4 {
+        return None;
+    }
+
+    let mean = profile.iter().sum::<f64>() / profile.len() as f64;
+    let centered: Vec<f64> = profile.iter().map(|v| v - mean).collect();
+    let energy: f64 = centered.iter().map(|v| v * v).sum();
+    if energy <= 0.0 {
+        return None;
+    }
+
+    let max_lag = profile.len() / 2;
+    let autocorrelation = (0..=max_lag)
+        .map(|lag| {
+            let sum: f64 = centered
+                .iter()
+                .zip(&centered[lag..])
+                .map(|(a, b)| a * b)
+                .sum();
+            // Unbiased so long lags are not penalized for having fewer terms
+            sum / energy * profile.len() as f64 / (profile.len() - lag) as f64
+        })
+        .collect();
+    Some(autocorrelation)
+}
+
+fn resolve_step_sizes(
+    profile_x: &[f64],
+    profile_y: &[f64],
+    step_x_opt: Option<f64>,
+    step_y_opt: Option<f64>,
+    config: &Config,
+) -> (f64, f64, StepSource) {
+    if let Some((px, py)) = config.pixel_size_overrides() {
+        return (px, py, StepSource::Override);
+    }
+    let (width, height) = (profile_x.len(), profile_y.len());
+
+    match (step_x_opt, step_y_opt) {
+        (Some(sx), Some(sy)) => {
+            let ratio = if sx > sy { sx / sy } else { sy / sx };
+            let larger_axis_profile = if sx > sy { profile_x } else { profile_y };
+            if config.allow_non_square
+                && ratio >= config.non_square_min_ratio
+                && rejects_step(larger_axis_profile, sx.min(sy), config)
+            {
+                (sx, sy, StepSource::NonSquare)
+            } else if ratio > config.max_step_ratio {
+                let smaller = sx.min(sy);
+                (smaller, smaller, StepSource::SmallerAxis)
+            } else {
+                let avg = (sx + sy) / 2.0;
+                (avg, avg, StepSource::BothAxes)
+            }
+        }
+
+        (Some(sx), None) => (sx, sx, StepSource::ColumnsOnly),
+
+        (None, Some(sy)) => (sy, sy, StepSource::RowsOnly),
+
+        (None, None) => {
+            let fallback_step =
+                ((width.min(height) as f64) / config.fallback_target_segments as f64).max(1.0);
+            (fallback_step, fallback_step, StepSource::Fallback)
+        }
+    }
+}
+
+/// Walks `profile` with `step` and tells whether most cuts missed a profile peak, meaning the
+/// axis has no edges at that spacing and its own larger step is genuine.
+fn rejects_step(profile: &[f64], step: f64, config: &Config) -> bool {
+    match walk(profile, step, 0.0, profile.len(), config) {
+        Ok(cuts) => cut_peak_fraction(profile, &cuts, config) < config.non_square_max_peak_fraction,
+        Err(_) => false,
+    }
+}
+
+fn stabilize_both_axes(
+    profile_x: &[f64],
+    profile_y: &[f64],
+    raw_col_cuts: Vec<usize>,
+    raw_row_cuts: Vec<usize>,
+    aspect: f64,
+    config: &Config,
+) -> (AxisCuts, AxisCuts) {
+    let (width, height) = (profile_x.len(), profile_y.len());
+    let col_cuts_pass1 = stabilize_cuts(
+        profile_x,
+        raw_col_cuts.clone(),
+        width,
+        &raw_row_cuts,
+        height,
+        aspect,
+        config,
+    );
+    let row_cuts_pass1 = stabilize_cuts(
+        profile_y,
+        raw_row_cuts.clone(),
+        height,
+        &raw_col_cuts,
+        width,
+        1.0 / aspect,
+        config,
+    );
+
+    // Check if the results are coherent. Row steps are scaled by the expected aspect ratio
+    // so non-square pixels are compared in column units.
+    let col_cells = col_cuts_pass1.cuts.len().saturating_sub(1).max(1);
+    let row_cells = row_cuts_pass1.cuts.len().saturating_sub(1).max(1);
+    let col_step = width as f64 / col_cells as f64;
+    let row_step = height as f64 / row_cells as f64 * aspect;
+
+    let step_ratio = if col_step > row_step {
+        col_step / row_step
+    } else {
+        row_step / col_step
+    };
+
+    if step_ratio > config.max_step_ratio {
+        let target_step = col_step.min(row_step);
+
+        let final_col_cuts = if col_step > target_step * 1.2 {
+            AxisCuts::uniform(snap_uniform_cuts(
+                profile_x,
+                width,
+                target_step,
+                config,
+                config.min_cuts_per_axis,
+            ))
+        } else {
+            col_cuts_pass1
+        };
+
+        let final_row_cuts = if row_step > target_step * 1.2 {
+            AxisCuts::uniform(snap_uniform_cuts(
+                profile_y,
+                height,
+                target_step / aspect,
+                config,
+                config.min_cuts_per_axis,
+            ))
+        } else {
+            row_cuts_pass1
+        };
+
+        (final_col_cuts, final_row_cuts)
+    } else {
+        (col_cuts_pass1, row_cuts_pass1)
+    }
+}
+
+/// Final cuts of one axis along with how they were obtained.
+struct AxisCuts {
+    cuts: Vec<usize>,
+    source: CutSource,
+}
+
+impl AxisCuts {
+    fn walker(cuts: Vec<usize>) -> Self {
+        Self {
+            cuts,
+            source: CutSource::Walker,
+        }
+    }
+
+    fn uniform(cuts: Vec<usize>) -> Self {
+        Self {
+            cuts,
+            source: CutSource::Uniform,
+        }
+    }
+}
+
+/// Finds the offset in `[0, step)` whose grid lines collect the most gradient energy.
+fn estimate_phase(profile: &[f64], step: f64) -> f64 {
+    if step < 2.0 || profile.is_empty() {
+        return 0.0;
+    }
+
+    let mut best_phase = 0;
+    let mut best_score = f64::MIN;
+    for phase in 0..step.ceil() as usize {
+        let mut score = 0.0;
+        let mut lines = 0;
+        let mut pos = phase as f64;
+        while (pos.round() as usize) < profile.len() {
+            // A sharp edge at `i` shows up on both `i - 1` and `i`, so give `i - 1` some weight
+            // without letting it win ties against `i`
+            let i = pos.round() as usize;
+            score += profile[i] + 0.5 * i.checked_sub(1).map_or(0.0, |j| profile[j]);
+            lines += 1;
+            pos += step;
+        }
+        let score = score / lines.max(1) as f64;
+        if score > best_score {
+            best_score = score;
+            best_phase = phase;
+        }
+    }
+    best_phase as f64
+}
+
+// Tried uniform grid instead of an elastic-ish walker, but the result was a bit worse.
+// Keeping the walker for now. But some distortions might happen...
+fn walk(
+    profile: &[f64],
+    step_size: f64,
+    offset: f64,
+    limit: usize,
+    config: &Config,
+) -> Result<Vec<usize>> {
+    if profile.is_empty() {
+        return Err(PixelSnapperError::ProcessingError(
+            "Cannot walk on empty profile".to_string(),
+        ));
+    }
+
+    if config.subpixel {
+        return Ok(walk_lattice(profile, step_size, offset, limit, config));
+    }
+
+    let mut cuts = vec![0];
+    let mut current_pos = 0.0;
+    // Cells clipped by the left or top border become a leading partial cell
+    if offset >= 1.0 && offset < limit as f64 {
+        cuts.push(offset.round() as usize);
+        current_pos = offset.round();
+    }
+    let search_window =
+        (step_size * config.walker_search_window_ratio).max(config.walker_min_search_window);
+    let mean_val: f64 = profile.iter().sum::<f64>() / profile.len() as f64;
+
+    while current_pos < limit as f64 {
+        let target = current_pos + step_size;
+        if target >= limit as f64 {
+            cuts.push(limit);
+            break;
+        }
+
+        let start_search = ((target - search_window) as usize).max((current_pos + 1.0) as usize);
+        let end_search = ((target + search_window) as usize).min(limit);
+
+        if end_search <= start_search {
+            current_pos = target;
+            continue;
         }
-        BatchEvent::Finished {
-            input,
-            output,
-            index,
-            total,
-        } => {
-            println!(
-                "Done {}/{}: {} -> {}",
-                index + 1,
-                total,
-                input.display(),
-                output.display()
-            );
+
+        let mut max_val = -1.0;
+        let mut max_idx = start_search;
+        for (i, &value) in profile
+            .iter()
+            .enumerate()
+            .take(end_search)
+            .skip(start_search)
+        {
+            if value > max_val {
+                max_val = value;
+                max_idx = i;
+            }
         }
-        BatchEvent::Failed {
-            input,
-            output,
-            error,
-            index,
-            total,
-        } => {
-            eprintln!(
-                "Failed {}/{}: {} -> {} ({})",
-                index + 1,
-                total,
-                input.display(),
-                output.display(),
-                error
-            );
+
+        if max_val > mean_val * config.walker_strength_threshold {
+            cuts.push(max_idx);
+            current_pos = max_idx as f64;
+        } else {
+            cuts.push(target as usize);
+            current_pos = target;
+        }
+    }
+    Ok(cuts)
+}
+
+/// Subpixel variant of `walk`. Each target is `offset + n * step_size` rather than a step past
+/// the previous cut, so snapping to peaks and rounding never shift the following cells.
+fn walk_lattice(
+    profile: &[f64],
+    step_size: f64,
+    offset: f64,
+    limit: usize,
+    config: &Config,
+) -> Vec<usize> {
+    let search_window =
+        (step_size * config.walker_search_window_ratio).max(config.walker_min_search_window);
+    let strength_threshold = walker_strength_threshold(profile, config);
+
+    let mut cuts = vec![0];
+    // Cells clipped by the left or top border become a leading partial cell
+    let mut index = if offset >= 1.0 { 0.0 } else { 1.0 };
+    loop {
+        let target = offset + index * step_size;
+        index += 1.0;
+        if target.round() >= limit as f64 {
+            cuts.push(limit);
+            break;
+        }
+
+        let prev = *cuts.last().unwrap();
+        let start_search = ((target - search_window).ceil().max(0.0) as usize).max(prev + 1);
+        let end_search = ((target + search_window).floor() as usize).min(limit - 1);
+        if end_search < start_search {
+            continue;
+        }
+
+        let (max_idx, max_val) = (start_search..=end_search).map(|i| (i, profile[i])).fold(
+            (start_search, -1.0),
+            |best, candidate| {
+                if candidate.1 > best.1 {
+                    candidate
+                } else {
+                    best
+                }
+            },
+        );
+
+        if max_val > strength_threshold {
+            cuts.push(max_idx);
+        } else {
+            cuts.push((target.round() as usize).clamp(start_search, end_search));
+        }
+    }
+    cuts
+}
+
+fn stabilize_cuts(
+    profile: &[f64],
+    cuts: Vec<usize>,
+    limit: usize,
+    sibling_cuts: &[usize],
+    sibling_limit: usize,
+    aspect: f64,
+    config: &Config,
+) -> AxisCuts {
+    if limit == 0 {
+        return AxisCuts::walker(vec![0]);
+    }
+
+    let cuts = sanitize_cuts(cuts, limit);
+    let min_required = config.min_cuts_per_axis.max(2).min(limit.saturating_add(1));
+    let axis_cells = cuts.len().saturating_sub(1);
+    let sibling_cells = sibling_cuts.len().saturating_sub(1);
+    let sibling_has_grid =
+        sibling_limit > 0 && sibling_cells >= min_required.saturating_sub(1) && sibling_cells > 0;
+    let steps_skewed = sibling_has_grid && axis_cells > 0 && {
+        let axis_step = limit as f64 / axis_cells as f64;
+        let sibling_step = sibling_limit as f64 / sibling_cells as f64;
+        let step_ratio = axis_step / (sibling_step * aspect);
+        step_ratio > config.max_step_ratio || step_ratio < 1.0 / config.max_step_ratio
+    };
+    let has_enough = cuts.len() >= min_required;
+
+    if has_enough && !steps_skewed {
+        return AxisCuts::walker(cuts);
+    }
+
+    let mut target_step = if sibling_has_grid {
+        sibling_limit as f64 / sibling_cells as f64 * aspect
+    } else if config.fallback_target_segments > 1 {
+        limit as f64 / config.fallback_target_segments as f64
+    } else if axis_cells > 0 {
+        limit as f64 / axis_cells as f64
+    } else {
+        limit as f64
+    };
+    if !target_step.is_finite() || target_step <= 0.0 {
+        target_step = 1.0;
+    }
+
+    AxisCuts::uniform(snap_uniform_cuts(
+        profile,
+        limit,
+        target_step,
+        config,
+        min_required,
+    ))
+}
+
+fn axis_confidence(
+    profile: &[f64],
+    cuts: &[usize],
+    estimate: Option<f64>,
+    step: f64,
+    config: &Config,
+) -> AxisConfidence {
+    let peak_fraction = cut_peak_fraction(profile, cuts, config);
+    let regularity = cut_regularity(cuts);
+    let step_agreement = match estimate {
+        Some(estimate) if estimate > 0.0 && step > 0.0 => estimate.min(step) / estimate.max(step),
+        Some(_) => 0.0,
+        None => 1.0,
+    };
+
+    AxisConfidence {
+        peak_fraction,
+        regularity,
+        step_agreement,
+        score: peak_fraction * regularity * step_agreement,
+    }
+}
+
+/// Fraction of the interior `cuts` that land on a local profile maximum strong enough for
+/// the walker to accept it.
+fn cut_peak_fraction(profile: &[f64], cuts: &[usize], config: &Config) -> f64 {
+    let interior = cuts.get(1..cuts.len().saturating_sub(1)).unwrap_or(&[]);
+    if interior.is_empty() || profile.is_empty() {
+        return 0.0;
+    }
+
+    let threshold = walker_strength_threshold(profile, config);
+    let on_peak = interior
+        .iter()
+        .filter(|&&cut| is_cut_on_peak(profile, cut, threshold))
+        .count();
+
+    on_peak as f64 / interior.len() as f64
+}
+
+/// Minimum profile value the walker accepts as a cut instead of falling back to its target.
+fn walker_strength_threshold(profile: &[f64], config: &Config) -> f64 {
+    if profile.is_empty() {
+        return 0.0;
+    }
+    let mean_val = profile.iter().sum::<f64>() / profile.len() as f64;
+    mean_val * config.walker_strength_threshold
+}
+
+/// Tells whether `cut` sits on a local profile maximum above `strength_threshold`.
+fn is_cut_on_peak(profile: &[f64], cut: usize, strength_threshold: f64) -> bool {
+    // Sharp edges produce a two-sample plateau, so check both sides of the boundary
+    [cut.saturating_sub(1), cut].iter().any(|&i| {
+        let value = profile.get(i).copied().unwrap_or(0.0);
+        let left = i.checked_sub(1).map_or(0.0, |j| profile[j]);
+        let right = profile.get(i + 1).copied().unwrap_or(0.0);
+        value > strength_threshold && value >= left && value >= right
+    })
+}
+
+/// One minus the coefficient of variation of the cell widths. The outer cells are skipped
+/// since they are often clipped by the image border.
+fn cut_regularity(cuts: &[usize]) -> f64 {
+    let widths: Vec<f64> = cuts.windows(2).map(|w| (w[1] - w[0]) as f64).collect();
+    let inner = if widths.len() > 2 {
+        &widths[1..widths.len() - 1]
+    } else {
+        &widths[..]
+    };
+    if inner.is_empty() {
+        return 0.0;
+    }
+
+    let mean = inner.iter().sum::<f64>() / inner.len() as f64;
+    if mean <= 0.0 {
+        return 0.0;
+    }
+    let variance = inner.iter().map(|w| (w - mean).powi(2)).sum::<f64>() / inner.len() as f64;
+    (1.0 - variance.sqrt() / mean).clamp(0.0, 1.0)
+}
+
+fn sanitize_cuts(mut cuts: Vec<usize>, limit: usize) -> Vec<usize> {
+    if limit == 0 {
+        return vec![0];
+    }
+
+    let mut has_zero = false;
+    let mut has_limit = false;
+
+    for value in cuts.iter_mut() {
+        if *value == 0 {
+            has_zero = true;
+        }
+        if *value >= limit {
+            *value = limit;
+        }
+        if *value == limit {
+            has_limit = true;
+        }
+    }
+
+    if !has_zero {
+        cuts.push(0);
+    }
+    if !has_limit {
+        cuts.push(limit);
+    }
+
+    cuts.sort_unstable();
+    cuts.dedup();
+    cuts
+}
+
+fn snap_uniform_cuts(
+    profile: &[f64],
+    limit: usize,
+    target_step: f64,
+    config: &Config,
+    min_required: usize,
+) -> Vec<usize> {
+    if limit == 0 {
+        return vec![0];
+    }
+    if limit == 1 {
+        return vec![0, 1];
+    }
+
+    // Get desired cells
+    let mut desired_cells = if target_step.is_finite() && target_step > 0.0 {
+        (limit as f64 / target_step).round() as usize
+    } else {
+        0
+    };
+    desired_cells = desired_cells
+        .max(min_required.saturating_sub(1))
+        .max(1)
+        .min(limit);
+
+    let cell_width = limit as f64 / desired_cells as f64;
+    let search_window =
+        (cell_width * config.walker_search_window_ratio).max(config.walker_min_search_window);
+    let mean_val = if profile.is_empty() {
+        0.0
+    } else {
+        profile.iter().sum::<f64>() / profile.len() as f64
+    };
+
+    let mut cuts = Vec::with_capacity(desired_cells + 1);
+    cuts.push(0);
+    for idx in 1..desired_cells {
+        let target = cell_width * idx as f64;
+        let prev = *cuts.last().unwrap();
+        if prev + 1 >= limit {
+            break;
+        }
+        let mut start = ((target - search_window).floor() as isize)
+            .max(prev as isize + 1)
+            .max(0);
+        let mut end = ((target + search_window).ceil() as isize).min(limit as isize - 1);
+        if end < start {
+            start = prev as isize + 1;
+            end = start;
+        }
+        let start = start as usize;
+        let end = end as usize;
+        let mut best_idx = start.min(profile.len().saturating_sub(1));
+        let mut best_val = -1.0;
+        for i in start..=end.min(profile.len().saturating_sub(1)) {
+            let v = profile.get(i).copied().unwrap_or(0.0);
+            if v > best_val {
+                best_val = v;
+                best_idx = i;
+            }
         }
-        BatchEvent::BatchFinished { input_dir, total } => {
-            println!(
-                "Processed {} image{} in: {}",
-                total,
-                if total == 1 { "" } else { "s" },
-                input_dir.display()
-            );
+        let strength_threshold = mean_val * config.walker_strength_threshold;
+        if best_val < strength_threshold {
+            let mut fallback_idx = target.round() as isize;
+            if fallback_idx <= prev as isize {
+                fallback_idx = prev as isize + 1;
+            }
+            if fallback_idx >= limit as isize {
+                fallback_idx = (limit as isize - 1).max(prev as isize + 1);
+            }
+            best_idx = fallback_idx as usize;
         }
-    })
+        cuts.push(best_idx);
+    }
+    if *cuts.last().unwrap() != limit {
+        cuts.push(limit);
+    }
+    cuts = sanitize_cuts(cuts, limit);
+    cuts
 }
 
-#[cfg(not(target_arch = "wasm32"))]
-pub fn process_batch_with_reporter<F>(config: &BatchConfig, reporter: F) -> Result<()>
-where
-    F: Fn(BatchEvent) + Send + Sync,
-{
-    let input_dir = &config.input_dir;
-    let output_dir = &config.output_dir;
-
-    // Do not silently replace inputs; maybe that's ok though
-    if input_dir == output_dir {
-        return Err(PixelSnapperError::InvalidInput(
-            "Batch output directory must be different from the input directory".to_string(),
+/// Most common color of every cell, ties going to the lowest RGBA bytes. Cells are counted
+/// over palette indices rather than colors, one output row per task on native targets.
+fn resample(img: &RgbaImage, cols: &[usize], rows: &[usize]) -> Result<RgbaImage> {
+    if cols.len() < 2 || rows.len() < 2 {
+        return Err(PixelSnapperError::ProcessingError(
+            "Insufficient grid cuts for resampling".to_string(),
         ));
     }
+    let out_w = (cols.len().max(1) - 1) as u32;
+    let out_h = (rows.len().max(1) - 1) as u32;
+    let (palette, indices) = index_image(img);
+    let (width, height) = (img.width() as usize, img.height() as usize);
+
+    let cell_row = |tally: &mut CellTally, w_y: &[usize]| -> Vec<[u8; 4]> {
+        let (ys, ye) = (w_y[0], w_y[1]);
+        cols.windows(2)
+            .map(|w_x| {
+                let (xs, xe) = (w_x[0], w_x[1]);
+                if xe <= xs || ye <= ys {
+                    return [0, 0, 0, 0];
+                }
+                let (xs, xe) = (xs.min(width), xe.min(width));
+                for y in ys..ye.min(height) {
+                    tally.add(&indices[y * width + xs..y * width + xe]);
+                }
+                tally
+                    .take()
+                    .map_or([0, 0, 0, 0], |index| palette[index as usize])
+            })
+            .collect()
+    };
 
-    if output_dir.exists() && !output_dir.is_dir() {
-        return Err(PixelSnapperError::InvalidInput(format!(
-            "Batch output path must be a directory: {}",
-            output_dir.display()
-        )));
-    }
-
-    std::fs::create_dir_all(output_dir).map_err(|e| {
-        PixelSnapperError::ProcessingError(format!(
-            "Failed to create output directory '{}': {}",
-            output_dir.display(),
-            e
-        ))
-    })?;
-
-    let mut inputs = collect_batch_inputs(input_dir)?;
-    inputs.sort();
+    #[cfg(not(target_arch = "wasm32"))]
+    let cells: Vec<Vec<[u8; 4]>> = rows
+        .par_windows(2)
+        .map_init(|| CellTally::new(palette.len()), cell_row)
+        .collect();
+    #[cfg(target_arch = "wasm32")]
+    let cells: Vec<Vec<[u8; 4]>> = {
+        let mut tally = CellTally::new(palette.len());
+        rows.windows(2)
+            .map(|w_y| cell_row(&mut tally, w_y))
+            .collect()
+    };
 
-    if inputs.is_empty() {
-        return Err(PixelSnapperError::InvalidInput(format!(
-            "No supported images found in '{}'",
-            input_dir.display()
-        )));
+    let mut final_img: RgbaImage = ImageBuffer::new(out_w, out_h);
+    for (y_i, row) in cells.iter().enumerate() {
+        for (x_i, pixel) in row.iter().enumerate() {
+            final_img.put_pixel(x_i as u32, y_i as u32, Rgba(*pixel));
+        }
     }
+    Ok(final_img)
+}
 
-    let items: Vec<(PathBuf, PathBuf)> = inputs
-        .iter()
-        .map(|input| Ok((input.clone(), get_output_path(output_dir, input)?)))
-        .collect::<Result<_>>()?;
-
-    reporter(BatchEvent::BatchStarted {
-        input_dir: input_dir.clone(),
-        total: items.len(),
-    });
-
-    let results: Vec<(PathBuf, Result<()>)> = items
-        .par_iter()
-        .enumerate()
-        .map(|(index, (input, output))| {
-            reporter(BatchEvent::Started {
-                input: input.clone(),
-                index,
-                total: items.len(),
-            });
-            let item_config = Config::from(config);
-            let result = process_file(input, output, &item_config).map(|_| ());
-            match &result {
-                Ok(()) => reporter(BatchEvent::Finished {
-                    input: input.clone(),
-                    output: output.clone(),
-                    index,
-                    total: items.len(),
-                }),
-                Err(err) => reporter(BatchEvent::Failed {
-                    input: input.clone(),
-                    output: output.clone(),
-                    error: err.to_string(),
-                    index,
-                    total: items.len(),
-                }),
+/// Distinct colors of `img` in ascending byte order, along with the index of every pixel
+/// into them in row-major order.
+fn index_image(img: &RgbaImage) -> (Vec<[u8; 4]>, Vec<u32>) {
+    let mut lookup: HashMap<u32, u32, BuildHasherDefault<ColorHasher>> = HashMap::default();
+    let mut colors: Vec<[u8; 4]> = Vec::new();
+    // Direct-mapped cache in front of the map, enough for a quantized image on its own
+    let mut recent: [Option<(u32, u32)>; 256] = [None; 256];
+    let mut indices: Vec<u32> = img
+        .pixels()
+        .map(|p| {
+            let key = u32::from_le_bytes(p.0);
+            let slot = (key.wrapping_mul(0x9e37_79b1) >> 24) as usize;
+            if let Some((cached, index)) = recent[slot] {
+                if cached == key {
+                    return index;
+                }
             }
-            (input.clone(), result)
+            let index = *lookup.entry(key).or_insert_with(|| {
+                colors.push(p.0);
+                (colors.len() - 1) as u32
+            });
+            recent[slot] = Some((key, index));
+            index
         })
         .collect();
 
-    let mut failures = Vec::new();
-    for (input, result) in results {
-        match result {
-            Ok(()) => {}
-            Err(err) => failures.push(format!("{} ({})", input.display(), err)),
-        }
+    // Renumber so that a lower index is a lower color, which settles ties like the bytes would
+    let mut order: Vec<u32> = (0..colors.len() as u32).collect();
+    order.sort_unstable_by_key(|&i| colors[i as usize]);
+    let mut rank = vec![0u32; colors.len()];
+    for (r, &i) in order.iter().enumerate() {
+        rank[i as usize] = r as u32;
     }
-
-    if failures.is_empty() {
-        reporter(BatchEvent::BatchFinished {
-            input_dir: input_dir.clone(),
-            total: items.len(),
-        });
-        Ok(())
-    } else {
-        Err(PixelSnapperError::ProcessingError(format!(
-            "Batch completed with {} failure{}: {}",
-            failures.len(),
-            if failures.len() == 1 { "" } else { "s" },
-            failures.join("; ")
-        )))
+    for index in &mut indices {
+        *index = rank[*index as usize];
     }
+    let palette = order.iter().map(|&i| colors[i as usize]).collect();
+    (palette, indices)
 }
 
-#[cfg(not(target_arch = "wasm32"))]
-fn process_file(input_path: &Path, output_path: &Path, config: &Config) -> Result<ProcessedImage> {
-    let img_bytes = std::fs::read(input_path).map_err(|e| {
-        PixelSnapperError::ProcessingError(format!(
-            "Failed to read input file '{}': {}",
-            input_path.display(),
-            e
-        ))
-    })?;
+/// Hashes packed RGBA colors with a single multiply, as the default hasher would take longer
+/// than the counting itself.
+#[derive(Default)]
+struct ColorHasher(u64);
 
-    let processed = process_image_common(&img_bytes, Some(config.clone()))?;
+impl Hasher for ColorHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.write_u32(byte as u32);
+        }
+    }
 
-    std::fs::write(output_path, &processed.output_bytes).map_err(|e| {
-        PixelSnapperError::ProcessingError(format!(
-            "Failed to write output file '{}': {}",
-            output_path.display(),
-            e
-        ))
-    })?;
+    fn write_u32(&mut self, value: u32) {
+        self.0 = (self.0 ^ value as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    }
 
-    Ok(processed)
+    fn finish(&self) -> u64 {
+        self.0 ^ (self.0 >> 32)
+    }
 }
 
-#[cfg(not(target_arch = "wasm32"))]
-fn print_processed_image(
-    pixel_size: f64,
-    pixel_size_override: bool,
-    output_width: u32,
-    output_height: u32,
-) {
-    println!(
-        "Pixel size: {:.1}px ({})",
-        pixel_size,
-        if pixel_size_override {
-            "override"
-        } else {
-            "auto-detected"
-        }
-    );
-    println!("Output size: {}x{}", output_width, output_height);
+/// Largest palette counted with a fixed-size array per cell. Larger ones, such as unquantized
+/// photos, sort the indices of each cell instead.
+const MAX_TALLY_COLORS: usize = 1 << 16;
+
+/// Per-cell vote over palette indices, reused from one cell to the next.
+enum CellTally {
+    Counts { counts: Vec<u32>, touched: Vec<u32> },
+    Sorted(Vec<u32>),
 }
 
-#[cfg(not(target_arch = "wasm32"))]
-fn collect_batch_inputs(input_dir: &Path) -> Result<Vec<PathBuf>> {
-    let entries = std::fs::read_dir(input_dir).map_err(|e| {
-        PixelSnapperError::ProcessingError(format!(
-            "Failed to read input directory '{}': {}",
-            input_dir.display(),
-            e
-        ))
-    })?;
+impl CellTally {
+    fn new(colors: usize) -> Self {
+        if colors <= MAX_TALLY_COLORS {
+            CellTally::Counts {
+                counts: vec![0; colors],
+                touched: Vec::new(),
+            }
+        } else {
+            CellTally::Sorted(Vec::new())
+        }
+    }
 
-    let mut inputs = Vec::new();
-    for entry in entries {
-        let entry = entry.map_err(|e| {
-            PixelSnapperError::ProcessingError(format!(
-                "Failed to read an entry from '{}': {}",
-                input_dir.display(),
-                e
-            ))
-        })?;
-        let path = entry.path();
-        if path.is_file() && is_supported_image_path(&path) {
-            inputs.push(path);
+    /// Counts one row of a cell.
+    fn add(&mut self, span: &[u32]) {
+        match self {
+            CellTally::Counts { counts, touched } => {
+                for &index in span {
+                    let count = &mut counts[index as usize];
+                    if *count == 0 {
+                        touched.push(index);
+                    }
+                    *count += 1;
+                }
+            }
+            CellTally::Sorted(indices) => indices.extend_from_slice(span),
         }
     }
 
-    Ok(inputs)
+    /// Most counted index, the lowest one on ties, leaving the tally empty for the next cell.
+    fn take(&mut self) -> Option<u32> {
+        let mut best: Option<(u32, u32)> = None;
+        let mut consider = |index: u32, count: u32| {
+            let better = match best {
+                Some((best_index, best_count)) => {
+                    count > best_count || (count == best_count && index < best_index)
+                }
+                None => true,
+            };
+            if better {
+                best = Some((index, count));
+            }
+        };
+        match self {
+            CellTally::Counts { counts, touched } => {
+                for &index in touched.iter() {
+                    consider(index, counts[index as usize]);
+                    counts[index as usize] = 0;
+                }
+                touched.clear();
+            }
+            CellTally::Sorted(indices) => {
+                indices.sort_unstable();
+                for run in indices.chunk_by(|a, b| a == b) {
+                    consider(run[0], run.len() as u32);
+                }
+                indices.clear();
+            }
+        }
+        best.map(|(index, _)| index)
+    }
 }
 
-#[cfg(not(target_arch = "wasm32"))]
-fn is_supported_image_path(path: &Path) -> bool {
-    path.extension()
-        .and_then(|ext| ext.to_str())
-        .map(|ext| matches!(ext.to_ascii_lowercase().as_str(), "png" | "jpg" | "jpeg"))
-        .unwrap_or(false)
-}
+/// Reduces every cell with `mode`. [`ResampleMode::Mean`] compares colors in `space`.
+/// Resamples `img` with `mode`. `palette` holds the points of `space` that
+/// [`ResampleMode::Mean`] snaps to.
+fn resample_with(
+    img: &RgbaImage,
+    cols: &[usize],
+    rows: &[usize],
+    mode: ResampleMode,
+    palette: &[[f32; 3]],
+    space: ColorSpace,
+) -> Result<RgbaImage> {
+    if mode == ResampleMode::Majority {
+        return resample(img, cols, rows);
+    }
+    if cols.len() < 2 || rows.len() < 2 {
+        return Err(PixelSnapperError::ProcessingError(
+            "Insufficient grid cuts for resampling".to_string(),
+        ));
+    }
+    let out_w = (cols.len() - 1) as u32;
+    let out_h = (rows.len() - 1) as u32;
+    let mut final_img: RgbaImage = ImageBuffer::new(out_w, out_h);
 
-#[cfg(not(target_arch = "wasm32"))]
-fn get_output_path(output_dir: &Path, input_path: &Path) -> Result<PathBuf> {
-    let stem = input_path
-        .file_stem()
-        .and_then(|stem| stem.to_str())
-        .filter(|stem| !stem.is_empty())
-        .ok_or_else(|| {
-            PixelSnapperError::InvalidInput(format!(
-                "Input path has no file stem: {}",
-                input_path.display()
-            ))
-        })?;
+    for (y_i, w_y) in rows.windows(2).enumerate() {
+        for (x_i, w_x) in cols.windows(2).enumerate() {
+            let (ys, ye) = (w_y[0], w_y[1].min(img.height() as usize));
+            let (xs, xe) = (w_x[0], w_x[1].min(img.width() as usize));
+            if xe <= xs || ye <= ys {
+                continue;
+            }
 
-    Ok(output_dir.join(format!("{}.png", stem)))
+            let pixel = match mode {
+                ResampleMode::Center => {
+                    img.get_pixel(((xs + xe) / 2) as u32, ((ys + ye) / 2) as u32)
+                        .0
+                }
+                ResampleMode::CenterWeighted => {
+                    // Tent weights, highest on the center and lowest on the border pixels
+                    let (cx, cy) = ((xs + xe) as f64 / 2.0, (ys + ye) as f64 / 2.0);
+                    let (hw, hh) = ((xe - xs) as f64 / 2.0, (ye - ys) as f64 / 2.0);
+                    let mut weights: HashMap<[u8; 4], f64> = HashMap::new();
+                    for y in ys..ye {
+                        let wy = 1.0 - (y as f64 + 0.5 - cy).abs() / hh;
+                        for x in xs..xe {
+                            let wx = 1.0 - (x as f64 + 0.5 - cx).abs() / hw;
+                            *weights
+                                .entry(img.get_pixel(x as u32, y as u32).0)
+                                .or_insert(0.0) += wx * wy;
+                        }
+                    }
+                    weights
+                        .into_iter()
+                        .max_by(|a, b| a.1.total_cmp(&b.1).then(b.0.cmp(&a.0)))
+                        .map_or([0; 4], |(color, _)| color)
+                }
+                ResampleMode::Majority | ResampleMode::Median | ResampleMode::Mean => {
+                    let mut counts: HashMap<[u8; 4], usize> = HashMap::new();
+                    for y in ys..ye {
+                        for x in xs..xe {
+                            *counts
+                                .entry(img.get_pixel(x as u32, y as u32).0)
+                                .or_insert(0) += 1;
+                        }
+                    }
+                    let total = (xe - xs) * (ye - ys);
+                    let opaque: Vec<([u8; 4], usize)> = counts
+                        .iter()
+                        .filter(|(c, _)| c[3] != 0)
+                        .map(|(c, n)| (*c, *n))
+                        .collect();
+                    // Majority cells are normally resampled by `resample`. Mostly transparent
+                    // cells keep their majority pixel too
+                    if mode == ResampleMode::Majority
+                        || opaque.iter().map(|(_, n)| n).sum::<usize>() * 2 <= total
+                    {
+                        counts
+                            .into_iter()
+                            .max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(&a.0)))
+                            .map_or([0; 4], |(color, _)| color)
+                    } else if mode == ResampleMode::Median {
+                        cell_medoid(&opaque, space)
+                    } else {
+                        cell_mean(&opaque, palette, space)
+                    }
+                }
+            };
+            final_img.put_pixel(x_i as u32, y_i as u32, Rgba(pixel));
+        }
+    }
+    Ok(final_img)
 }
 
-fn validate_image_dimensions(width: u32, height: u32) -> Result<()> {
-    if width == 0 || height == 0 {
-        return Err(PixelSnapperError::InvalidInput(
-            "Image dimensions cannot be zero".to_string(),
-        ));
-    }
-    if width > 10000 || height > 10000 {
-        return Err(PixelSnapperError::InvalidInput(
-            "Image dimensions too large (max 10000x10000)".to_string(),
-        ));
+/// Color of `colors`, with their pixel counts, with the smallest summed distance in `space`
+/// to all the others. Ties go to the lowest RGBA value.
+fn cell_medoid(colors: &[([u8; 4], usize)], space: ColorSpace) -> [u8; 4] {
+    let points: Vec<[f32; 3]> = colors
+        .iter()
+        .map(|(c, _)| space.encode([c[0], c[1], c[2]]))
+        .collect();
+    let cost = |i: usize| -> f64 {
+        points
+            .iter()
+            .zip(colors)
+            .map(|(p, (_, n))| dist_sq(&points[i], p).sqrt() as f64 * *n as f64)
+            .sum()
+    };
+    (0..colors.len())
+        .map(|i| (cost(i), colors[i].0))
+        .min_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)))
+        .map_or([0; 4], |(_, color)| color)
+}
+
+/// Mean of `colors`, with their pixel counts, snapped to the nearest of `palette`, points of
+/// `space`. The alpha channel is averaged.
+fn cell_mean(colors: &[([u8; 4], usize)], palette: &[[f32; 3]], space: ColorSpace) -> [u8; 4] {
+    let total: usize = colors.iter().map(|(_, n)| n).sum();
+    let mut mean = [0.0f32; 3];
+    let mut alpha = 0.0;
+    for (color, count) in colors {
+        let point = space.encode([color[0], color[1], color[2]]);
+        for c in 0..3 {
+            mean[c] += point[c] * *count as f32 / total as f32;
+        }
+        alpha += color[3] as f32 * *count as f32 / total as f32;
     }
-    Ok(())
+    let nearest = palette
+        .iter()
+        .min_by(|a, b| dist_sq(&mean, a).total_cmp(&dist_sq(&mean, b)))
+        .map_or(mean, |p| *p);
+    let [r, g, b] = space.decode(nearest);
+    [r, g, b, alpha.round() as u8]
 }
 
-fn quantize_image(img: &RgbaImage, config: &Config) -> Result<RgbaImage> {
-    if config.k_colors == 0 {
-        return Err(PixelSnapperError::InvalidInput(
-            "Number of colors must be greater than 0".to_string(),
+/// Per-channel median of every cell, which ignores the blended pixels along cell edges.
+/// Cells that are mostly transparent become fully transparent.
+fn cell_medians(img: &RgbaImage, cols: &[usize], rows: &[usize]) -> Result<RgbaImage> {
+    if cols.len() < 2 || rows.len() < 2 {
+        return Err(PixelSnapperError::ProcessingError(
+            "Insufficient grid cuts for resampling".to_string(),
         ));
     }
+    let out_w = (cols.len() - 1) as u32;
+    let out_h = (rows.len() - 1) as u32;
+    let mut final_img: RgbaImage = ImageBuffer::new(out_w, out_h);
 
-    let opaque_pixels: Vec<[f32; 3]> = img
-        .pixels()
-        .filter_map(|p| {
-            if p[3] == 0 {
-                None
-            } else {
-                Some([p[0] as f32, p[1] as f32, p[2] as f32])
+    let mut channels: [Vec<u8>; 4] = Default::default();
+    for (y_i, w_y) in rows.windows(2).enumerate() {
+        for (x_i, w_x) in cols.windows(2).enumerate() {
+            let ye = w_y[1].min(img.height() as usize);
+            let xe = w_x[1].min(img.width() as usize);
+            channels.iter_mut().for_each(Vec::clear);
+            let mut total = 0;
+            for y in w_y[0]..ye {
+                for x in w_x[0]..xe {
+                    total += 1;
+                    let p = img.get_pixel(x as u32, y as u32);
+                    if p[3] != 0 {
+                        for (channel, &value) in channels.iter_mut().zip(p.0.iter()) {
+                            channel.push(value);
+                        }
+                    }
+                }
             }
-        })
-        .collect();
-    let n_pixels = opaque_pixels.len();
-    if n_pixels == 0 {
-        return Ok(img.clone());
+
+            if channels[3].len() * 2 <= total {
+                continue;
+            }
+            let median = std::array::from_fn(|c| {
+                let values = &mut channels[c];
+                let mid = values.len() / 2;
+                *values.select_nth_unstable(mid).1
+            });
+            final_img.put_pixel(x_i as u32, y_i as u32, Rgba(median));
+        }
     }
+    Ok(final_img)
+}
 
-    let mut rng = ChaCha8Rng::seed_from_u64(config.k_seed);
-    let k = config.k_colors.min(n_pixels);
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod cli_tests {
+    use super::*;
 
-    fn sample_index(rng: &mut ChaCha8Rng, upper: usize) -> usize {
-        debug_assert!(upper > 0);
-        let upper = upper as u64;
-        rng.gen_range(0..upper) as usize
+    fn args(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| (*value).to_string()).collect()
     }
 
-    fn dist_sq(p: &[f32; 3], c: &[f32; 3]) -> f32 {
-        let dr = p[0] - c[0];
-        let dg = p[1] - c[1];
-        let db = p[2] - c[2];
-        dr * dr + dg * dg + db * db
+    #[test]
+    fn no_arguments_show_help() {
+        assert!(matches!(parse_cli_args(&[]).unwrap(), CliCommand::Help));
     }
 
-    let mut centroids: Vec<[f32; 3]> = Vec::with_capacity(k);
-    let first_idx = sample_index(&mut rng, n_pixels);
-    centroids.push(opaque_pixels[first_idx]);
-    let mut distances = vec![f32::MAX; n_pixels];
-
-    // Maybe try a faster algorithm for this? like https://crates.io/crates/kmeans_colors
-    for _ in 1..k {
-        let last_c = centroids.last().unwrap();
-        let mut sum_sq_dist = 0.0;
-
-        for (i, p) in opaque_pixels.iter().enumerate() {
-            let d_sq = dist_sq(p, last_c);
-            if d_sq < distances[i] {
-                distances[i] = d_sq;
-            }
-            sum_sq_dist += distances[i];
-        }
+    #[test]
+    fn help_and_version_flags_are_recognized() {
+        assert!(matches!(
+            parse_cli_args(&args(&["--help"])).unwrap(),
+            CliCommand::Help
+        ));
+        assert!(matches!(
+            parse_cli_args(&args(&["--version"])).unwrap(),
+            CliCommand::Version
+        ));
+    }
 
-        if sum_sq_dist <= 0.0 {
-            let idx = sample_index(&mut rng, n_pixels);
-            centroids.push(opaque_pixels[idx]);
-        } else {
-            let dist = WeightedIndex::new(&distances).map_err(|e| {
-                PixelSnapperError::ProcessingError(format!("Failed to sample new centroid: {}", e))
-            })?;
-            let idx = dist.sample(&mut rng);
-            centroids.push(opaque_pixels[idx]);
-        }
+    #[test]
+    fn output_path_is_required() {
+        let error = parse_cli_args(&args(&["input.png"])).unwrap_err();
+        assert!(matches!(error, PixelSnapperError::InvalidInput(_)));
+        assert!(error.to_string().contains("missing output path"));
     }
 
-    let mut prev_centroids = centroids.clone();
-    for iteration in 0..config.max_kmeans_iterations {
-        let mut sums = vec![[0.0f32; 3]; k];
-        let mut counts = vec![0usize; k];
+    #[test]
+    fn parses_all_supported_cli_options() {
+        let command = parse_cli_args(&args(&[
+            "input.png",
+            "output.png",
+            "24",
+            "--pixel-size",
+            "8",
+            "--palette",
+            "0d2b45,ffecd6",
+        ]))
+        .unwrap();
 
-        for p in &opaque_pixels {
-            let mut min_dist = f32::MAX;
-            let mut best_k = 0;
+        let CliCommand::Run(config) = command else {
+            panic!("expected a processing command");
+        };
 
-            for (i, c) in centroids.iter().enumerate() {
-                let d = dist_sq(p, c);
-                if d < min_dist {
-                    min_dist = d;
-                    best_k = i;
-                }
-            }
-            sums[best_k][0] += p[0];
-            sums[best_k][1] += p[1];
-            sums[best_k][2] += p[2];
-            counts[best_k] += 1;
-        }
-
-        for i in 0..k {
-            if counts[i] > 0 {
-                let fcount = counts[i] as f32;
-                centroids[i] = [
-                    sums[i][0] / fcount,
-                    sums[i][1] / fcount,
-                    sums[i][2] / fcount,
-                ];
-            }
-        }
+        assert_eq!(config.input_path, "input.png");
+        assert_eq!(config.output_path, "output.png");
+        assert_eq!(config.k_colors, 24);
+        assert_eq!(config.pixel_size_override, Some(8.0));
+        assert_eq!(config.palette, Some(vec![[13, 43, 69], [255, 236, 214]]));
+    }
 
-        if iteration > 0 {
-            let mut max_movement = 0.0f32;
-            for (new_c, old_c) in centroids.iter().zip(prev_centroids.iter()) {
-                let movement = dist_sq(new_c, old_c);
-                if movement > max_movement {
-                    max_movement = movement;
-                }
-            }
+    #[test]
+    fn parses_analyze_command() {
+        let command = parse_cli_args(&args(&[
+            "analyze",
+            "input.png",
+            "8",
+            "--pixel-size",
+            "4",
+            "--json",
+        ]))
+        .unwrap();
 
-            if max_movement < 0.01 {
-                break;
-            }
-        }
+        let CliCommand::Analyze { config, json } = command else {
+            panic!("expected an analyze command");
+        };
 
-        prev_centroids.copy_from_slice(&centroids);
+        assert_eq!(config.input_path, "input.png");
+        assert_eq!(config.k_colors, 8);
+        assert_eq!(config.pixel_size_override, Some(4.0));
+        assert!(json);
     }
 
-    let mut new_img = RgbaImage::new(img.width(), img.height());
-    for (x, y, pixel) in img.enumerate_pixels() {
-        if pixel[3] == 0 {
-            new_img.put_pixel(x, y, *pixel);
-            continue;
-        }
-        let p = [pixel[0] as f32, pixel[1] as f32, pixel[2] as f32];
-        let mut min_dist = f32::MAX;
-        let mut best_c = [pixel[0], pixel[1], pixel[2]];
-
-        for c in &centroids {
-            let d = dist_sq(&p, c);
-            if d < min_dist {
-                min_dist = d;
-                best_c = [c[0].round() as u8, c[1].round() as u8, c[2].round() as u8];
-            }
-        }
-        new_img.put_pixel(x, y, Rgba([best_c[0], best_c[1], best_c[2], pixel[3]]));
+    #[test]
+    fn analyze_rejects_output_only_options() {
+        let error =
+            parse_cli_args(&args(&["analyze", "input.png", "--palette", "pico8"])).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("'--palette' only applies to the output"));
+
+        // A file named like the command is still reachable through a path
+        let command = parse_cli_args(&args(&["./analyze", "out.png"])).unwrap();
+        assert!(matches!(command, CliCommand::Run(_)));
     }
-    Ok(new_img)
-}
 
-fn parse_palette_hex(value: &str) -> Result<Vec<[u8; 3]>> {
-    if value.trim().is_empty() {
-        return Err(PixelSnapperError::InvalidInput(
-            "Palette must contain at least one color".to_string(),
-        ));
-    }
+    #[test]
+    fn min_confidence_must_be_between_zero_and_one() {
+        let command =
+            parse_cli_args(&args(&["in.png", "out.png", "--min-confidence", "0.4"])).unwrap();
+        let CliCommand::Run(config) = command else {
+            panic!("expected a processing command");
+        };
+        assert_eq!(config.min_confidence, Some(0.4));
 
-    let mut seen = std::collections::HashSet::new();
-    let mut palette = Vec::new();
-    for part in value.split(',') {
-        let hex = part.trim().trim_start_matches('#');
-        if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
-            return Err(PixelSnapperError::InvalidInput(format!(
-                "invalid palette color '{}', expected a 6-digit hex code",
-                part.trim()
-            )));
-        }
-        let color = [
-            u8::from_str_radix(&hex[0..2], 16).unwrap(),
-            u8::from_str_radix(&hex[2..4], 16).unwrap(),
-            u8::from_str_radix(&hex[4..6], 16).unwrap(),
-        ];
-        if seen.insert(color) {
-            palette.push(color);
-        }
+        let error =
+            parse_cli_args(&args(&["in.png", "out.png", "--min-confidence", "2"])).unwrap_err();
+        assert!(error.to_string().contains("invalid --min-confidence '2'"));
     }
 
-    if palette.len() > MAX_PALETTE_COLORS {
-        return Err(PixelSnapperError::InvalidInput(format!(
-            "Palette must contain at most {} distinct colors",
-            MAX_PALETTE_COLORS
-        )));
+    #[test]
+    fn parses_non_square_pixel_size() {
+        let command = parse_cli_args(&args(&["in.png", "out.png", "--pixel-size", "8x4"])).unwrap();
+        let CliCommand::Run(config) = command else {
+            panic!("expected a processing command");
+        };
+        assert_eq!(config.pixel_size_overrides(), Some((8.0, 4.0)));
+
+        let error =
+            parse_cli_args(&args(&["in.png", "out.png", "--pixel-size", "8x"])).unwrap_err();
+        assert!(error.to_string().contains("invalid --pixel-size '8x'"));
     }
-    Ok(palette)
-}
 
-fn nearest_palette_color(rgb: [u8; 3], palette: &[[u8; 3]]) -> [u8; 3] {
-    let mut best_color = palette[0];
-    let mut best_distance = u32::MAX;
-    for color in palette {
-        let dr = rgb[0] as i32 - color[0] as i32;
-        let dg = rgb[1] as i32 - color[1] as i32;
-        let db = rgb[2] as i32 - color[2] as i32;
-        let distance = (dr * dr + dg * dg + db * db) as u32;
-        if distance < best_distance {
-            best_distance = distance;
-            best_color = *color;
-        }
+    #[test]
+    fn parses_grid_offset() {
+        let command =
+            parse_cli_args(&args(&["in.png", "out.png", "--grid-offset", "3,4.5"])).unwrap();
+        let CliCommand::Run(config) = command else {
+            panic!("expected a processing command");
+        };
+        assert_eq!(config.grid_offset(), Some((3.0, 4.5)));
+
+        let error =
+            parse_cli_args(&args(&["in.png", "out.png", "--grid-offset", "-1,2"])).unwrap_err();
+        assert!(error.to_string().contains("invalid --grid-offset '-1,2'"));
+
+        let error =
+            parse_cli_args(&args(&["in.png", "out.png", "--grid-offset", "3"])).unwrap_err();
+        assert!(error.to_string().contains("invalid --grid-offset '3'"));
     }
-    best_color
-}
 
-fn apply_palette(img: &RgbaImage, palette: &[[u8; 3]]) -> Result<RgbaImage> {
-    if palette.is_empty() {
-        return Err(PixelSnapperError::InvalidInput(
-            "Palette must contain at least one RGB color".to_string(),
+    #[test]
+    fn reads_cuts_file() {
+        let path = std::env::temp_dir().join(format!(
+            "spritefusion-pixel-snapper-cuts-test-{}.json",
+            std::process::id()
         ));
-    }
+        std::fs::write(&path, r#"{"col_cuts": [0, 4, 8], "row_cuts": [0, 8]}"#).unwrap();
+        let command = parse_cli_args(&args(&[
+            "in.png",
+            "out.png",
+            "--cuts-file",
+            path.to_str().unwrap(),
+            "--export-cuts",
+            "grid.json",
+        ]))
+        .unwrap();
 
-    let mut cache: HashMap<[u8; 3], [u8; 3]> = HashMap::new();
-    let mut recolored_img = RgbaImage::new(img.width(), img.height());
+        let CliCommand::Run(config) = command else {
+            panic!("expected a processing command");
+        };
+        // The file is only read when processing starts
+        assert_eq!(config.cuts(), None);
+        let loaded = load_cuts_file(&config).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.cuts(), Some((&[0, 4, 8][..], &[0, 8][..])));
+        assert_eq!(config.cuts_export_path.as_deref(), Some("grid.json"));
+
+        let error = load_cuts_file(&loaded).unwrap_err();
+        assert!(matches!(error, PixelSnapperError::ProcessingError(_)));
+
+        let error = parse_cli_args(&args(&[
+            "in.png",
+            "out.png",
+            "--cuts-file",
+            "grid.json",
+            "--pixel-size",
+            "8",
+        ]))
+        .unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("cannot be combined with --pixel-size"));
+    }
 
-    for (x, y, pixel) in img.enumerate_pixels() {
-        if pixel[3] == 0 {
-            recolored_img.put_pixel(x, y, *pixel);
-            continue;
-        }
+    #[test]
+    fn parses_step_estimator() {
+        let command = parse_cli_args(&args(&[
+            "in.png",
+            "out.png",
+            "--step-estimator",
+            "autocorrelation",
+        ]))
+        .unwrap();
+        let CliCommand::Run(config) = command else {
+            panic!("expected a processing command");
+        };
+        assert_eq!(config.step_estimator(), StepEstimator::Autocorrelation);
 
-        let key = [pixel[0], pixel[1], pixel[2]];
-        let color = *cache
-            .entry(key)
-            .or_insert_with(|| nearest_palette_color(key, palette));
-        recolored_img.put_pixel(x, y, Rgba([color[0], color[1], color[2], pixel[3]]));
+        let error =
+            parse_cli_args(&args(&["in.png", "out.png", "--step-estimator", "fft"])).unwrap_err();
+        assert!(error.to_string().contains("invalid step estimator 'fft'"));
     }
 
-    Ok(recolored_img)
-}
-
-fn compute_profiles(img: &RgbaImage) -> Result<(Vec<f64>, Vec<f64>)> {
-    let (w, h) = img.dimensions();
+    #[test]
+    fn parses_profile_mode() {
+        let command = parse_cli_args(&args(&["in.png", "out.png", "--profile", "color"])).unwrap();
+        let CliCommand::Run(config) = command else {
+            panic!("expected a processing command");
+        };
+        assert_eq!(config.profile_mode(), ProfileMode::Color);
 
-    if w < 3 || h < 3 {
-        return Err(PixelSnapperError::InvalidInput(
-            "Image too small (minimum 3x3)".to_string(),
-        ));
+        let error = parse_cli_args(&args(&["in.png", "out.png", "--profile", "hue"])).unwrap_err();
+        assert!(error.to_string().contains("invalid profile mode 'hue'"));
     }
 
-    let mut col_proj = vec![0.0; w as usize];
-    let mut row_proj = vec![0.0; h as usize];
+    #[test]
+    fn parses_color_space() {
+        let command =
+            parse_cli_args(&args(&["in.png", "out.png", "--color-space", "oklab"])).unwrap();
+        let CliCommand::Run(config) = command else {
+            panic!("expected a processing command");
+        };
+        assert_eq!(config.color_space(), ColorSpace::Oklab);
 
-    let gray = |x, y| {
-        let p = img.get_pixel(x, y);
-        if p[3] == 0 {
-            0.0
-        } else {
-            0.299 * p[0] as f64 + 0.587 * p[1] as f64 + 0.114 * p[2] as f64
-        }
-    };
+        let error =
+            parse_cli_args(&args(&["in.png", "out.png", "--color-space", "hsv"])).unwrap_err();
+        assert!(error.to_string().contains("invalid color space 'hsv'"));
+    }
 
-    // kernels: [-1, 0, 1]
-    for y in 0..h {
-        for x in 1..w - 1 {
-            let left = gray(x - 1, y);
-            let right = gray(x + 1, y);
-            let grad = (right - left).abs();
-            col_proj[x as usize] += grad;
-        }
+    #[test]
+    fn parses_quantizer() {
+        let command =
+            parse_cli_args(&args(&["in.png", "out.png", "--quantizer", "median-cut"])).unwrap();
+        let CliCommand::Run(config) = command else {
+            panic!("expected a processing command");
+        };
+        assert_eq!(config.quantizer(), QuantizerKind::MedianCut);
+
+        let error =
+            parse_cli_args(&args(&["in.png", "out.png", "--quantizer", "neuquant"])).unwrap_err();
+        assert!(error.to_string().contains("invalid quantizer 'neuquant'"));
     }
-    for x in 0..w {
-        for y in 1..h - 1 {
-            let top = gray(x, y - 1);
-            let bottom = gray(x, y + 1);
-            let grad = (bottom - top).abs();
-            row_proj[y as usize] += grad;
-        }
+
+    #[test]
+    fn parses_auto_color_count() {
+        let command =
+            parse_cli_args(&args(&["in.png", "out.png", "auto", "--max-colors", "12"])).unwrap();
+        let CliCommand::Run(config) = command else {
+            panic!("expected a processing command");
+        };
+        assert_eq!(config.auto_colors(), Some(12));
+
+        let error =
+            parse_cli_args(&args(&["in.png", "out.png", "8", "--max-colors", "12"])).unwrap_err();
+        assert!(error.to_string().contains("--max-colors requires"));
     }
 
-    Ok((col_proj, row_proj))
-}
+    #[test]
+    fn parses_keep_accents() {
+        let command = parse_cli_args(&args(&["in.png", "out.png", "--keep-accents", "3"])).unwrap();
+        let CliCommand::Run(config) = command else {
+            panic!("expected a processing command");
+        };
+        assert_eq!(config.accent_cells(), Some(3));
 
-fn estimate_step_size(profile: &[f64], config: &Config) -> Option<f64> {
-    if profile.is_empty() {
-        return None;
+        let error =
+            parse_cli_args(&args(&["in.png", "out.png", "--keep-accents", "0"])).unwrap_err();
+        assert!(error.to_string().contains("invalid --keep-accents '0'"));
     }
 
-    let max_val = profile.iter().cloned().fold(f64::NAN, f64::max);
-    if max_val == 0.0 {
-        return None; // Decide later
+    #[test]
+    fn parses_pipeline_order() {
+        let command =
+            parse_cli_args(&args(&["in.png", "out.png", "--pipeline", "snap-first"])).unwrap();
+        let CliCommand::Run(config) = command else {
+            panic!("expected a processing command");
+        };
+        assert_eq!(config.pipeline_order(), PipelineOrder::SnapFirst);
+
+        let error =
+            parse_cli_args(&args(&["in.png", "out.png", "--pipeline", "backwards"])).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("invalid pipeline order 'backwards'"));
     }
-    let threshold = max_val * config.peak_threshold_multiplier;
 
-    let mut peaks = Vec::new();
-    for i in 1..profile.len() - 1 {
-        if profile[i] > threshold && profile[i] > profile[i - 1] && profile[i] > profile[i + 1] {
-            peaks.push(i);
-        }
-    }
+    #[test]
+    fn reads_palette_file() {
+        let path = std::env::temp_dir().join(format!(
+            "spritefusion-pixel-snapper-palette-test-{}.gpl",
+            std::process::id()
+        ));
+        std::fs::write(
+            &path,
+            "GIMP Palette\nName: Test\n 13  43  69\tNight\n255 236 214\n",
+        )
+        .unwrap();
+        let command = parse_cli_args(&args(&[
+            "in.png",
+            "out.png",
+            "--palette-file",
+            path.to_str().unwrap(),
+        ]));
+        std::fs::remove_file(&path).unwrap();
+
+        let CliCommand::Run(config) = command.unwrap() else {
+            panic!("expected a processing command");
+        };
+        assert_eq!(config.palette, Some(vec![[13, 43, 69], [255, 236, 214]]));
 
-    if peaks.len() < 2 {
-        return None;
+        let error = parse_cli_args(&args(&[
+            "in.png",
+            "out.png",
+            "--palette-file",
+            "colors.txt",
+        ]))
+        .unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("Failed to read palette file 'colors.txt'"));
     }
 
-    let mut clean_peaks = vec![peaks[0]];
-    for &p in peaks.iter().skip(1) {
-        if p - clean_peaks.last().unwrap() > (config.peak_distance_filter - 1) {
-            clean_peaks.push(p);
-        }
+    #[test]
+    fn parses_palette_export() {
+        let command = parse_cli_args(&args(&[
+            "in.png",
+            "out.png",
+            "--export-palette",
+            "colors.gpl",
+            "--palette-order",
+            "luminance",
+        ]))
+        .unwrap();
+        let CliCommand::Run(config) = command else {
+            panic!("expected a processing command");
+        };
+        assert_eq!(config.palette_export_path.as_deref(), Some("colors.gpl"));
+        assert_eq!(config.palette_order, PaletteOrder::Luminance);
+
+        let error = parse_cli_args(&args(&[
+            "in.png",
+            "out.png",
+            "--export-palette",
+            "colors.txt",
+        ]))
+        .unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("unknown palette format of 'colors.txt'"));
     }
 
-    if clean_peaks.len() < 2 {
-        return None;
+    #[test]
+    fn parses_shared_palette() {
+        let command = parse_cli_args(&args(&["frames", "snapped", "--shared-palette"])).unwrap();
+        let CliCommand::Run(config) = command else {
+            panic!("expected a processing command");
+        };
+        assert!(BatchConfig::from(&config).shared_palette);
+
+        let error = parse_cli_args(&args(&[
+            "frames",
+            "snapped",
+            "--shared-palette",
+            "--palette",
+            "0d2b45",
+        ]))
+        .unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("--shared-palette cannot be combined"));
     }
 
-    // Compute diffs
-    let mut diffs: Vec<f64> = clean_peaks
-        .windows(2)
-        .map(|w| (w[1] - w[0]) as f64)
-        .collect();
+    #[test]
+    fn parses_named_palettes() {
+        let command =
+            parse_cli_args(&args(&["in.png", "out.png", "--palette", "Game-Boy"])).unwrap();
+        let CliCommand::Run(config) = command else {
+            panic!("expected a processing command");
+        };
+        assert_eq!(
+            config.palette(),
+            Some(&[[15, 56, 15], [48, 98, 48], [139, 172, 15], [155, 188, 15]][..])
+        );
+
+        let error =
+            parse_cli_args(&args(&["in.png", "out.png", "--palette", "pico9"])).unwrap_err();
+        assert!(error.to_string().contains("unknown palette 'pico9'"));
+        assert!(matches!(
+            parse_cli_args(&args(&["palettes"])).unwrap(),
+            CliCommand::Palettes
+        ));
+        // A path prefix processes an input file named like the command
+        let CliCommand::Run(config) = parse_cli_args(&args(&["./palettes", "out.png"])).unwrap()
+        else {
+            panic!("expected a processing command");
+        };
+        assert_eq!(config.input_path, "./palettes");
+    }
 
-    // Median
-    diffs.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
-    Some(diffs[diffs.len() / 2])
-}
+    #[test]
+    fn parses_dither() {
+        let command = parse_cli_args(&args(&[
+            "in.png",
+            "out.png",
+            "--palette",
+            "gameboy",
+            "--dither",
+            "bayer4",
+        ]))
+        .unwrap();
+        let CliCommand::Run(config) = command else {
+            panic!("expected a processing command");
+        };
+        assert_eq!(config.dither(), Dither::Bayer4);
 
-fn resolve_step_sizes(
-    step_x_opt: Option<f64>,
-    step_y_opt: Option<f64>,
-    width: u32,
-    height: u32,
-    config: &Config,
-) -> (f64, f64) {
-    if let Some(px) = config.pixel_size_override {
-        return (px, px);
+        let error =
+            parse_cli_args(&args(&["in.png", "out.png", "--dither", "atkinson"])).unwrap_err();
+        assert!(error.to_string().contains("--dither requires"));
+        let error = parse_cli_args(&args(&["in.png", "out.png", "--dither", "noise"])).unwrap_err();
+        assert!(error.to_string().contains("invalid dither 'noise'"));
     }
 
-    match (step_x_opt, step_y_opt) {
-        (Some(sx), Some(sy)) => {
-            let ratio = if sx > sy { sx / sy } else { sy / sx };
-            if ratio > config.max_step_ratio {
-                let smaller = sx.min(sy);
-                (smaller, smaller)
-            } else {
-                let avg = (sx + sy) / 2.0;
-                (avg, avg)
-            }
-        }
+    #[test]
+    fn parses_resample_mode() {
+        let command = parse_cli_args(&args(&[
+            "in.png",
+            "out.png",
+            "--resample",
+            "center-weighted",
+        ]))
+        .unwrap();
+        let CliCommand::Run(config) = command else {
+            panic!("expected a processing command");
+        };
+        assert_eq!(config.resample_mode(), ResampleMode::CenterWeighted);
 
-        (Some(sx), None) => (sx, sx),
+        let error =
+            parse_cli_args(&args(&["in.png", "out.png", "--resample", "mode"])).unwrap_err();
+        assert!(error.to_string().contains("invalid resample mode 'mode'"));
+    }
 
-        (None, Some(sy)) => (sy, sy),
+    #[test]
+    fn parses_alpha_mode() {
+        let modes = [
+            ("keep", AlphaMode::Keep),
+            ("threshold", AlphaMode::Threshold(128)),
+            ("threshold:64", AlphaMode::Threshold(64)),
+            ("levels:3", AlphaMode::Levels(3)),
+            ("alpha-weighted", AlphaMode::AlphaWeighted(128)),
+        ];
+        for (value, mode) in modes {
+            let command = parse_cli_args(&args(&["in.png", "out.png", "--alpha", value])).unwrap();
+            let CliCommand::Run(config) = command else {
+                panic!("expected a processing command");
+            };
+            assert_eq!(config.alpha_mode(), mode);
+        }
 
-        (None, None) => {
-            let fallback_step =
-                ((width.min(height) as f64) / config.fallback_target_segments as f64).max(1.0);
-            (fallback_step, fallback_step)
+        for value in [
+            "levels:1",
+            "threshold:0",
+            "threshold:300",
+            "keep:4",
+            "binary",
+        ] {
+            let error =
+                parse_cli_args(&args(&["in.png", "out.png", "--alpha", value])).unwrap_err();
+            assert!(
+                error.to_string().contains("invalid alpha mode"),
+                "{}",
+                value
+            );
         }
     }
-}
-
-fn stabilize_both_axes(
-    profile_x: &[f64],
-    profile_y: &[f64],
-    raw_col_cuts: Vec<usize>,
-    raw_row_cuts: Vec<usize>,
-    width: usize,
-    height: usize,
-    config: &Config,
-) -> (Vec<usize>, Vec<usize>) {
-    let col_cuts_pass1 = stabilize_cuts(
-        profile_x,
-        raw_col_cuts.clone(),
-        width,
-        &raw_row_cuts,
-        height,
-        config,
-    );
-    let row_cuts_pass1 = stabilize_cuts(
-        profile_y,
-        raw_row_cuts.clone(),
-        height,
-        &raw_col_cuts,
-        width,
-        config,
-    );
-
-    // Check if the results are coherent
-    let col_cells = col_cuts_pass1.len().saturating_sub(1).max(1);
-    let row_cells = row_cuts_pass1.len().saturating_sub(1).max(1);
-    let col_step = width as f64 / col_cells as f64;
-    let row_step = height as f64 / row_cells as f64;
 
-    let step_ratio = if col_step > row_step {
-        col_step / row_step
-    } else {
-        row_step / col_step
-    };
-
-    if step_ratio > config.max_step_ratio {
-        let target_step = col_step.min(row_step);
+    #[test]
+    fn parses_background_key() {
+        let keys = [
+            ("auto", BackgroundKey::Auto),
+            ("#ff00ff", BackgroundKey::Color([255, 0, 255])),
+        ];
+        for (value, key) in keys {
+            let command =
+                parse_cli_args(&args(&["in.png", "out.png", "--key-background", value])).unwrap();
+            let CliCommand::Run(config) = command else {
+                panic!("expected a processing command");
+            };
+            assert_eq!(config.background_key(), Some(key));
+            assert_eq!(config.background_tolerance(), 32.0);
+        }
 
-        let final_col_cuts = if col_step > target_step * 1.2 {
-            snap_uniform_cuts(
-                profile_x,
-                width,
-                target_step,
-                config,
-                config.min_cuts_per_axis,
-            )
-        } else {
-            col_cuts_pass1
+        let command = parse_cli_args(&args(&[
+            "in.png",
+            "out.png",
+            "--key-background",
+            "auto",
+            "--key-tolerance",
+            "60",
+        ]))
+        .unwrap();
+        let CliCommand::Run(config) = command else {
+            panic!("expected a processing command");
         };
+        assert_eq!(config.background_tolerance(), 60.0);
+        assert_eq!(BatchConfig::from(&config).background_tolerance, 60.0);
+
+        let error =
+            parse_cli_args(&args(&["in.png", "out.png", "--key-tolerance", "-1"])).unwrap_err();
+        assert!(error.to_string().contains("invalid --key-tolerance '-1'"));
+
+        let error = parse_cli_args(&args(&[
+            "in.png",
+            "out.png",
+            "--key-background",
+            "ff00ff,000",
+        ]))
+        .unwrap_err();
+        assert!(error.to_string().contains("invalid background key"));
+    }
 
-        let final_row_cuts = if row_step > target_step * 1.2 {
-            snap_uniform_cuts(
-                profile_y,
-                height,
-                target_step,
-                config,
-                config.min_cuts_per_axis,
-            )
-        } else {
-            row_cuts_pass1
-        };
+    #[test]
+    fn analyze_requires_an_input_path() {
+        let error = parse_cli_args(&args(&["analyze"])).unwrap_err();
+        assert!(error.to_string().contains("missing input path"));
+    }
 
-        (final_col_cuts, final_row_cuts)
-    } else {
-        (col_cuts_pass1, row_cuts_pass1)
+    #[test]
+    fn rejects_unknown_options() {
+        let error = parse_cli_args(&args(&["input.png", "output.png", "--unknown"])).unwrap_err();
+        assert!(error.to_string().contains("unknown argument '--unknown'"));
     }
 }
 
-// Tried uniform grid instead of an elastic-ish walker, but the result was a bit worse.
-// Keeping the walker for now. But some distortions might happen...
-fn walk(profile: &[f64], step_size: f64, limit: usize, config: &Config) -> Result<Vec<usize>> {
-    if profile.is_empty() {
-        return Err(PixelSnapperError::ProcessingError(
-            "Cannot walk on empty profile".to_string(),
-        ));
-    }
+#[cfg(test)]
+mod snapper_tests {
+    use super::*;
 
-    let mut cuts = vec![0];
-    let mut current_pos = 0.0;
-    let search_window =
-        (step_size * config.walker_search_window_ratio).max(config.walker_min_search_window);
-    let mean_val: f64 = profile.iter().sum::<f64>() / profile.len() as f64;
+    const SPRITE_COLORS: [[u8; 4]; 4] = [
+        [20, 20, 40, 255],
+        [200, 60, 60, 255],
+        [60, 180, 90, 255],
+        [240, 220, 120, 255],
+    ];
 
-    while current_pos < limit as f64 {
-        let target = current_pos + step_size;
-        if target >= limit as f64 {
-            cuts.push(limit);
-            break;
-        }
+    fn sprite_color(cx: u32, cy: u32) -> [u8; 4] {
+        SPRITE_COLORS[(cx * 7 + cy * 13 + cx * cy) as usize % SPRITE_COLORS.len()]
+    }
 
-        let start_search = ((target - search_window) as usize).max((current_pos + 1.0) as usize);
-        let end_search = ((target + search_window) as usize).min(limit);
+    fn blend(a: [u8; 4], b: [u8; 4]) -> [u8; 4] {
+        std::array::from_fn(|i| ((a[i] as u16 + b[i] as u16) / 2) as u8)
+    }
 
-        if end_search <= start_search {
-            current_pos = target;
-            continue;
-        }
+    /// Builds a `cells_w` x `cells_h` sprite upscaled by `scale`, with a one pixel blend on
+    /// the leading edge of every cell like a smooth AI upscale.
+    fn upscaled_sprite(cells_w: u32, cells_h: u32, scale: u32) -> RgbaImage {
+        upscaled_sprite_xy(cells_w, cells_h, scale, scale)
+    }
 
-        let mut max_val = -1.0;
-        let mut max_idx = start_search;
-        for (i, &value) in profile
-            .iter()
-            .enumerate()
-            .take(end_search)
-            .skip(start_search)
-        {
-            if value > max_val {
-                max_val = value;
-                max_idx = i;
+    fn upscaled_sprite_xy(cells_w: u32, cells_h: u32, scale_x: u32, scale_y: u32) -> RgbaImage {
+        RgbaImage::from_fn(cells_w * scale_x, cells_h * scale_y, |x, y| {
+            let (cx, cy) = (x / scale_x, y / scale_y);
+            let mut color = sprite_color(cx, cy);
+            if x % scale_x == 0 && cx > 0 {
+                color = blend(color, sprite_color(cx - 1, cy));
             }
-        }
-
-        if max_val > mean_val * config.walker_strength_threshold {
-            cuts.push(max_idx);
-            current_pos = max_idx as f64;
-        } else {
-            cuts.push(target as usize);
-            current_pos = target;
-        }
+            if y % scale_y == 0 && cy > 0 {
+                color = blend(color, sprite_color(cx, cy - 1));
+            }
+            Rgba(color)
+        })
     }
-    Ok(cuts)
-}
 
-fn stabilize_cuts(
-    profile: &[f64],
-    cuts: Vec<usize>,
-    limit: usize,
-    sibling_cuts: &[usize],
-    sibling_limit: usize,
-    config: &Config,
-) -> Vec<usize> {
-    if limit == 0 {
-        return vec![0];
+    #[test]
+    fn autocorrelation_detects_hard_edged_upscales() {
+        // Without blended edges every boundary is a two sample plateau that the peak
+        // detector skips
+        let img = RgbaImage::from_fn(16 * 8, 12 * 8, |x, y| Rgba(sprite_color(x / 8, y / 8)));
+        let peaks = Snapper::new(Config::default()).analyze(&img).unwrap();
+        assert_eq!(peaks.grid.step_source, StepSource::Fallback);
+
+        for estimator in [StepEstimator::Autocorrelation, StepEstimator::Auto] {
+            let config = Config::default().with_step_estimator(estimator);
+            let analysis = Snapper::new(config).analyze(&img).unwrap();
+            assert_eq!((analysis.grid.step_x, analysis.grid.step_y), (8.0, 8.0));
+            assert_eq!(analysis.grid.step_source, StepSource::BothAxes);
+        }
     }
 
-    let cuts = sanitize_cuts(cuts, limit);
-    let min_required = config.min_cuts_per_axis.max(2).min(limit.saturating_add(1));
-    let axis_cells = cuts.len().saturating_sub(1);
-    let sibling_cells = sibling_cuts.len().saturating_sub(1);
-    let sibling_has_grid =
-        sibling_limit > 0 && sibling_cells >= min_required.saturating_sub(1) && sibling_cells > 0;
-    let steps_skewed = sibling_has_grid && axis_cells > 0 && {
-        let axis_step = limit as f64 / axis_cells as f64;
-        let sibling_step = sibling_limit as f64 / sibling_cells as f64;
-        let step_ratio = axis_step / sibling_step;
-        step_ratio > config.max_step_ratio || step_ratio < 1.0 / config.max_step_ratio
-    };
-    let has_enough = cuts.len() >= min_required;
+    #[test]
+    fn auto_estimator_keeps_the_step_the_profile_repeats_at() {
+        let estimate = |profile: &[f64], estimator| {
+            estimate_step_size(profile, &Config::default().with_step_estimator(estimator))
+        };
 
-    if has_enough && !steps_skewed {
-        return cuts;
+        // A plateau halfway through every 8 sample cell correlates well enough at 4 samples
+        // to be taken as the step, while the peak detector skips plateaus
+        let plateaus: Vec<f64> = (0..96)
+            .map(|i| match i % 8 {
+                0 => 10.0,
+                4 | 5 => 7.0,
+                _ => 0.0,
+            })
+            .collect();
+        assert_eq!(
+            estimate(&plateaus, StepEstimator::Autocorrelation),
+            Some(4.0)
+        );
+        assert_eq!(estimate(&plateaus, StepEstimator::Peaks), Some(8.0));
+        assert_eq!(estimate(&plateaus, StepEstimator::Auto), Some(8.0));
+
+        // Noise between 10 sample edges halves the median peak distance but does not repeat
+        let noisy: Vec<f64> = (0..120)
+            .map(|i| {
+                if i % 10 == 0 {
+                    10.0
+                } else {
+                    0.3 * ((i * 37) % 11) as f64
+                }
+            })
+            .collect();
+        assert_eq!(estimate(&noisy, StepEstimator::Autocorrelation), Some(10.0));
+        assert_eq!(estimate(&noisy, StepEstimator::Peaks), Some(5.0));
+        assert_eq!(estimate(&noisy, StepEstimator::Auto), Some(10.0));
     }
 
-    let mut target_step = if sibling_has_grid {
-        sibling_limit as f64 / sibling_cells as f64
-    } else if config.fallback_target_segments > 1 {
-        limit as f64 / config.fallback_target_segments as f64
-    } else if axis_cells > 0 {
-        limit as f64 / axis_cells as f64
-    } else {
-        limit as f64
-    };
-    if !target_step.is_finite() || target_step <= 0.0 {
-        target_step = 1.0;
+    #[test]
+    fn subpixel_steps_follow_fractional_upscales() {
+        let small = RgbaImage::from_fn(40, 30, |x, y| {
+            let hash = (x.wrapping_mul(73_856_093) ^ y.wrapping_mul(19_349_663))
+                .wrapping_mul(2_654_435_761);
+            Rgba(SPRITE_COLORS[(hash >> 28) as usize % SPRITE_COLORS.len()])
+        });
+        // 7.4px cells alternate between 7 and 8 pixels wide
+        let img = image::imageops::resize(&small, 296, 222, image::imageops::FilterType::Nearest);
+        let config = Config::default().with_step_estimator(StepEstimator::Autocorrelation);
+
+        let whole = Snapper::new(config.clone()).detect_grid(&img).unwrap();
+        assert_eq!(whole.step_x, 7.0);
+        assert_ne!(whole.output_width(), 40);
+
+        let result = Snapper::new(config.with_subpixel(true)).snap(&img).unwrap();
+        assert!((result.grid.step_x - 7.4).abs() < 0.05);
+        assert!((result.grid.step_y - 7.4).abs() < 0.05);
+        assert_eq!(result.image, small);
     }
 
-    snap_uniform_cuts(profile, limit, target_step, config, min_required)
-}
+    #[test]
+    fn color_profiles_see_edges_without_luminance_change() {
+        // Opaque black and transparent cells are both zero luminance
+        let black = [0, 0, 0, 255];
+        let cell = |cx: u32, cy: u32| {
+            if (cx * 7 + cy * 13 + cx * cy) % 3 == 0 {
+                black
+            } else {
+                [0, 0, 0, 0]
+            }
+        };
+        let img = RgbaImage::from_fn(16 * 8, 12 * 8, |x, y| {
+            let (cx, cy) = (x / 8, y / 8);
+            let mut color = cell(cx, cy);
+            if x % 8 == 0 && cx > 0 {
+                color = blend(color, cell(cx - 1, cy));
+            }
+            if y % 8 == 0 && cy > 0 {
+                color = blend(color, cell(cx, cy - 1));
+            }
+            Rgba(color)
+        });
 
-fn sanitize_cuts(mut cuts: Vec<usize>, limit: usize) -> Vec<usize> {
-    if limit == 0 {
-        return vec![0];
+        let luminance = Snapper::new(Config::default()).detect_grid(&img).unwrap();
+        assert_eq!(luminance.step_source, StepSource::Fallback);
+
+        let config = Config::default().with_profile_mode(ProfileMode::Color);
+        let grid = Snapper::new(config).detect_grid(&img).unwrap();
+        assert_eq!((grid.step_x, grid.step_y), (8.0, 8.0));
+        assert_eq!((grid.output_width(), grid.output_height()), (16, 12));
     }
 
-    let mut has_zero = false;
-    let mut has_limit = false;
+    #[test]
+    fn color_profiles_see_iso_luminant_hue_stripes() {
+        // Orange, green and blue with exactly the same luminance
+        let hues = [[148, 76, 10, 255], [6, 146, 22, 255], [0, 104, 254, 255]];
+        let img = RgbaImage::from_fn(12 * 8, 24, |x, _| Rgba(hues[(x / 8) as usize % 3]));
+        let config = Config::default().with_step_estimator(StepEstimator::Autocorrelation);
+
+        let luminance = Snapper::new(config.clone()).detect_grid(&img).unwrap();
+        assert!(luminance.profile_x.iter().all(|&v| v == 0.0));
+        assert_eq!(luminance.step_source, StepSource::Fallback);
+
+        let config = config.with_profile_mode(ProfileMode::Color);
+        let grid = Snapper::new(config).detect_grid(&img).unwrap();
+        assert_eq!(grid.step_source, StepSource::ColumnsOnly);
+        assert_eq!(grid.step_x, 8.0);
+        // Hard edges are two samples wide, so cuts land on either side of them
+        assert!(grid.col_cuts.len() >= 13);
+        assert!(grid.col_cuts.iter().all(|&cut| matches!(cut % 8, 0 | 7)));
+    }
 
-    for value in cuts.iter_mut() {
-        if *value == 0 {
-            has_zero = true;
-        }
-        if *value >= limit {
-            *value = limit;
-        }
-        if *value == limit {
-            has_limit = true;
+    #[test]
+    fn every_quantizer_snaps_the_sprite() {
+        let img = upscaled_sprite(16, 12, 8);
+        let expected = Snapper::new(Config::default()).snap(&img).unwrap();
+        for kind in [
+            QuantizerKind::MedianCut,
+            QuantizerKind::Octree,
+            QuantizerKind::Wu,
+        ] {
+            let config = Config::default().with_quantizer(kind);
+            let result = Snapper::new(config).snap(&img).unwrap();
+            assert_eq!(result.image, expected.image, "{:?}", kind);
         }
     }
 
-    if !has_zero {
-        cuts.push(0);
-    }
-    if !has_limit {
-        cuts.push(limit);
+    #[test]
+    fn auto_colors_picks_the_sprite_colors() {
+        let img = RgbaImage::from_fn(16 * 8, 12 * 8, |x, y| Rgba(sprite_color(x / 8, y / 8)));
+        let config = Config::default().with_auto_colors(32);
+        let quantized = Snapper::new(config).quantize(&img).unwrap();
+        assert_eq!(quantized.k_colors, SPRITE_COLORS.len());
+        assert_eq!(image_palette(&quantized.image).len(), SPRITE_COLORS.len());
     }
 
-    cuts.sort_unstable();
-    cuts.dedup();
-    cuts
-}
+    #[test]
+    fn accent_cells_survive_quantization() {
+        // Three magenta cells are too few for Wu to give them a box of their own
+        const ACCENT: [u8; 4] = [255, 0, 255, 255];
+        let accent_cells = [(2, 3), (9, 7), (13, 1)];
+        let mut img = upscaled_sprite(16, 12, 8);
+        for (cx, cy) in accent_cells {
+            for y in cy * 8 + 1..(cy + 1) * 8 {
+                for x in cx * 8 + 1..(cx + 1) * 8 {
+                    img.put_pixel(x, y, Rgba(ACCENT));
+                }
+            }
+        }
+        let has_accent = |result: &SnapResult| result.image.pixels().any(|p| p.0 == ACCENT);
 
-fn snap_uniform_cuts(
-    profile: &[f64],
-    limit: usize,
-    target_step: f64,
-    config: &Config,
-    min_required: usize,
-) -> Vec<usize> {
-    if limit == 0 {
-        return vec![0];
-    }
-    if limit == 1 {
-        return vec![0, 1];
+        let config = Config {
+            k_colors: 4,
+            ..Config::default()
+        }
+        .with_quantizer(QuantizerKind::Wu);
+        let plain = Snapper::new(config.clone()).snap(&img).unwrap();
+        assert!(!has_accent(&plain));
+
+        let kept = Snapper::new(config.clone().with_accent_cells(3))
+            .snap(&img)
+            .unwrap();
+        assert!(has_accent(&kept));
+        assert_eq!(kept.palette.len(), 4);
+        assert_eq!(kept.image.dimensions(), (16, 12));
+
+        let ignored = Snapper::new(config.with_accent_cells(4))
+            .snap(&img)
+            .unwrap();
+        assert!(!has_accent(&ignored));
     }
 
-    // Get desired cells
-    let mut desired_cells = if target_step.is_finite() && target_step > 0.0 {
-        (limit as f64 / target_step).round() as usize
-    } else {
-        0
-    };
-    desired_cells = desired_cells
-        .max(min_required.saturating_sub(1))
-        .max(1)
-        .min(limit);
-
-    let cell_width = limit as f64 / desired_cells as f64;
-    let search_window =
-        (cell_width * config.walker_search_window_ratio).max(config.walker_min_search_window);
-    let mean_val = if profile.is_empty() {
-        0.0
-    } else {
-        profile.iter().sum::<f64>() / profile.len() as f64
-    };
+    #[test]
+    fn noisy_accent_cells_survive_quantization() {
+        // No two pixels of the magenta cells match exactly, so only their median color
+        // stands out from the blended cell borders
+        let accent_cells = [(2, 3), (9, 7), (13, 1)];
+        let mut img = upscaled_sprite(16, 12, 8);
+        for (cx, cy) in accent_cells {
+            for y in cy * 8 + 1..(cy + 1) * 8 {
+                for x in cx * 8 + 1..(cx + 1) * 8 {
+                    let (dx, dy) = ((x % 8) as u8, (y % 8) as u8);
+                    img.put_pixel(x, y, Rgba([255 - dx * 3, dy * 3, 250 - dx - dy, 255]));
+                }
+            }
+        }
+        let is_accent = |p: &Rgba<u8>| p[0] > 200 && p[1] < 60 && p[2] > 200;
 
-    let mut cuts = Vec::with_capacity(desired_cells + 1);
-    cuts.push(0);
-    for idx in 1..desired_cells {
-        let target = cell_width * idx as f64;
-        let prev = *cuts.last().unwrap();
-        if prev + 1 >= limit {
-            break;
+        let config = Config {
+            k_colors: 4,
+            ..Config::default()
         }
-        let mut start = ((target - search_window).floor() as isize)
-            .max(prev as isize + 1)
-            .max(0);
-        let mut end = ((target + search_window).ceil() as isize).min(limit as isize - 1);
-        if end < start {
-            start = prev as isize + 1;
-            end = start;
+        .with_quantizer(QuantizerKind::Wu)
+        .with_accent_cells(3);
+        let kept = Snapper::new(config).snap(&img).unwrap();
+        assert_eq!(kept.palette.len(), 4);
+        for (cx, cy) in accent_cells {
+            assert!(is_accent(kept.image.get_pixel(cx, cy)));
         }
-        let start = start as usize;
-        let end = end as usize;
-        let mut best_idx = start.min(profile.len().saturating_sub(1));
-        let mut best_val = -1.0;
-        for i in start..=end.min(profile.len().saturating_sub(1)) {
-            let v = profile.get(i).copied().unwrap_or(0.0);
-            if v > best_val {
-                best_val = v;
-                best_idx = i;
+    }
+
+    #[test]
+    fn snap_first_quantizes_the_cells() {
+        // Two pixel wide blurred borders, blended with the previous cell on each axis
+        let mut img = RgbaImage::from_fn(16 * 8, 12 * 8, |x, y| {
+            let (cx, cy) = (x / 8, y / 8);
+            let mut color = sprite_color(cx, cy);
+            if x % 8 < 2 && cx > 0 {
+                color = blend(color, sprite_color(cx - 1, cy));
             }
-        }
-        let strength_threshold = mean_val * config.walker_strength_threshold;
-        if best_val < strength_threshold {
-            let mut fallback_idx = target.round() as isize;
-            if fallback_idx <= prev as isize {
-                fallback_idx = prev as isize + 1;
+            if y % 8 < 2 && cy > 0 {
+                color = blend(color, sprite_color(cx, cy - 1));
             }
-            if fallback_idx >= limit as isize {
-                fallback_idx = (limit as isize - 1).max(prev as isize + 1);
+            Rgba(color)
+        });
+        // A transparent corner cell, with a few stray opaque pixels
+        for y in 0..8 {
+            for x in 0..8 {
+                img.put_pixel(x, y, Rgba(if x == y { [255; 4] } else { [0; 4] }));
             }
-            best_idx = fallback_idx as usize;
         }
-        cuts.push(best_idx);
+        let config = Config {
+            k_colors: SPRITE_COLORS.len(),
+            // Blurred borders confuse detection, so give the grid
+            cuts: Some((
+                (0..=128).step_by(8).collect(),
+                (0..=96).step_by(8).collect(),
+            )),
+            ..Config::default()
+        };
+        let is_sprite_color = |p: &[u8; 3]| SPRITE_COLORS.iter().any(|c| c[..3] == p[..]);
+
+        let snapper = Snapper::new(config.clone().with_pipeline_order(PipelineOrder::SnapFirst));
+        let result = snapper.snap(&img).unwrap();
+        assert_eq!(result.image.dimensions(), (16, 12));
+        assert_eq!(result.image.get_pixel(0, 0)[3], 0);
+        for (x, y, pixel) in result.image.enumerate_pixels().skip(1) {
+            assert_eq!(pixel.0, sprite_color(x, y), "cell {},{}", x, y);
+        }
+        assert_eq!(result.palette.len(), SPRITE_COLORS.len());
+        assert!(result.palette.iter().all(is_sprite_color));
+
+        // Quantizing the full image first lets the blended pixels pull the palette away
+        let result = Snapper::new(config).snap(&img).unwrap();
+        assert!(!result.palette.iter().all(is_sprite_color));
     }
-    if *cuts.last().unwrap() != limit {
-        cuts.push(limit);
+
+    #[test]
+    fn shared_palette_gives_frames_the_same_colors() {
+        // Each frame is a slightly different shade of the sprite, as separate generations are
+        let shade = |offset: u8| {
+            let mut img = upscaled_sprite(12, 12, 6);
+            for p in img.pixels_mut() {
+                p[0] = p[0].saturating_add(offset);
+            }
+            img
+        };
+        let frames = [shade(0), shade(6)];
+        let config = Config {
+            k_colors: 4,
+            ..Config::default()
+        };
+        let palette = Snapper::new(config.clone())
+            .shared_palette(&frames)
+            .unwrap();
+        assert_eq!(palette.len(), 4);
+
+        let snapper = Snapper::new(config.with_palette(palette));
+        let mut first = snapper.snap(&frames[0]).unwrap().palette;
+        let mut second = snapper.snap(&frames[1]).unwrap().palette;
+        first.sort();
+        second.sort();
+        assert_eq!(first, second);
     }
-    cuts = sanitize_cuts(cuts, limit);
-    cuts
-}
 
-fn resample(img: &RgbaImage, cols: &[usize], rows: &[usize]) -> Result<RgbaImage> {
-    if cols.len() < 2 || rows.len() < 2 {
-        return Err(PixelSnapperError::ProcessingError(
-            "Insufficient grid cuts for resampling".to_string(),
-        ));
+    #[test]
+    fn resample_modes_pick_the_cell_color() {
+        // Black and white blends cover more of each cell than its gray center
+        const BLACK: [u8; 4] = [0, 0, 0, 255];
+        const GRAY: [u8; 4] = [128, 128, 128, 255];
+        const WHITE: [u8; 4] = [255, 255, 255, 255];
+        let row = [BLACK, BLACK, BLACK, GRAY, GRAY, WHITE, WHITE, WHITE];
+        let img = RgbaImage::from_fn(16, 8, |x, _| Rgba(row[x as usize % 8]));
+        let config = Config::default().with_cuts(vec![0, 8, 16], vec![0, 8]);
+        let grid = Snapper::new(config.clone()).detect_grid(&img).unwrap();
+        let quantized = Snapper::new(config.clone()).quantize(&img).unwrap();
+        assert_eq!(quantized.image, img);
+
+        let modes = [
+            (ResampleMode::Majority, BLACK),
+            (ResampleMode::CenterWeighted, GRAY),
+            (ResampleMode::Center, GRAY),
+            (ResampleMode::Median, GRAY),
+            (ResampleMode::Mean, GRAY),
+        ];
+        for (mode, expected) in modes {
+            let snapper = Snapper::new(config.clone().with_resample_mode(mode));
+            let snapped = snapper.resample(&quantized, &grid).unwrap();
+            assert_eq!(snapped.dimensions(), (2, 1));
+            assert!(snapped.pixels().all(|p| p.0 == expected), "{:?}", mode);
+        }
+
+        // Means snap straight to a configured palette
+        let palette = vec![[0, 0, 0], [100, 100, 100], [255, 255, 255]];
+        let config = config
+            .with_resample_mode(ResampleMode::Mean)
+            .with_palette(palette);
+        let snapped = Snapper::new(config).resample(&quantized, &grid).unwrap();
+        assert!(snapped.pixels().all(|p| p.0 == [100, 100, 100, 255]));
     }
-    let out_w = (cols.len().max(1) - 1) as u32;
-    let out_h = (rows.len().max(1) - 1) as u32;
-    let mut final_img: RgbaImage = ImageBuffer::new(out_w, out_h);
 
-    for (y_i, w_y) in rows.windows(2).enumerate() {
-        for (x_i, w_x) in cols.windows(2).enumerate() {
-            let ys = w_y[0];
-            let ye = w_y[1];
-            let xs = w_x[0];
-            let xe = w_x[1];
+    #[test]
+    fn alpha_modes_clean_edges() {
+        let alphas = [0, 37, 112, 200, 255];
+        let img = RgbaImage::from_fn(5, 1, |x, _| Rgba([90, 60, 30, alphas[x as usize]]));
+        let cleaned_alphas = |mode| {
+            let snapper = Snapper::new(Config::default().with_alpha_mode(mode));
+            let cleaned = snapper.clean_alpha(&img);
+            if mode != AlphaMode::Keep {
+                assert!(cleaned.pixels().all(|p| p[3] != 0 || p.0 == [0, 0, 0, 0]));
+            }
+            cleaned.pixels().map(|p| p[3]).collect::<Vec<_>>()
+        };
 
-            if xe <= xs || ye <= ys {
-                continue;
+        assert_eq!(cleaned_alphas(AlphaMode::Keep), alphas);
+        assert_eq!(
+            cleaned_alphas(AlphaMode::Threshold(128)),
+            [0, 0, 0, 255, 255]
+        );
+        assert_eq!(cleaned_alphas(AlphaMode::Levels(3)), [0, 0, 128, 255, 255]);
+        assert_eq!(
+            cleaned_alphas(AlphaMode::AlphaWeighted(64)),
+            [0, 0, 112, 200, 255]
+        );
+    }
+
+    #[test]
+    fn alpha_weighted_pixels_weigh_the_palette() {
+        // Half faint red edge pixels, half opaque blue ones
+        let img = RgbaImage::from_fn(8, 8, |x, _| {
+            if x < 4 {
+                Rgba([255, 0, 0, 100])
+            } else {
+                Rgba([0, 0, 255, 255])
             }
+        });
+        let quantize = |mode| {
+            let config = Config {
+                k_colors: 1,
+                ..Config::default()
+            };
+            let snapper = Snapper::new(config.with_alpha_mode(mode));
+            snapper.quantize(&img).unwrap().image
+        };
+
+        let flat = quantize(AlphaMode::Threshold(64));
+        let weighted = quantize(AlphaMode::AlphaWeighted(64));
+        assert!(weighted.pixels().all(|p| p[3] == 255));
+        let (flat, weighted) = (flat.get_pixel(0, 0), weighted.get_pixel(0, 0));
+        assert!(flat[0].abs_diff(flat[2]) < 8);
+        assert!(weighted[2] > weighted[0] + 64);
+
+        // Each pixel is a single point weighted by its alpha, wherever it is
+        let points = palette_points(&img, ColorSpace::Srgb, AlphaMode::AlphaWeighted(64));
+        assert_eq!(points.colors.len(), 64);
+        let weights = points.weights.unwrap();
+        assert_eq!((weights[0], weights[4]), (100.0 / 255.0, 1.0));
+        assert!(
+            palette_points(&img, ColorSpace::Srgb, AlphaMode::Threshold(64))
+                .weights
+                .is_none()
+        );
+
+        // Without visible pixels the image is still cleaned
+        let faint = RgbaImage::from_pixel(4, 4, Rgba([255, 0, 0, 30]));
+        let snapper = Snapper::new(Config::default().with_alpha_mode(AlphaMode::Threshold(64)));
+        let quantized = snapper.quantize(&faint).unwrap();
+        assert!(quantized.image.pixels().all(|p| p.0 == [0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn keyed_background_takes_no_palette_slot() {
+        // The sprite sits on a flat magenta margin of two cells
+        const MAGENTA: [u8; 4] = [255, 0, 255, 255];
+        let sprite = upscaled_sprite(12, 12, 6);
+        let img = RgbaImage::from_fn(16 * 6, 16 * 6, |x, y| {
+            let inside = (12..84).contains(&x) && (12..84).contains(&y);
+            Rgba(if inside {
+                sprite.get_pixel(x - 12, y - 12).0
+            } else {
+                MAGENTA
+            })
+        });
+        let config = Config {
+            k_colors: 4,
+            ..Config::default()
+        };
+        let snap = |config: Config| Snapper::new(config).snap(&img).unwrap();
+
+        let plain = snap(config.clone());
+        assert!(plain.palette.contains(&[255, 0, 255]));
+
+        let config = config.with_background_key(BackgroundKey::Auto);
+        let keyed = snap(config.clone());
+        assert_eq!(keyed.image.get_pixel(0, 0)[3], 0);
+        assert_eq!(keyed.palette.len(), 4);
+        assert!(!keyed.palette.contains(&[255, 0, 255]));
+
+        // The overlay shows the keyed image the grid was detected on
+        let overlay = Snapper::new(config.clone())
+            .debug_overlay(&img, &keyed.grid)
+            .unwrap();
+        assert_ne!(overlay.get_pixel(1, 1).0, MAGENTA);
+
+        // Empty images are rejected before keying looks at their border
+        let snapper = Snapper::new(config.clone());
+        let empty = RgbaImage::new(0, 12);
+        for error in [
+            snapper.snap(&empty).unwrap_err(),
+            snapper.analyze(&empty).unwrap_err(),
+        ] {
+            assert!(matches!(error, PixelSnapperError::InvalidInput(_)));
+        }
+        assert_eq!(snapper.key_background(&empty).dimensions(), (0, 12));
 
-            let mut counts: HashMap<[u8; 4], usize> = HashMap::new();
+        assert!(config.with_background_tolerance(-1.0).is_err());
+    }
+
+    #[test]
+    fn majority_ties_go_to_the_lowest_color() {
+        let (high, low) = ([200, 10, 10, 255], [10, 200, 10, 255]);
+        let img = RgbaImage::from_fn(4, 2, |x, _| Rgba(if x % 2 == 0 { high } else { low }));
+        let out = resample(&img, &[0, 2, 4], &[0, 2]).unwrap();
+        assert!(out.pixels().all(|p| p.0 == low));
+
+        // Unquantized images count by sorting and must settle ties the same way
+        for colors in [4, MAX_TALLY_COLORS + 1] {
+            let mut tally = CellTally::new(colors);
+            tally.add(&[3, 1, 3, 1, 2]);
+            assert_eq!(tally.take(), Some(1));
+            assert_eq!(tally.take(), None);
+        }
+    }
 
-            for y in ys..ye {
-                for x in xs..xe {
-                    if x < img.width() as usize && y < img.height() as usize {
-                        let p = img.get_pixel(x as u32, y as u32).0;
-                        *counts.entry(p).or_insert(0) += 1;
+    #[test]
+    fn resample_matches_a_counting_reference() {
+        // Majority by counting every cell in a map, as resample did before indexing colors
+        fn reference(img: &RgbaImage, cols: &[usize], rows: &[usize]) -> RgbaImage {
+            let mut out = RgbaImage::new(cols.len() as u32 - 1, rows.len() as u32 - 1);
+            for (y_i, w_y) in rows.windows(2).enumerate() {
+                for (x_i, w_x) in cols.windows(2).enumerate() {
+                    let mut counts: HashMap<[u8; 4], usize> = HashMap::new();
+                    for y in w_y[0]..w_y[1].min(img.height() as usize) {
+                        for x in w_x[0]..w_x[1].min(img.width() as usize) {
+                            *counts
+                                .entry(img.get_pixel(x as u32, y as u32).0)
+                                .or_insert(0) += 1;
+                        }
                     }
+                    let mut candidates: Vec<([u8; 4], usize)> = counts.into_iter().collect();
+                    candidates.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+                    let pixel = candidates.first().map_or([0; 4], |c| c.0);
+                    out.put_pixel(x_i as u32, y_i as u32, Rgba(pixel));
                 }
             }
+            out
+        }
 
-            let mut best_pixel = [0, 0, 0, 0];
-
-            let mut candidates: Vec<([u8; 4], usize)> = counts.into_iter().collect();
-            candidates.sort_by(|a, b| {
-                let count_cmp = b.1.cmp(&a.1);
-                if count_cmp == Ordering::Equal {
-                    a.0.cmp(&b.0)
+        let mut seed = 0x2545_f491_4f6c_dd1du64;
+        let mut next = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed
+        };
+        // Uneven cells, an empty one and cuts past the image edge
+        let cols = [0, 3, 4, 41, 41, 120, 133, 247, 260, 398, 410];
+        let rows = [0, 5, 6, 117, 230, 244, 352, 401, 420];
+        let common: Vec<[u8; 4]> = (0..6)
+            .map(|_| next().to_le_bytes()[..4].try_into().unwrap())
+            .collect();
+        // Half of the pixels take one of a few common colors so cells have a majority, the
+        // rest take random colors, enough of them to be counted by sorting
+        for random_share in [0, 50] {
+            let img = RgbaImage::from_fn(400, 400, |_, _| {
+                let value = next();
+                if value % 100 < random_share {
+                    Rgba((value >> 32).to_le_bytes()[..4].try_into().unwrap())
                 } else {
-                    count_cmp
+                    Rgba(common[(value >> 8) as usize % common.len()])
                 }
             });
-
-            if let Some(winner) = candidates.first() {
-                best_pixel = winner.0;
+            if random_share > 0 {
+                assert!(index_image(&img).0.len() > MAX_TALLY_COLORS);
             }
-
-            final_img.put_pixel(x_i as u32, y_i as u32, Rgba(best_pixel));
+            let out = resample(&img, &cols, &rows).unwrap();
+            assert_eq!(
+                out,
+                reference(&img, &cols, &rows),
+                "{}% random",
+                random_share
+            );
         }
     }
-    Ok(final_img)
-}
 
-#[cfg(all(test, not(target_arch = "wasm32")))]
-mod cli_tests {
-    use super::*;
+    #[test]
+    fn snap_recovers_the_original_resolution() {
+        let img = upscaled_sprite(16, 12, 8);
+        let result = Snapper::new(Config::default()).snap(&img).unwrap();
+        assert_eq!(result.image.dimensions(), (16, 12));
+        assert_eq!(
+            result.image.get_pixel(3, 5),
+            img.get_pixel(3 * 8 + 4, 5 * 8 + 4)
+        );
+        assert_eq!(result.grid.step_source, StepSource::BothAxes);
+        assert_eq!(result.grid.col_cuts.first(), Some(&0));
+        assert_eq!(result.grid.col_cuts.last(), Some(&(16 * 8)));
+    }
 
-    fn args(values: &[&str]) -> Vec<String> {
-        values.iter().map(|value| (*value).to_string()).collect()
+    #[test]
+    fn snap_result_palette_lists_output_colors() {
+        let img = upscaled_sprite(12, 12, 6);
+        let result = Snapper::new(Config::default()).snap(&img).unwrap();
+        let mut palette = result.palette.clone();
+        palette.sort();
+        let mut expected: Vec<[u8; 3]> = SPRITE_COLORS.iter().map(|c| [c[0], c[1], c[2]]).collect();
+        expected.sort();
+        assert_eq!(palette, expected);
+        assert_eq!(
+            parse_palette_hex(&format_palette_hex(&result.palette)).unwrap(),
+            result.palette
+        );
     }
 
     #[test]
-    fn no_arguments_show_help() {
-        assert!(matches!(parse_cli_args(&[]).unwrap(), CliCommand::Help));
+    fn stages_compose_into_snap() {
+        let img = upscaled_sprite(16, 16, 6);
+        let snapper =
+            Snapper::new(Config::default().with_palette(vec![[0, 0, 0], [255, 255, 255]]));
+
+        let quantized = snapper.quantize(&img).unwrap();
+        assert!(quantized.centroids.len() <= snapper.config().k_colors);
+        let grid = snapper.detect_grid(&quantized.image).unwrap();
+        assert_eq!((grid.output_width(), grid.output_height()), (16, 16));
+        let snapped = snapper.resample(&quantized, &grid).unwrap();
+        let recolored = snapper.recolor(&snapped).unwrap();
+
+        assert_eq!(recolored, snapper.snap(&img).unwrap().image);
     }
 
     #[test]
-    fn help_and_version_flags_are_recognized() {
-        assert!(matches!(
-            parse_cli_args(&args(&["--help"])).unwrap(),
-            CliCommand::Help
-        ));
+    fn analyze_reports_a_confident_grid() {
+        let img = upscaled_sprite(16, 12, 8);
+        let analysis = Snapper::new(Config::default()).analyze(&img).unwrap();
+        assert_eq!((analysis.input_width, analysis.input_height), (128, 96));
+        assert_eq!(analysis.grid.output_width(), 16);
+        assert!(analysis.grid.confidence.overall > 0.9);
+
+        let json = analysis.to_json();
+        assert!(json.contains("\"output_width\": 16"));
+        assert!(json.contains("\"col_cuts\": [0, 8, 16,"));
+
+        // Snap-first only counts the colors of the cells
+        let config = Config::default()
+            .with_auto_colors(16)
+            .with_pipeline_order(PipelineOrder::SnapFirst);
+        let analysis = Snapper::new(config.clone()).analyze(&img).unwrap();
+        let result = Snapper::new(config).snap(&img).unwrap();
+        assert_eq!(analysis.k_colors, result.k_colors);
+        assert_eq!(analysis.k_colors, SPRITE_COLORS.len());
+    }
+
+    #[test]
+    fn fallback_grid_has_zero_confidence() {
+        let img = RgbaImage::from_pixel(64, 64, Rgba([10, 20, 30, 255]));
+        let grid = Snapper::new(Config::default()).detect_grid(&img).unwrap();
+        assert_eq!(grid.step_source, StepSource::Fallback);
+        assert_eq!(grid.confidence.overall, 0.0);
+    }
+
+    #[test]
+    fn min_confidence_is_a_fraction() {
+        for threshold in [f64::NAN, -0.1, 1.5] {
+            assert!(Config::default().with_min_confidence(threshold).is_err());
+        }
+    }
+
+    #[test]
+    fn forced_steps_keep_their_confidence() {
+        // A plain checkerboard of 8 pixel cells yields no estimate on either axis
+        let img = RgbaImage::from_fn(64, 64, |x, y| {
+            Rgba(if (x / 8 + y / 8) % 2 == 0 {
+                [0, 0, 0, 255]
+            } else {
+                [255; 4]
+            })
+        });
+        let config = Config {
+            pixel_size_override: Some(8.0),
+            ..Config::default()
+        };
+        let grid = Snapper::new(config).detect_grid(&img).unwrap();
+        assert_eq!(grid.confidence.x.step_agreement, 1.0);
+        assert!(grid.confidence.overall > 0.5);
+    }
+
+    #[test]
+    fn snap_fails_below_min_confidence() {
+        let img = RgbaImage::from_pixel(64, 64, Rgba([10, 20, 30, 255]));
+        let error = Snapper::new(Config::default().with_min_confidence(0.5).unwrap())
+            .snap(&img)
+            .unwrap_err();
         assert!(matches!(
-            parse_cli_args(&args(&["--version"])).unwrap(),
-            CliCommand::Version
+            error,
+            PixelSnapperError::LowConfidence { threshold, .. } if threshold == 0.5
         ));
+
+        let img = upscaled_sprite(16, 12, 8);
+        assert!(
+            Snapper::new(Config::default().with_min_confidence(0.5).unwrap())
+                .snap(&img)
+                .is_ok()
+        );
     }
 
     #[test]
-    fn output_path_is_required() {
-        let error = parse_cli_args(&args(&["input.png"])).unwrap_err();
-        assert!(matches!(error, PixelSnapperError::InvalidInput(_)));
-        assert!(error.to_string().contains("missing output path"));
+    fn non_square_pixels_keep_their_aspect_when_allowed() {
+        let img = upscaled_sprite_xy(16, 24, 12, 6);
+
+        let grid = Snapper::new(Config::default().with_non_square(true))
+            .detect_grid(&img)
+            .unwrap();
+        assert_eq!(grid.step_source, StepSource::NonSquare);
+        assert_eq!((grid.step_x, grid.step_y), (12.0, 6.0));
+        assert_eq!((grid.output_width(), grid.output_height()), (16, 24));
+
+        let grid = Snapper::new(Config::default()).detect_grid(&img).unwrap();
+        assert_eq!(grid.step_x, grid.step_y);
     }
 
     #[test]
-    fn parses_all_supported_cli_options() {
-        let command = parse_cli_args(&args(&[
-            "input.png",
-            "output.png",
-            "24",
-            "--pixel-size",
-            "8",
-            "--palette",
-            "0d2b45,ffecd6",
-        ]))
-        .unwrap();
+    fn non_square_override_sets_each_axis() {
+        let config = Config {
+            pixel_size_override: Some(12.0),
+            pixel_size_override_y: Some(6.0),
+            ..Default::default()
+        };
+        let grid = Snapper::new(config)
+            .detect_grid(&upscaled_sprite_xy(16, 24, 12, 6))
+            .unwrap();
+        assert_eq!(grid.step_source, StepSource::Override);
+        assert_eq!((grid.output_width(), grid.output_height()), (16, 24));
+    }
 
-        let CliCommand::Run(config) = command else {
-            panic!("expected a processing command");
+    #[test]
+    fn pixel_size_override_estimates_the_grid_phase() {
+        let img = image::imageops::crop_imm(&upscaled_sprite(17, 13, 8), 4, 3, 128, 96).to_image();
+        let config = Config {
+            pixel_size_override: Some(8.0),
+            ..Default::default()
         };
+        let grid = Snapper::new(config).detect_grid(&img).unwrap();
+        assert_eq!((grid.offset_x, grid.offset_y), (4.0, 5.0));
+        assert_eq!(&grid.col_cuts[..4], &[0, 4, 12, 20]);
+        assert_eq!(&grid.row_cuts[..4], &[0, 5, 13, 21]);
+    }
 
-        assert_eq!(config.input_path, "input.png");
-        assert_eq!(config.output_path, "output.png");
-        assert_eq!(config.k_colors, 24);
-        assert_eq!(config.pixel_size_override, Some(8.0));
-        assert_eq!(config.palette, Some(vec![[13, 43, 69], [255, 236, 214]]));
+    #[test]
+    fn grid_offset_wraps_to_the_step() {
+        let config = Config {
+            pixel_size_override: Some(8.0),
+            ..Default::default()
+        }
+        .with_grid_offset(2.0, 10.0);
+        let grid = Snapper::new(config)
+            .detect_grid(&upscaled_sprite(16, 12, 8))
+            .unwrap();
+        assert_eq!((grid.offset_x, grid.offset_y), (2.0, 2.0));
+        assert_eq!(&grid.col_cuts[..2], &[0, 2]);
     }
 
     #[test]
-    fn rejects_unknown_options() {
-        let error = parse_cli_args(&args(&["input.png", "output.png", "--unknown"])).unwrap_err();
-        assert!(error.to_string().contains("unknown argument '--unknown'"));
+    fn explicit_cuts_bypass_detection() {
+        let img = upscaled_sprite(16, 12, 8);
+        let config = Config::default().with_cuts(vec![64, 32, 500], vec![48]);
+        let result = Snapper::new(config).snap(&img).unwrap();
+        assert_eq!(result.grid.col_cuts, vec![0, 32, 64, 128]);
+        assert_eq!(result.grid.row_cuts, vec![0, 48, 96]);
+        assert_eq!(result.grid.step_source, StepSource::Explicit);
+        assert_eq!(result.image.dimensions(), (3, 2));
+    }
+
+    #[test]
+    fn cuts_json_round_trips() {
+        let grid = Snapper::new(Config::default())
+            .detect_grid(&upscaled_sprite(16, 12, 8))
+            .unwrap();
+        let (col_cuts, row_cuts) = parse_cuts_json(&grid.cuts_json()).unwrap();
+        assert_eq!(col_cuts, grid.col_cuts);
+        assert_eq!(row_cuts, grid.row_cuts);
+
+        let error = parse_cuts_json(r#"{"col_cuts": [0, 1.5], "row_cuts": []}"#).unwrap_err();
+        assert!(error.to_string().contains("non-negative integers"));
+        let error = parse_cuts_json(r#"{"col_cuts": [0, 8]}"#).unwrap_err();
+        assert!(error.to_string().contains("'row_cuts' array"));
+    }
+
+    #[test]
+    fn debug_overlay_draws_cuts_and_profiles() {
+        let img = upscaled_sprite(16, 12, 8);
+        let snapper = Snapper::new(Config::default());
+        let grid = snapper.detect_grid(&img).unwrap();
+        let overlay = snapper.debug_overlay(&img, &grid).unwrap();
+
+        // 128x96 is upscaled 4 times, with the profile charts on the right and bottom
+        assert_eq!(overlay.dimensions(), (512 + 129, 384 + 129));
+        assert_eq!(overlay.get_pixel(8 * 4, 10), &Rgba([0, 230, 120, 255]));
+        let threshold = Rgba([255, 200, 0, 255]);
+        assert!(overlay.pixels().any(|p| *p == threshold));
+
+        // Only the peaks estimator has a threshold to draw
+        let snapper =
+            Snapper::new(Config::default().with_step_estimator(StepEstimator::Autocorrelation));
+        let overlay = snapper.debug_overlay(&img, &grid).unwrap();
+        assert!(!overlay.pixels().any(|p| *p == threshold));
+
+        let error = snapper
+            .debug_overlay(&upscaled_sprite(16, 12, 4), &grid)
+            .unwrap_err();
+        assert!(matches!(error, PixelSnapperError::InvalidInput(_)));
+    }
+
+    #[test]
+    fn detect_grid_rejects_out_of_range_pixel_size() {
+        let config = Config {
+            pixel_size_override: Some(100.0),
+            ..Default::default()
+        };
+        let error = Snapper::new(config)
+            .detect_grid(&upscaled_sprite(8, 8, 4))
+            .unwrap_err();
+        assert!(matches!(error, PixelSnapperError::InvalidInput(_)));
     }
 }
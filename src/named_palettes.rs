@@ -0,0 +1,130 @@
+//! Built-in palettes of retro hardware and popular pixel art palettes, selectable by name.
+
+/// Palette shipped with the crate. `colors` is a list accepted by
+/// [`parse_palette_hex`](crate::parse_palette_hex).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NamedPalette {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub colors: &'static str,
+}
+
+impl NamedPalette {
+    pub fn colors(&self) -> Vec<[u8; 3]> {
+        // Every built-in list is checked by the tests
+        crate::parse_palette_hex(self.colors).unwrap()
+    }
+}
+
+pub const NAMED_PALETTES: &[NamedPalette] = &[
+    NamedPalette {
+        name: "pico8",
+        description: "PICO-8 fantasy console",
+        colors: "000000,1d2b53,7e2553,008751,ab5236,5f574f,c2c3c7,fff1e8,\
+                 ff004d,ffa300,ffec27,00e436,29adff,83769c,ff77a8,ffccaa",
+    },
+    NamedPalette {
+        name: "gameboy",
+        description: "Game Boy DMG green shades",
+        colors: "0f380f,306230,8bac0f,9bbc0f",
+    },
+    NamedPalette {
+        name: "nes",
+        description: "Nintendo Entertainment System",
+        colors: "7c7c7c,0000fc,0000bc,4428bc,940084,a80020,a81000,881400,\
+                 503000,007800,006800,005800,004058,000000,bcbcbc,0078f8,\
+                 0058f8,6844fc,d800cc,e40058,f83800,e45c10,ac7c00,00b800,\
+                 00a800,00a844,008888,f8f8f8,3cbcfc,6888fc,9878f8,f878f8,\
+                 f85898,f87858,fca044,f8b800,b8f818,58d854,58f898,00e8d8,\
+                 787878,fcfcfc,a4e4fc,b8b8f8,d8b8f8,f8b8f8,f8a4c0,f0d0b0,\
+                 fce0a8,f8d878,d8f878,b8f8b8,b8f8d8,00fcfc,f8d8f8",
+    },
+    NamedPalette {
+        name: "cga",
+        description: "IBM CGA mode 4, palette 1 high intensity",
+        colors: "000000,55ffff,ff55ff,ffffff",
+    },
+    NamedPalette {
+        name: "ega",
+        description: "IBM EGA default 16 colors",
+        colors: "000000,0000aa,00aa00,00aaaa,aa0000,aa00aa,aa5500,aaaaaa,\
+                 555555,5555ff,55ff55,55ffff,ff5555,ff55ff,ffff55,ffffff",
+    },
+    NamedPalette {
+        name: "c64",
+        description: "Commodore 64",
+        colors: "000000,626262,898989,adadad,ffffff,9f4e44,cb7e75,6d5412,\
+                 a1683c,c9d487,9ae29b,5cab5e,6abfc6,887ecb,50459b,a057a3",
+    },
+    NamedPalette {
+        name: "zx-spectrum",
+        description: "ZX Spectrum, normal and bright colors",
+        colors: "000000,0000d7,d70000,d700d7,00d700,00d7d7,d7d700,d7d7d7,\
+                 0000ff,ff0000,ff00ff,00ff00,00ffff,ffff00,ffffff",
+    },
+    NamedPalette {
+        name: "sweetie16",
+        description: "Sweetie 16 by GrafxKid",
+        colors: "1a1c2c,5d275d,b13e53,ef7d57,ffcd75,a7f070,38b764,257179,\
+                 29366f,3b5dc9,41a6f6,73eff7,f4f4f4,94b0c2,566c86,333c57",
+    },
+    NamedPalette {
+        name: "db16",
+        description: "DawnBringer 16",
+        colors: "140c1c,442434,30346d,4e4a4e,854c30,346524,d04648,757161,\
+                 597dce,d27d2c,8595a1,6daa2c,d2aa99,6dc2ca,dad45e,deeed6",
+    },
+    NamedPalette {
+        name: "db32",
+        description: "DawnBringer 32",
+        colors: "000000,222034,45283c,663931,8f563b,df7126,d9a066,eec39a,\
+                 fbf236,99e550,6abe30,37946e,4b692f,524b24,323c39,3f3f74,\
+                 306082,5b6ee1,639bff,5fcde4,cbdbfc,ffffff,9badb7,847e87,\
+                 696a6a,595652,76428a,ac3232,d95763,d77bba,8f974a,8a6f30",
+    },
+    NamedPalette {
+        name: "endesga32",
+        description: "Endesga 32",
+        colors: "be4a2f,d77643,ead4aa,e4a672,b86f50,733e39,3e2731,a22633,\
+                 e43b44,f77622,feae34,fee761,63c74d,3e8948,265c42,193c3e,\
+                 124e89,0099db,2ce8f5,ffffff,c0cbdc,8b9bb4,5a6988,3a4466,\
+                 262b44,181425,ff0044,68386c,b55088,f6757a,e8b796,c28569",
+    },
+];
+
+/// Finds a built-in palette, ignoring case and separators so `PICO-8` finds `pico8`.
+pub fn named_palette(name: &str) -> Option<&'static NamedPalette> {
+    let normalize = |value: &str| -> String {
+        value
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .map(|c| c.to_ascii_lowercase())
+            .collect()
+    };
+    let wanted = normalize(name);
+    NAMED_PALETTES
+        .iter()
+        .find(|palette| normalize(palette.name) == wanted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn built_in_palettes_parse() {
+        let sizes = [16, 4, 55, 4, 16, 16, 15, 16, 16, 32, 32];
+        assert_eq!(NAMED_PALETTES.len(), sizes.len());
+        for (palette, size) in NAMED_PALETTES.iter().zip(sizes) {
+            let colors = crate::parse_palette_hex(palette.colors).unwrap();
+            assert_eq!(colors.len(), size, "{}", palette.name);
+        }
+    }
+
+    #[test]
+    fn finds_palettes_by_loose_name() {
+        assert_eq!(named_palette("PICO-8").unwrap().name, "pico8");
+        assert_eq!(named_palette("zx_spectrum").unwrap().name, "zx-spectrum");
+        assert!(named_palette("pico9").is_none());
+    }
+}
@@ -0,0 +1,189 @@
+//! Debug overlay showing the detected grid on top of the input image.
+//!
+//! The image is upscaled and the final cuts are drawn over it, then the column profile is
+//! charted below it and the row profile to its right, both with the peak threshold used by
+//! `estimate_step_from_peaks` when that estimator runs.
+
+use crate::{is_cut_on_peak, walker_strength_threshold, Config, CutSource, Grid, StepEstimator};
+use image::{Rgba, RgbaImage};
+
+/// Minimum size of the longest side of the upscaled image.
+const TARGET_SIZE: u32 = 512;
+/// Maximum size of the longest side of the upscaled image.
+const MAX_SIZE: u32 = 4096;
+/// Thickness of the profile charts.
+const CHART_SIZE: u32 = 128;
+
+const BACKGROUND: Rgba<u8> = Rgba([32, 32, 36, 255]);
+const CHECKER_LIGHT: Rgba<u8> = Rgba([72, 72, 78, 255]);
+const CHECKER_DARK: Rgba<u8> = Rgba([56, 56, 62, 255]);
+const BAR: Rgba<u8> = Rgba([150, 150, 160, 255]);
+const THRESHOLD: Rgba<u8> = Rgba([255, 200, 0, 255]);
+/// Walker cut on a strong profile peak.
+const WALKER_PEAK: Rgba<u8> = Rgba([0, 230, 120, 255]);
+/// Uniform fallback cut snapped to a strong profile peak.
+const UNIFORM_PEAK: Rgba<u8> = Rgba([0, 170, 255, 255]);
+/// Cut placed on a fallback target away from any strong peak.
+const FALLBACK: Rgba<u8> = Rgba([255, 40, 80, 255]);
+
+pub(crate) fn render(img: &RgbaImage, grid: &Grid, config: &Config) -> RgbaImage {
+    let (width, height) = img.dimensions();
+    let longest = width.max(height).max(1);
+    let scale = (TARGET_SIZE / longest).clamp(1, (MAX_SIZE / longest).max(1));
+    let (scaled_w, scaled_h) = (width * scale, height * scale);
+
+    let mut overlay = RgbaImage::from_pixel(
+        scaled_w + CHART_SIZE + 1,
+        scaled_h + CHART_SIZE + 1,
+        BACKGROUND,
+    );
+
+    for y in 0..scaled_h {
+        for x in 0..scaled_w {
+            let checker = if ((x / 8) + (y / 8)) % 2 == 0 {
+                CHECKER_LIGHT
+            } else {
+                CHECKER_DARK
+            };
+            let pixel = blend(checker, *img.get_pixel(x / scale, y / scale));
+            overlay.put_pixel(x, y, pixel);
+        }
+    }
+
+    // The autocorrelation estimator has no threshold to show
+    let threshold = match config.step_estimator {
+        StepEstimator::Peaks | StepEstimator::Auto => Some(config.peak_threshold_multiplier),
+        StepEstimator::Autocorrelation => None,
+    };
+    draw_column_chart(
+        &mut overlay,
+        &grid.profile_x,
+        scale,
+        scaled_h + 1,
+        threshold,
+    );
+    draw_row_chart(
+        &mut overlay,
+        &grid.profile_y,
+        scale,
+        scaled_w + 1,
+        threshold,
+    );
+
+    let col_colors = cut_colors(&grid.profile_x, &grid.col_cuts, grid.col_source, config);
+    for (&cut, color) in grid.col_cuts.iter().zip(col_colors) {
+        let x = (cut as u32 * scale).min(scaled_w - 1);
+        for y in 0..overlay.height() {
+            overlay.put_pixel(x, y, color);
+        }
+    }
+
+    let row_colors = cut_colors(&grid.profile_y, &grid.row_cuts, grid.row_source, config);
+    for (&cut, color) in grid.row_cuts.iter().zip(row_colors) {
+        let y = (cut as u32 * scale).min(scaled_h - 1);
+        for x in 0..overlay.width() {
+            overlay.put_pixel(x, y, color);
+        }
+    }
+
+    overlay
+}
+
+fn cut_colors(
+    profile: &[f64],
+    cuts: &[usize],
+    source: CutSource,
+    config: &Config,
+) -> Vec<Rgba<u8>> {
+    let threshold = walker_strength_threshold(profile, config);
+    let last = cuts.len().saturating_sub(1);
+    cuts.iter()
+        .enumerate()
+        .map(|(i, &cut)| {
+            // Image borders and explicit cuts are not detected, so draw them like peaks
+            if i == 0
+                || i == last
+                || source == CutSource::Explicit
+                || is_cut_on_peak(profile, cut, threshold)
+            {
+                match source {
+                    CutSource::Uniform => UNIFORM_PEAK,
+                    CutSource::Walker | CutSource::Explicit => WALKER_PEAK,
+                }
+            } else {
+                FALLBACK
+            }
+        })
+        .collect()
+}
+
+/// Draws `profile` as vertical bars growing down from `top`.
+fn draw_column_chart(
+    overlay: &mut RgbaImage,
+    profile: &[f64],
+    scale: u32,
+    top: u32,
+    threshold: Option<f64>,
+) {
+    let max_val = profile.iter().cloned().fold(0.0, f64::max);
+    if max_val <= 0.0 {
+        return;
+    }
+
+    for (i, &value) in profile.iter().enumerate() {
+        let bar = chart_length(value, max_val);
+        for x in i as u32 * scale..(i as u32 + 1) * scale {
+            for y in top..top + bar {
+                overlay.put_pixel(x, y, BAR);
+            }
+        }
+    }
+
+    if let Some(multiplier) = threshold {
+        let y = top + chart_length(max_val * multiplier, max_val);
+        for x in 0..profile.len() as u32 * scale {
+            overlay.put_pixel(x, y.min(overlay.height() - 1), THRESHOLD);
+        }
+    }
+}
+
+/// Draws `profile` as horizontal bars growing right from `left`.
+fn draw_row_chart(
+    overlay: &mut RgbaImage,
+    profile: &[f64],
+    scale: u32,
+    left: u32,
+    threshold: Option<f64>,
+) {
+    let max_val = profile.iter().cloned().fold(0.0, f64::max);
+    if max_val <= 0.0 {
+        return;
+    }
+
+    for (i, &value) in profile.iter().enumerate() {
+        let bar = chart_length(value, max_val);
+        for y in i as u32 * scale..(i as u32 + 1) * scale {
+            for x in left..left + bar {
+                overlay.put_pixel(x, y, BAR);
+            }
+        }
+    }
+
+    if let Some(multiplier) = threshold {
+        let x = left + chart_length(max_val * multiplier, max_val);
+        for y in 0..profile.len() as u32 * scale {
+            overlay.put_pixel(x.min(overlay.width() - 1), y, THRESHOLD);
+        }
+    }
+}
+
+fn chart_length(value: f64, max_val: f64) -> u32 {
+    ((value / max_val) * (CHART_SIZE - 1) as f64).round() as u32
+}
+
+fn blend(background: Rgba<u8>, pixel: Rgba<u8>) -> Rgba<u8> {
+    let alpha = pixel[3] as u32;
+    let mix =
+        |i: usize| ((pixel[i] as u32 * alpha + background[i] as u32 * (255 - alpha)) / 255) as u8;
+    Rgba([mix(0), mix(1), mix(2), 255])
+}
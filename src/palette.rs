@@ -0,0 +1,599 @@
+//! Readers and writers for the palette file formats used by pixel art tools.
+
+use crate::{json, ColorSpace, PixelSnapperError, Result, MAX_PALETTE_COLORS};
+use image::RgbaImage;
+use std::collections::HashMap;
+
+/// Palette file format accepted by [`parse_palette`] and [`write_palette`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaletteFormat {
+    /// GIMP palette (`.gpl`).
+    Gpl,
+    /// Adobe Swatch Exchange (`.ase`), also written by Aseprite.
+    Ase,
+    /// JASC palette (`.pal`), from Paint Shop Pro.
+    Pal,
+    /// One 6-digit hex color per line (`.hex`), as downloaded from Lospec.
+    Hex,
+    /// Adobe Color Table (`.act`), from Photoshop.
+    Act,
+    /// Image whose distinct opaque colors form the palette, like a 1xN swatch strip.
+    Png,
+    /// JSON object with a `colors` array of `#rrggbb` strings.
+    Json,
+}
+
+impl PaletteFormat {
+    /// Detects the format from the magic bytes of `bytes`, or from the extension of `path`
+    /// for formats without one.
+    pub fn detect(path: &str, bytes: &[u8]) -> Option<Self> {
+        if bytes.starts_with(b"GIMP Palette") {
+            return Some(PaletteFormat::Gpl);
+        }
+        if bytes.starts_with(b"ASEF") {
+            return Some(PaletteFormat::Ase);
+        }
+        if bytes.starts_with(b"JASC-PAL") {
+            return Some(PaletteFormat::Pal);
+        }
+        if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+            return Some(PaletteFormat::Png);
+        }
+        if bytes.trim_ascii_start().starts_with(b"{") {
+            return Some(PaletteFormat::Json);
+        }
+        Self::from_extension(path)
+    }
+
+    /// Format matching the extension of `path`, if any.
+    pub fn from_extension(path: &str) -> Option<Self> {
+        let extension = std::path::Path::new(path)
+            .extension()?
+            .to_str()?
+            .to_ascii_lowercase();
+        match extension.as_str() {
+            "gpl" => Some(PaletteFormat::Gpl),
+            "ase" => Some(PaletteFormat::Ase),
+            "pal" => Some(PaletteFormat::Pal),
+            "hex" => Some(PaletteFormat::Hex),
+            "act" => Some(PaletteFormat::Act),
+            "png" => Some(PaletteFormat::Png),
+            "json" => Some(PaletteFormat::Json),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            PaletteFormat::Gpl => "GPL",
+            PaletteFormat::Ase => "ASE",
+            PaletteFormat::Pal => "PAL",
+            PaletteFormat::Hex => "HEX",
+            PaletteFormat::Act => "ACT",
+            PaletteFormat::Png => "PNG",
+            PaletteFormat::Json => "JSON",
+        }
+    }
+}
+
+/// Reads the colors of a palette file. Duplicate colors are dropped and the first
+/// occurrence order is kept.
+pub fn parse_palette(bytes: &[u8], format: PaletteFormat) -> Result<Vec<[u8; 3]>> {
+    let colors = match format {
+        PaletteFormat::Gpl => parse_gpl(text(bytes, format)?),
+        PaletteFormat::Ase => parse_ase(bytes),
+        PaletteFormat::Pal => parse_pal(text(bytes, format)?),
+        PaletteFormat::Hex => parse_hex_lines(text(bytes, format)?),
+        PaletteFormat::Act => parse_act(bytes),
+        PaletteFormat::Png => parse_png(bytes),
+        PaletteFormat::Json => parse_json(text(bytes, format)?),
+    }
+    .map_err(|msg| {
+        PixelSnapperError::InvalidInput(format!("invalid {} palette: {}", format.name(), msg))
+    })?;
+    distinct_colors(colors)
+}
+
+/// Order of the colors listed by [`sorted_palette`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PaletteOrder {
+    /// Most used colors first.
+    #[default]
+    Frequency,
+    /// Darkest colors first.
+    Luminance,
+}
+
+impl std::str::FromStr for PaletteOrder {
+    type Err = PixelSnapperError;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "frequency" => Ok(PaletteOrder::Frequency),
+            "luminance" => Ok(PaletteOrder::Luminance),
+            _ => Err(PixelSnapperError::InvalidInput(format!(
+                "invalid palette order '{}': expected frequency or luminance",
+                value
+            ))),
+        }
+    }
+}
+
+/// Distinct opaque colors of `img` in `order`. Ties keep the order of first appearance.
+pub fn sorted_palette(img: &RgbaImage, order: PaletteOrder) -> Vec<[u8; 3]> {
+    let mut counts: HashMap<[u8; 3], usize> = HashMap::new();
+    let mut palette = Vec::new();
+    for p in img.pixels().filter(|p| p[3] != 0) {
+        let color = [p[0], p[1], p[2]];
+        let count = counts.entry(color).or_insert(0);
+        if *count == 0 {
+            palette.push(color);
+        }
+        *count += 1;
+    }
+
+    match order {
+        PaletteOrder::Frequency => palette.sort_by_key(|c| std::cmp::Reverse(counts[c])),
+        PaletteOrder::Luminance => palette.sort_by(|a, b| luminance(a).total_cmp(&luminance(b))),
+    }
+    palette
+}
+
+fn luminance(c: &[u8; 3]) -> f64 {
+    0.299 * c[0] as f64 + 0.587 * c[1] as f64 + 0.114 * c[2] as f64
+}
+
+/// Encodes `palette` in `format`, readable back with [`parse_palette`].
+pub fn write_palette(palette: &[[u8; 3]], format: PaletteFormat) -> Result<Vec<u8>> {
+    let hex = |c: &[u8; 3]| format!("{:02x}{:02x}{:02x}", c[0], c[1], c[2]);
+    let bytes = match format {
+        PaletteFormat::Gpl => {
+            let mut text = String::from("GIMP Palette\nName: Pixel Snapper\n#\n");
+            for c in palette {
+                text += &format!("{:3} {:3} {:3}\t{}\n", c[0], c[1], c[2], hex(c));
+            }
+            text.into_bytes()
+        }
+        PaletteFormat::Pal => {
+            let mut text = format!("JASC-PAL\r\n0100\r\n{}\r\n", palette.len());
+            for c in palette {
+                text += &format!("{} {} {}\r\n", c[0], c[1], c[2]);
+            }
+            text.into_bytes()
+        }
+        PaletteFormat::Hex => palette
+            .iter()
+            .map(|c| hex(c) + "\n")
+            .collect::<String>()
+            .into_bytes(),
+        PaletteFormat::Act => {
+            let mut bytes = vec![0; 772];
+            for (slot, c) in bytes.chunks_exact_mut(3).zip(palette) {
+                slot.copy_from_slice(c);
+            }
+            bytes[768..770].copy_from_slice(&(palette.len().min(256) as u16).to_be_bytes());
+            // No transparent color
+            bytes[770..].copy_from_slice(&[0xff, 0xff]);
+            bytes
+        }
+        PaletteFormat::Ase => {
+            let mut bytes = b"ASEF\x00\x01\x00\x00".to_vec();
+            bytes.extend_from_slice(&(palette.len() as u32).to_be_bytes());
+            for c in palette {
+                let name: Vec<u16> = hex(c).encode_utf16().chain([0]).collect();
+                let mut block = (name.len() as u16).to_be_bytes().to_vec();
+                block.extend(name.iter().flat_map(|unit| unit.to_be_bytes()));
+                block.extend_from_slice(b"RGB ");
+                block.extend(c.iter().flat_map(|&v| (v as f32 / 255.0).to_be_bytes()));
+                // Normal color, neither global nor spot
+                block.extend_from_slice(&2u16.to_be_bytes());
+
+                bytes.extend_from_slice(&1u16.to_be_bytes());
+                bytes.extend_from_slice(&(block.len() as u32).to_be_bytes());
+                bytes.extend(block);
+            }
+            bytes
+        }
+        PaletteFormat::Png => {
+            let strip = RgbaImage::from_fn(palette.len() as u32, 1, |x, _| {
+                let [r, g, b] = palette[x as usize];
+                image::Rgba([r, g, b, 255])
+            });
+            let mut bytes = Vec::new();
+            strip.write_to(
+                &mut std::io::Cursor::new(&mut bytes),
+                image::ImageFormat::Png,
+            )?;
+            bytes
+        }
+        PaletteFormat::Json => {
+            let colors: Vec<String> = palette.iter().map(|c| format!("\"#{}\"", hex(c))).collect();
+            format!("{{ \"colors\": [{}] }}\n", colors.join(", ")).into_bytes()
+        }
+    };
+    Ok(bytes)
+}
+
+/// Drops duplicate colors and checks the palette size.
+pub(crate) fn distinct_colors(colors: Vec<[u8; 3]>) -> Result<Vec<[u8; 3]>> {
+    let mut seen = std::collections::HashSet::new();
+    let palette: Vec<[u8; 3]> = colors.into_iter().filter(|c| seen.insert(*c)).collect();
+
+    if palette.is_empty() {
+        return Err(PixelSnapperError::InvalidInput(
+            "Palette must contain at least one color".to_string(),
+        ));
+    }
+    if palette.len() > MAX_PALETTE_COLORS {
+        return Err(PixelSnapperError::InvalidInput(format!(
+            "Palette must contain at most {} distinct colors",
+            MAX_PALETTE_COLORS
+        )));
+    }
+    Ok(palette)
+}
+
+/// Format errors are plain messages, wrapped with the format name by [`parse_palette`].
+type ParseResult<T> = std::result::Result<T, String>;
+
+fn text(bytes: &[u8], format: PaletteFormat) -> Result<&str> {
+    std::str::from_utf8(bytes).map_err(|_| {
+        PixelSnapperError::InvalidInput(format!(
+            "invalid {} palette: not a UTF-8 text file",
+            format.name()
+        ))
+    })
+}
+
+fn parse_gpl(text: &str) -> ParseResult<Vec<[u8; 3]>> {
+    let mut lines = text.lines().enumerate();
+    match lines.next() {
+        Some((_, header)) if header.trim() == "GIMP Palette" => {}
+        _ => return Err("missing 'GIMP Palette' header".to_string()),
+    }
+
+    let mut colors = Vec::new();
+    for (index, line) in lines {
+        let line = line.trim();
+        if line.is_empty()
+            || line.starts_with('#')
+            || line.starts_with("Name:")
+            || line.starts_with("Columns:")
+        {
+            continue;
+        }
+        // Anything after the three channels is the color name
+        let channels: Vec<&str> = line.split_whitespace().take(3).collect();
+        colors.push(parse_channels(&channels, index + 1)?);
+    }
+    Ok(colors)
+}
+
+fn parse_pal(text: &str) -> ParseResult<Vec<[u8; 3]>> {
+    let mut lines = text.lines().map(str::trim);
+    if lines.next() != Some("JASC-PAL") {
+        return Err("missing 'JASC-PAL' header".to_string());
+    }
+    if lines.next() != Some("0100") {
+        return Err("unsupported version, expected 0100".to_string());
+    }
+    let count: usize = lines
+        .next()
+        .and_then(|line| line.parse().ok())
+        .ok_or("line 3: expected the number of colors")?;
+
+    let colors = lines
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(index, line)| {
+            let channels: Vec<&str> = line.split_whitespace().collect();
+            parse_channels(&channels, index + 4)
+        })
+        .collect::<ParseResult<Vec<_>>>()?;
+    if colors.len() != count {
+        return Err(format!(
+            "header announces {} colors but the file has {}",
+            count,
+            colors.len()
+        ));
+    }
+    Ok(colors)
+}
+
+fn parse_channels(channels: &[&str], line: usize) -> ParseResult<[u8; 3]> {
+    let invalid = || format!("line {}: expected three channels between 0 and 255", line);
+    if channels.len() != 3 {
+        return Err(invalid());
+    }
+    let mut color = [0; 3];
+    for (value, channel) in color.iter_mut().zip(channels) {
+        *value = channel.parse().map_err(|_| invalid())?;
+    }
+    Ok(color)
+}
+
+fn parse_hex_lines(text: &str) -> ParseResult<Vec<[u8; 3]>> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            parse_hex_color(line).ok_or_else(|| {
+                format!(
+                    "line {}: '{}' is not a 6-digit hex color",
+                    index + 1,
+                    line.trim()
+                )
+            })
+        })
+        .collect()
+}
+
+fn parse_act(bytes: &[u8]) -> ParseResult<Vec<[u8; 3]>> {
+    // 256 RGB triples, optionally followed by the color count and the transparent index
+    let (count, transparent) = match bytes.len() {
+        768 => (256, None),
+        772 => {
+            let count = u16::from_be_bytes([bytes[768], bytes[769]]) as usize;
+            let transparent = u16::from_be_bytes([bytes[770], bytes[771]]) as usize;
+            (count.clamp(1, 256), Some(transparent))
+        }
+        len => return Err(format!("expected 768 or 772 bytes, found {}", len)),
+    };
+
+    Ok(bytes[..count * 3]
+        .chunks_exact(3)
+        .enumerate()
+        .filter(|&(index, _)| Some(index) != transparent)
+        .map(|(_, rgb)| [rgb[0], rgb[1], rgb[2]])
+        .collect())
+}
+
+fn parse_ase(bytes: &[u8]) -> ParseResult<Vec<[u8; 3]>> {
+    let mut reader = Reader { bytes, pos: 0 };
+    if reader.take(4)? != b"ASEF" {
+        return Err("missing 'ASEF' signature".to_string());
+    }
+    let major = reader.u16()?;
+    reader.u16()?;
+    if major != 1 {
+        return Err(format!("unsupported version {}", major));
+    }
+
+    let blocks = reader.u32()?;
+    let mut colors = Vec::new();
+    for _ in 0..blocks {
+        let kind = reader.u16()?;
+        let length = reader.u32()? as usize;
+        let block = reader.take(length)?;
+        // Group start and end blocks only carry a name
+        if kind == 0x0001 {
+            colors.push(parse_ase_color(block)?);
+        }
+    }
+    Ok(colors)
+}
+
+fn parse_ase_color(block: &[u8]) -> ParseResult<[u8; 3]> {
+    let mut reader = Reader {
+        bytes: block,
+        pos: 0,
+    };
+    // UTF-16 name, length in code units including the terminator
+    let name_len = reader.u16()? as usize;
+    reader.take(name_len * 2)?;
+
+    let model = reader.take(4)?;
+    let mut values = |n: usize| {
+        (0..n)
+            .map(|_| reader.f32())
+            .collect::<ParseResult<Vec<_>>>()
+    };
+    let to_byte = |v: f32| (v.clamp(0.0, 1.0) * 255.0).round() as u8;
+    match model {
+        b"RGB " => {
+            let v = values(3)?;
+            Ok([to_byte(v[0]), to_byte(v[1]), to_byte(v[2])])
+        }
+        b"Gray" => Ok([to_byte(values(1)?[0]); 3]),
+        b"CMYK" => {
+            let v = values(4)?;
+            let k = 1.0 - v[3];
+            Ok([0, 1, 2].map(|i| to_byte((1.0 - v[i]) * k)))
+        }
+        b"LAB " => {
+            // L is stored as a fraction, a and b as is
+            let v = values(3)?;
+            Ok(ColorSpace::Lab.decode([v[0] * 100.0, v[1], v[2]].map(|c| c * 2.55)))
+        }
+        _ => Err(format!(
+            "unsupported color model '{}'",
+            String::from_utf8_lossy(model).trim()
+        )),
+    }
+}
+
+fn parse_json(text: &str) -> ParseResult<Vec<[u8; 3]>> {
+    let document = json::parse(text).map_err(|e| e.to_string())?;
+    let colors = document
+        .get("colors")
+        .and_then(json::JsonValue::as_array)
+        .ok_or("expected an object with a 'colors' array")?;
+    colors
+        .iter()
+        .map(|value| {
+            value
+                .as_str()
+                .and_then(parse_hex_color)
+                .ok_or_else(|| "'colors' must only contain 6-digit hex strings".to_string())
+        })
+        .collect()
+}
+
+fn parse_hex_color(value: &str) -> Option<[u8; 3]> {
+    let hex = value.trim().trim_start_matches('#');
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some(std::array::from_fn(|i| {
+        u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).unwrap()
+    }))
+}
+
+fn parse_png(bytes: &[u8]) -> ParseResult<Vec<[u8; 3]>> {
+    let img = image::load_from_memory_with_format(bytes, image::ImageFormat::Png)
+        .map_err(|e| e.to_string())?
+        .to_rgba8();
+    Ok(img
+        .pixels()
+        .filter(|p| p[3] != 0)
+        .map(|p| [p[0], p[1], p[2]])
+        .collect())
+}
+
+/// Big-endian reader over a binary palette.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> ParseResult<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or("unexpected end of file")?;
+        let slice = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn u16(&mut self) -> ParseResult<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> ParseResult<u32> {
+        self.take(4)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn f32(&mut self) -> ParseResult<f32> {
+        self.u32().map(f32::from_bits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COLORS: [[u8; 3]; 3] = [[13, 43, 69], [255, 236, 214], [200, 60, 60]];
+
+    #[test]
+    fn parses_text_formats() {
+        let gpl = "GIMP Palette\nName: Test\nColumns: 3\n# comment\n 13  43  69\tNight\n255 236 214\n200 60 60 Red\n";
+        let pal = "JASC-PAL\r\n0100\r\n3\r\n13 43 69\r\n255 236 214\r\n200 60 60\r\n";
+        let hex = "0d2b45\nFFECD6\n\n#c83c3c\n";
+        for (bytes, path) in [(gpl, "a.gpl"), (pal, "a.pal"), (hex, "a.hex")] {
+            let format = PaletteFormat::detect(path, bytes.as_bytes()).unwrap();
+            assert_eq!(parse_palette(bytes.as_bytes(), format).unwrap(), COLORS);
+        }
+    }
+
+    #[test]
+    fn parses_binary_formats() {
+        let mut act = vec![0; 772];
+        act[..9].copy_from_slice(&COLORS.concat());
+        act[768..].copy_from_slice(&[0, 4, 0, 3]);
+        assert_eq!(parse_palette(&act, PaletteFormat::Act).unwrap(), COLORS);
+
+        let mut ase = b"ASEF\x00\x01\x00\x00".to_vec();
+        ase.extend_from_slice(&(COLORS.len() as u32 + 2).to_be_bytes());
+        ase.extend_from_slice(&[0xc0, 0x01, 0, 0, 0, 0]);
+        for (i, color) in COLORS.iter().enumerate() {
+            let mut block = vec![0, 2, 0, b'a' + i as u8, 0, 0];
+            block.extend_from_slice(b"RGB ");
+            for channel in color {
+                block.extend_from_slice(&(*channel as f32 / 255.0).to_be_bytes());
+            }
+            block.extend_from_slice(&[0, 2]);
+            ase.extend_from_slice(&[0, 1]);
+            ase.extend_from_slice(&(block.len() as u32).to_be_bytes());
+            ase.extend_from_slice(&block);
+        }
+        ase.extend_from_slice(&[0xc0, 0x02, 0, 0, 0, 0]);
+        let format = PaletteFormat::detect("swatches", &ase).unwrap();
+        assert_eq!(parse_palette(&ase, format).unwrap(), COLORS);
+
+        let strip = image::RgbaImage::from_fn(6, 1, |x, _| {
+            let [r, g, b] = COLORS[x as usize / 2];
+            image::Rgba([r, g, b, 255])
+        });
+        let mut png = Vec::new();
+        image::DynamicImage::ImageRgba8(strip)
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        assert_eq!(parse_palette(&png, PaletteFormat::Png).unwrap(), COLORS);
+    }
+
+    #[test]
+    fn written_palettes_read_back() {
+        let formats = [
+            PaletteFormat::Gpl,
+            PaletteFormat::Ase,
+            PaletteFormat::Pal,
+            PaletteFormat::Hex,
+            PaletteFormat::Act,
+            PaletteFormat::Png,
+            PaletteFormat::Json,
+        ];
+        for format in formats {
+            let bytes = write_palette(&COLORS, format).unwrap();
+            assert_eq!(
+                parse_palette(&bytes, format).unwrap(),
+                COLORS,
+                "{:?}",
+                format
+            );
+        }
+    }
+
+    #[test]
+    fn sorts_palettes() {
+        // One pixel of the first color, two of the second and three of the third
+        let img = RgbaImage::from_fn(6, 1, |x, _| {
+            let [r, g, b] = COLORS[[0, 1, 1, 2, 2, 2][x as usize]];
+            image::Rgba([r, g, b, 255])
+        });
+        assert_eq!(
+            sorted_palette(&img, PaletteOrder::Frequency),
+            [COLORS[2], COLORS[1], COLORS[0]]
+        );
+        assert_eq!(
+            sorted_palette(&img, PaletteOrder::Luminance),
+            [COLORS[0], COLORS[2], COLORS[1]]
+        );
+    }
+
+    #[test]
+    fn reports_malformed_files() {
+        let cases: [(&[u8], PaletteFormat, &str); 5] = [
+            (b"GIMP Palette\n1 2\n", PaletteFormat::Gpl, "line 2"),
+            (
+                b"JASC-PAL\n0100\n2\n1 2 3\n",
+                PaletteFormat::Pal,
+                "announces 2",
+            ),
+            (b"0d2b45\nblue\n", PaletteFormat::Hex, "line 2"),
+            (&[0; 10], PaletteFormat::Act, "768 or 772"),
+            (
+                b"ASEF\x00\x01\x00\x00\x00\x00\x00\x01",
+                PaletteFormat::Ase,
+                "end of file",
+            ),
+        ];
+        for (bytes, format, message) in cases {
+            let error = parse_palette(bytes, format).unwrap_err().to_string();
+            assert!(error.contains(message), "{}", error);
+        }
+    }
+}
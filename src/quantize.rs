@@ -0,0 +1,967 @@
+//! Palette builders used by the quantization stage.
+//!
+//! Every quantizer works on points of the configured [`ColorSpace`](crate::ColorSpace) and
+//! returns its palette in the same space, so pixels are assigned to the nearest entry the same
+//! way whichever algorithm picked it.
+
+use crate::{color::dist_sq, ColorSpace, PixelSnapperError, Result};
+use image::RgbaImage;
+use rand::prelude::*;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use rand_distr::{Distribution, WeightedIndex};
+use std::collections::HashMap;
+
+/// Builds a palette of at most `k` colors representing `points`.
+///
+/// Implementations must be deterministic: the same points always give the same palette.
+pub trait Quantizer {
+    /// Palette where each point counts as much as its entry of `weights`, one per point, or
+    /// once without weights.
+    fn weighted_palette(
+        &self,
+        points: &[[f32; 3]],
+        weights: Option<&[f32]>,
+        k: usize,
+    ) -> Result<Vec<[f32; 3]>>;
+
+    fn palette(&self, points: &[[f32; 3]], k: usize) -> Result<Vec<[f32; 3]>> {
+        self.weighted_palette(points, None, k)
+    }
+}
+
+/// Algorithm used by the quantization stage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QuantizerKind {
+    /// Seeded k-means++ refined by Lloyd iterations. Slowest, usually the lowest error.
+    #[default]
+    KMeans,
+    /// Recursively splits the most spread out box of colors at its median.
+    MedianCut,
+    /// Merges the least used leaves of an 8 level color octree.
+    Octree,
+    /// Xiaolin Wu's variance minimizing box splits on a 32x32x32 histogram.
+    Wu,
+}
+
+impl std::str::FromStr for QuantizerKind {
+    type Err = PixelSnapperError;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "kmeans" => Ok(QuantizerKind::KMeans),
+            "median-cut" => Ok(QuantizerKind::MedianCut),
+            "octree" => Ok(QuantizerKind::Octree),
+            "wu" => Ok(QuantizerKind::Wu),
+            _ => Err(PixelSnapperError::InvalidInput(format!(
+                "invalid quantizer '{}': expected kmeans, median-cut, octree or wu",
+                value
+            ))),
+        }
+    }
+}
+
+/// K-means clustering seeded with k-means++.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KMeans {
+    pub seed: u64,
+    pub max_iterations: usize,
+}
+
+impl Default for KMeans {
+    fn default() -> Self {
+        Self {
+            seed: 42,
+            max_iterations: 15,
+        }
+    }
+}
+
+impl Quantizer for KMeans {
+    fn weighted_palette(
+        &self,
+        points: &[[f32; 3]],
+        weights: Option<&[f32]>,
+        k: usize,
+    ) -> Result<Vec<[f32; 3]>> {
+        let n_pixels = points.len();
+        let k = k.min(n_pixels);
+        if k == 0 {
+            return Ok(Vec::new());
+        }
+
+        let mut rng = ChaCha8Rng::seed_from_u64(self.seed);
+
+        fn sample_index(rng: &mut ChaCha8Rng, upper: usize) -> usize {
+            debug_assert!(upper > 0);
+            let upper = upper as u64;
+            rng.gen_range(0..upper) as usize
+        }
+
+        let mut centroids: Vec<[f32; 3]> = Vec::with_capacity(k);
+        let first_idx = sample_index(&mut rng, n_pixels);
+        centroids.push(points[first_idx]);
+        let mut distances = vec![f32::MAX; n_pixels];
+
+        // Maybe try a faster algorithm for this? like https://crates.io/crates/kmeans_colors
+        for _ in 1..k {
+            let last_c = centroids.last().unwrap();
+            let mut sum_sq_dist = 0.0;
+
+            for (i, p) in points.iter().enumerate() {
+                let d_sq = dist_sq(p, last_c);
+                if d_sq < distances[i] {
+                    distances[i] = d_sq;
+                }
+                sum_sq_dist += distances[i];
+            }
+
+            if sum_sq_dist <= 0.0 {
+                let idx = sample_index(&mut rng, n_pixels);
+                centroids.push(points[idx]);
+            } else {
+                // Heavier points are as likely to be picked as that many copies of them
+                let dist = match weights {
+                    Some(weights) => {
+                        WeightedIndex::new(distances.iter().zip(weights).map(|(d, w)| d * w))
+                    }
+                    None => WeightedIndex::new(&distances),
+                };
+                let dist = dist.map_err(|e| {
+                    PixelSnapperError::ProcessingError(format!(
+                        "Failed to sample new centroid: {}",
+                        e
+                    ))
+                })?;
+                let idx = dist.sample(&mut rng);
+                centroids.push(points[idx]);
+            }
+        }
+
+        let mut prev_centroids = centroids.clone();
+        for iteration in 0..self.max_iterations {
+            let mut sums = vec![[0.0f32; 3]; k];
+            let mut counts = vec![0.0f64; k];
+
+            for (index, p) in points.iter().enumerate() {
+                let weight = weights.map_or(1.0, |w| w[index]);
+                let mut min_dist = f32::MAX;
+                let mut best_k = 0;
+
+                for (i, c) in centroids.iter().enumerate() {
+                    let d = dist_sq(p, c);
+                    if d < min_dist {
+                        min_dist = d;
+                        best_k = i;
+                    }
+                }
+                sums[best_k][0] += p[0] * weight;
+                sums[best_k][1] += p[1] * weight;
+                sums[best_k][2] += p[2] * weight;
+                counts[best_k] += weight as f64;
+            }
+
+            for i in 0..k {
+                if counts[i] > 0.0 {
+                    let fcount = counts[i] as f32;
+                    centroids[i] = [
+                        sums[i][0] / fcount,
+                        sums[i][1] / fcount,
+                        sums[i][2] / fcount,
+                    ];
+                }
+            }
+
+            if iteration > 0 {
+                let mut max_movement = 0.0f32;
+                for (new_c, old_c) in centroids.iter().zip(prev_centroids.iter()) {
+                    let movement = dist_sq(new_c, old_c);
+                    if movement > max_movement {
+                        max_movement = movement;
+                    }
+                }
+
+                if max_movement < 0.01 {
+                    break;
+                }
+            }
+
+            prev_centroids.copy_from_slice(&centroids);
+        }
+
+        Ok(centroids)
+    }
+}
+
+/// Median cut on the distinct colors of the image, weighted by how often they occur.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MedianCut;
+
+impl Quantizer for MedianCut {
+    fn weighted_palette(
+        &self,
+        points: &[[f32; 3]],
+        weights: Option<&[f32]>,
+        k: usize,
+    ) -> Result<Vec<[f32; 3]>> {
+        let colors = histogram(points, weights);
+        if colors.is_empty() || k == 0 {
+            return Ok(Vec::new());
+        }
+
+        let mut boxes = vec![colors];
+        while boxes.len() < k {
+            // Split the box with the widest channel, first one wins ties
+            let widest = boxes
+                .iter()
+                .enumerate()
+                .filter(|(_, colors)| colors.len() > 1)
+                .map(|(i, colors)| {
+                    let (axis, range) = widest_axis(colors);
+                    (i, axis, range)
+                })
+                .fold(
+                    None,
+                    |best: Option<(usize, usize, f32)>, candidate| match best {
+                        Some(best) if best.2 >= candidate.2 => Some(best),
+                        _ => Some(candidate),
+                    },
+                );
+            let Some((index, axis, _)) = widest else {
+                break;
+            };
+
+            let colors = &mut boxes[index];
+            colors.sort_by(|a, b| a.0[axis].total_cmp(&b.0[axis]));
+            let total: f64 = colors.iter().map(|(_, count)| count).sum();
+            let mut seen = 0.0;
+            let median = colors
+                .iter()
+                .find(|(_, count)| {
+                    seen += count;
+                    seen * 2.0 >= total
+                })
+                .map_or(0.0, |(color, _)| color[axis]);
+            // Like MMCQ, move the cut from the median to the middle of the longer side so a
+            // dominant cluster does not get split in half
+            let (min, max) = (colors[0].0[axis], colors[colors.len() - 1].0[axis]);
+            let cut = if max - median >= median - min {
+                median + (max - median) / 2.0
+            } else {
+                median - (median - min) / 2.0
+            };
+            let split = colors
+                .partition_point(|(color, _)| color[axis] <= cut)
+                .clamp(1, colors.len() - 1);
+            let upper = colors.split_off(split);
+            boxes.push(upper);
+        }
+
+        Ok(boxes.iter().map(|colors| weighted_mean(colors)).collect())
+    }
+}
+
+/// Classic color octree over the bounding box of the points, reduced from its deepest level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Octree;
+
+const OCTREE_DEPTH: usize = 8;
+
+struct OctreeNode {
+    children: [Option<usize>; 8],
+    sum: [f64; 3],
+    count: f64,
+    leaf: bool,
+}
+
+impl Quantizer for Octree {
+    fn weighted_palette(
+        &self,
+        points: &[[f32; 3]],
+        weights: Option<&[f32]>,
+        k: usize,
+    ) -> Result<Vec<[f32; 3]>> {
+        let colors = histogram(points, weights);
+        if colors.is_empty() || k == 0 {
+            return Ok(Vec::new());
+        }
+        let bins = Bins::new(&colors, 256);
+
+        let new_node = || OctreeNode {
+            children: [None; 8],
+            sum: [0.0; 3],
+            count: 0.0,
+            leaf: false,
+        };
+        let mut nodes = vec![new_node()];
+        let mut levels: Vec<Vec<usize>> = vec![Vec::new(); OCTREE_DEPTH];
+        levels[0].push(0);
+        let mut leaves = 0;
+
+        for (color, count) in &colors {
+            let coords = bins.coords(color);
+            let mut node = 0;
+            for level in 0..OCTREE_DEPTH {
+                let shift = OCTREE_DEPTH - 1 - level;
+                let child = (((coords[0] >> shift) & 1) << 2)
+                    | (((coords[1] >> shift) & 1) << 1)
+                    | ((coords[2] >> shift) & 1);
+                node = match nodes[node].children[child] {
+                    Some(existing) => existing,
+                    None => {
+                        nodes.push(new_node());
+                        let created = nodes.len() - 1;
+                        nodes[node].children[child] = Some(created);
+                        if level + 1 < OCTREE_DEPTH {
+                            levels[level + 1].push(created);
+                        } else {
+                            nodes[created].leaf = true;
+                            leaves += 1;
+                        }
+                        created
+                    }
+                };
+            }
+            let leaf = &mut nodes[node];
+            for (sum, value) in leaf.sum.iter_mut().zip(color) {
+                *sum += *value as f64 * count;
+            }
+            leaf.count += count;
+        }
+
+        // Deeper levels hold the finest distinctions, so they are merged first, least used
+        // nodes before the others
+        'reduce: for level in (0..OCTREE_DEPTH).rev() {
+            let mut candidates = std::mem::take(&mut levels[level]);
+            for &node in &candidates {
+                let (sum, count) = subtree_totals(&nodes, node);
+                nodes[node].sum = sum;
+                nodes[node].count = count;
+            }
+            candidates.sort_by(|&a, &b| nodes[a].count.total_cmp(&nodes[b].count).then(a.cmp(&b)));
+
+            for node in candidates {
+                if leaves <= k {
+                    break 'reduce;
+                }
+                let children = nodes[node].children.iter().flatten().count();
+                nodes[node].children = [None; 8];
+                nodes[node].leaf = true;
+                leaves = leaves + 1 - children;
+            }
+        }
+
+        let mut palette = Vec::with_capacity(leaves);
+        collect_leaves(&nodes, 0, &mut palette);
+        Ok(palette)
+    }
+}
+
+fn subtree_totals(nodes: &[OctreeNode], node: usize) -> ([f64; 3], f64) {
+    if nodes[node].leaf {
+        return (nodes[node].sum, nodes[node].count);
+    }
+    let mut sum = [0.0; 3];
+    let mut count = 0.0;
+    for &child in nodes[node].children.iter().flatten() {
+        let (child_sum, child_count) = subtree_totals(nodes, child);
+        for (total, value) in sum.iter_mut().zip(child_sum) {
+            *total += value;
+        }
+        count += child_count;
+    }
+    (sum, count)
+}
+
+fn collect_leaves(nodes: &[OctreeNode], node: usize, palette: &mut Vec<[f32; 3]>) {
+    let current = &nodes[node];
+    if current.leaf {
+        if current.count > 0.0 {
+            palette.push(current.sum.map(|s| (s / current.count) as f32));
+        }
+        return;
+    }
+    for &child in current.children.iter().flatten() {
+        collect_leaves(nodes, child, palette);
+    }
+}
+
+/// Xiaolin Wu's quantizer: cumulative color moments on a histogram, then greedy box splits
+/// that remove the most variance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Wu;
+
+const WU_BINS: usize = 32;
+const WU_SIDE: usize = WU_BINS + 1;
+
+/// Weight, per-channel sums and sum of squared norms of the colors in a region.
+#[derive(Debug, Clone, Copy, Default)]
+struct Moments {
+    weight: f64,
+    sum: [f64; 3],
+    sq: f64,
+}
+
+impl std::ops::Add for Moments {
+    type Output = Moments;
+
+    fn add(self, other: Moments) -> Moments {
+        Moments {
+            weight: self.weight + other.weight,
+            sum: [
+                self.sum[0] + other.sum[0],
+                self.sum[1] + other.sum[1],
+                self.sum[2] + other.sum[2],
+            ],
+            sq: self.sq + other.sq,
+        }
+    }
+}
+
+impl std::ops::Sub for Moments {
+    type Output = Moments;
+
+    fn sub(self, other: Moments) -> Moments {
+        Moments {
+            weight: self.weight - other.weight,
+            sum: [
+                self.sum[0] - other.sum[0],
+                self.sum[1] - other.sum[1],
+                self.sum[2] - other.sum[2],
+            ],
+            sq: self.sq - other.sq,
+        }
+    }
+}
+
+impl Moments {
+    fn norm_sq_over_weight(&self) -> f64 {
+        self.sum.iter().map(|s| s * s).sum::<f64>() / self.weight
+    }
+}
+
+/// Half-open box `(lo, hi]` of histogram cells on each axis.
+#[derive(Debug, Clone, Copy)]
+struct WuBox {
+    lo: [usize; 3],
+    hi: [usize; 3],
+}
+
+impl WuBox {
+    fn volume(&self) -> usize {
+        (0..3).map(|axis| self.hi[axis] - self.lo[axis]).product()
+    }
+}
+
+fn wu_index(r: usize, g: usize, b: usize) -> usize {
+    (r * WU_SIDE + g) * WU_SIDE + b
+}
+
+fn wu_volume(cube: &WuBox, moments: &[Moments]) -> Moments {
+    let mut volume = Moments::default();
+    for corner in 0..8 {
+        let pick = |axis: usize| {
+            if corner & (4 >> axis) != 0 {
+                cube.hi[axis]
+            } else {
+                cube.lo[axis]
+            }
+        };
+        let value = moments[wu_index(pick(0), pick(1), pick(2))];
+        // Inclusion-exclusion: corners with an even number of `lo` coordinates are added
+        if (corner as u32).count_ones() % 2 == 1 {
+            volume = volume + value;
+        } else {
+            volume = volume - value;
+        }
+    }
+    volume
+}
+
+fn wu_variance(cube: &WuBox, moments: &[Moments]) -> f64 {
+    let volume = wu_volume(cube, moments);
+    if volume.weight <= 0.0 {
+        return 0.0;
+    }
+    volume.sq - volume.norm_sq_over_weight()
+}
+
+/// Best cut of `cube` along `axis` and the between-class score it reaches.
+fn wu_maximize(cube: &WuBox, axis: usize, whole: Moments, moments: &[Moments]) -> (f64, usize) {
+    let mut best = (0.0, 0);
+    for position in cube.lo[axis] + 1..cube.hi[axis] {
+        let mut half_box = *cube;
+        half_box.hi[axis] = position;
+        let half = wu_volume(&half_box, moments);
+        let other = whole - half;
+        if half.weight <= 0.0 || other.weight <= 0.0 {
+            continue;
+        }
+        let score = half.norm_sq_over_weight() + other.norm_sq_over_weight();
+        if score > best.0 {
+            best = (score, position);
+        }
+    }
+    best
+}
+
+fn wu_cut(cube: &mut WuBox, moments: &[Moments]) -> Option<WuBox> {
+    let whole = wu_volume(cube, moments);
+    let (axis, (_, position)) = (0..3)
+        .map(|axis| (axis, wu_maximize(cube, axis, whole, moments)))
+        .fold(
+            None,
+            |best: Option<(usize, (f64, usize))>, candidate| match best {
+                Some(best) if best.1 .0 >= candidate.1 .0 => Some(best),
+                _ => Some(candidate),
+            },
+        )?;
+    if position == 0 {
+        return None;
+    }
+
+    let mut upper = *cube;
+    upper.lo[axis] = position;
+    cube.hi[axis] = position;
+    Some(upper)
+}
+
+impl Quantizer for Wu {
+    fn weighted_palette(
+        &self,
+        points: &[[f32; 3]],
+        weights: Option<&[f32]>,
+        k: usize,
+    ) -> Result<Vec<[f32; 3]>> {
+        let colors = histogram(points, weights);
+        if colors.is_empty() || k == 0 {
+            return Ok(Vec::new());
+        }
+        let bins = Bins::new(&colors, WU_BINS);
+
+        let mut moments = vec![Moments::default(); WU_SIDE * WU_SIDE * WU_SIDE];
+        for (color, count) in &colors {
+            let [r, g, b] = bins.coords(color).map(|c| c + 1);
+            let weight = *count;
+            let cell = &mut moments[wu_index(r, g, b)];
+            cell.weight += weight;
+            for (sum, value) in cell.sum.iter_mut().zip(color) {
+                *sum += *value as f64 * weight;
+            }
+            cell.sq += color.iter().map(|&v| (v as f64).powi(2)).sum::<f64>() * weight;
+        }
+
+        // Cumulative moments so any box can be summed from its eight corners
+        for r in 1..WU_SIDE {
+            let mut area = [Moments::default(); WU_SIDE];
+            for g in 1..WU_SIDE {
+                let mut line = Moments::default();
+                for b in 1..WU_SIDE {
+                    line = line + moments[wu_index(r, g, b)];
+                    area[b] = area[b] + line;
+                    moments[wu_index(r, g, b)] = moments[wu_index(r - 1, g, b)] + area[b];
+                }
+            }
+        }
+
+        let mut cubes = vec![WuBox {
+            lo: [0; 3],
+            hi: [WU_BINS; 3],
+        }];
+        let mut variances = vec![0.0];
+        let mut next = 0;
+        while cubes.len() < k {
+            let Some(upper) = wu_cut(&mut cubes[next], &moments) else {
+                variances[next] = 0.0;
+                match best_wu_box(&variances) {
+                    Some(index) => {
+                        next = index;
+                        continue;
+                    }
+                    None => break,
+                }
+            };
+            cubes.push(upper);
+            variances.push(0.0);
+            for index in [next, cubes.len() - 1] {
+                variances[index] = if cubes[index].volume() > 1 {
+                    wu_variance(&cubes[index], &moments)
+                } else {
+                    0.0
+                };
+            }
+            match best_wu_box(&variances) {
+                Some(index) => next = index,
+                None => break,
+            }
+        }
+
+        Ok(cubes
+            .iter()
+            .map(|cube| wu_volume(cube, &moments))
+            .filter(|volume| volume.weight > 0.0)
+            .map(|volume| volume.sum.map(|s| (s / volume.weight) as f32))
+            .collect())
+    }
+}
+
+/// Index of the box with the most variance left to split, if any has some.
+fn best_wu_box(variances: &[f64]) -> Option<usize> {
+    variances
+        .iter()
+        .enumerate()
+        .fold(None, |best: Option<(usize, f64)>, (i, &v)| match best {
+            Some(best) if best.1 >= v => Some(best),
+            _ => Some((i, v)),
+        })
+        .filter(|&(_, v)| v > 0.0)
+        .map(|(i, _)| i)
+}
+
+/// Number of points the automatic color count looks at.
+const AUTO_COLORS_SAMPLE: usize = 16_384;
+
+/// Picks a color count up to `max` for `points`, weighted by `weights` like
+/// [`Quantizer::weighted_palette`], measured on an evenly spaced sample of them.
+///
+/// Stops at the first count whose palette keeps the root mean squared error within
+/// `tolerance`, or at the elbow where one more color removes less than `min_gain` of the
+/// remaining squared error, which is where blended and noisy pixels start getting colors.
+pub(crate) fn auto_color_count(
+    points: &[[f32; 3]],
+    weights: Option<&[f32]>,
+    quantizer: &dyn Quantizer,
+    max: usize,
+    tolerance: f64,
+    min_gain: f64,
+) -> Result<usize> {
+    let stride = points.len().div_ceil(AUTO_COLORS_SAMPLE).max(1);
+    let sample: Vec<[f32; 3]> = points.iter().step_by(stride).copied().collect();
+    let sample_weights: Option<Vec<f32>> =
+        weights.map(|w| w.iter().step_by(stride).copied().collect());
+    let sample_weights = sample_weights.as_deref();
+    let total_weight =
+        sample_weights.map_or(sample.len() as f64, |w| w.iter().map(|&w| w as f64).sum());
+    let max = max.min(histogram(&sample, None).len()).max(1);
+
+    let mut previous_error = f64::MAX;
+    let mut previous_len = 0;
+    let mut previous_k = 1;
+    for k in 1..max {
+        let palette = quantizer.weighted_palette(&sample, sample_weights, k)?;
+        // Octree merges whole nodes, so several counts can give the same palette
+        if palette.len() <= previous_len {
+            continue;
+        }
+        previous_len = palette.len();
+        let error = sample
+            .iter()
+            .enumerate()
+            .map(|(index, p)| {
+                let weight = sample_weights.map_or(1.0, |w| w[index] as f64);
+                palette
+                    .iter()
+                    .map(|c| dist_sq(p, c))
+                    .fold(f32::MAX, f32::min) as f64
+                    * weight
+            })
+            .sum::<f64>()
+            / total_weight;
+        if error > previous_error * (1.0 - min_gain) {
+            return Ok(previous_k);
+        }
+        if error <= tolerance * tolerance {
+            return Ok(k);
+        }
+        previous_error = error;
+        previous_k = k;
+    }
+    Ok(max)
+}
+
+/// Colors of at least `min_cells` pixels of `cells` that are further than `distance` from
+/// every color of `palette`, most common first.
+///
+/// `cells` holds one pixel per grid cell. Nearby cell colors are grouped and each group is
+/// represented by its most common exact color.
+pub(crate) fn accent_colors(
+    cells: &RgbaImage,
+    palette: &[[f32; 3]],
+    space: ColorSpace,
+    min_cells: usize,
+    distance: f32,
+) -> Vec<[f32; 3]> {
+    struct Group {
+        seed: [f32; 3],
+        cells: usize,
+        colors: HashMap<[u8; 3], usize>,
+    }
+
+    let mut groups: Vec<Group> = Vec::new();
+    for pixel in cells.pixels().filter(|p| p[3] != 0) {
+        let rgb = [pixel[0], pixel[1], pixel[2]];
+        let point = space.encode(rgb);
+        let nearest = palette
+            .iter()
+            .map(|c| dist_sq(&point, c))
+            .fold(f32::MAX, f32::min);
+        if nearest <= distance * distance {
+            continue;
+        }
+
+        let radius = distance / 2.0;
+        let group = match groups
+            .iter_mut()
+            .find(|g| dist_sq(&g.seed, &point) <= radius * radius)
+        {
+            Some(group) => group,
+            None => {
+                groups.push(Group {
+                    seed: point,
+                    cells: 0,
+                    colors: HashMap::new(),
+                });
+                groups.last_mut().unwrap()
+            }
+        };
+        group.cells += 1;
+        *group.colors.entry(rgb).or_insert(0) += 1;
+    }
+
+    groups.retain(|g| g.cells >= min_cells);
+    groups.sort_by_key(|g| std::cmp::Reverse(g.cells));
+    groups
+        .iter()
+        .map(|g| {
+            let (rgb, _) = g
+                .colors
+                .iter()
+                .max_by_key(|&(rgb, count)| (*count, std::cmp::Reverse(*rgb)))
+                .unwrap();
+            space.encode(*rgb)
+        })
+        .collect()
+}
+
+/// Merges the closest colors of `colors`, weighted by their pixel counts, until they fit in
+/// `k` alongside `accents`, then appends the accents. Keeps at least one regular color.
+pub(crate) fn merge_with_accents(
+    mut colors: Vec<([f32; 3], f64)>,
+    accents: &[[f32; 3]],
+    k: usize,
+) -> Vec<[f32; 3]> {
+    let accents = &accents[..accents.len().min(k.saturating_sub(1))];
+    while colors.len() > 1 && colors.len() + accents.len() > k {
+        // Ward's criterion: merging two small clusters costs less than moving a big one
+        let mut best = (f64::MAX, 0, 1);
+        for i in 0..colors.len() {
+            for j in i + 1..colors.len() {
+                let (wi, wj) = (colors[i].1, colors[j].1);
+                let cost = wi * wj / (wi + wj).max(f64::MIN_POSITIVE)
+                    * dist_sq(&colors[i].0, &colors[j].0) as f64;
+                if cost < best.0 {
+                    best = (cost, i, j);
+                }
+            }
+        }
+
+        let (_, i, j) = best;
+        let (b, wb) = colors.remove(j);
+        let (a, wa) = colors[i];
+        let total = (wa + wb).max(f64::MIN_POSITIVE);
+        let merged =
+            std::array::from_fn(|c| ((a[c] as f64 * wa + b[c] as f64 * wb) / total) as f32);
+        colors[i] = (merged, wa + wb);
+    }
+
+    colors
+        .into_iter()
+        .map(|(color, _)| color)
+        .chain(accents.iter().copied())
+        .collect()
+}
+
+/// Maps points to integer cells of a `size` wide grid spanning their bounding box, so the
+/// histogram based quantizers work in any color space.
+struct Bins {
+    min: [f32; 3],
+    scale: [f32; 3],
+    size: usize,
+}
+
+impl Bins {
+    fn new(colors: &[([f32; 3], f64)], size: usize) -> Self {
+        let mut min = [f32::MAX; 3];
+        let mut max = [f32::MIN; 3];
+        for (color, _) in colors {
+            for axis in 0..3 {
+                min[axis] = min[axis].min(color[axis]);
+                max[axis] = max[axis].max(color[axis]);
+            }
+        }
+        let scale = std::array::from_fn(|axis| {
+            let range = max[axis] - min[axis];
+            if range > 0.0 {
+                size as f32 / range
+            } else {
+                0.0
+            }
+        });
+        Self { min, scale, size }
+    }
+
+    fn coords(&self, color: &[f32; 3]) -> [usize; 3] {
+        std::array::from_fn(|axis| {
+            let bin = ((color[axis] - self.min[axis]) * self.scale[axis]) as usize;
+            bin.min(self.size - 1)
+        })
+    }
+}
+
+/// Distinct points with their total weight, the number of occurrences without `weights`, in
+/// a fixed order.
+fn histogram(points: &[[f32; 3]], weights: Option<&[f32]>) -> Vec<([f32; 3], f64)> {
+    let mut counts: HashMap<[u32; 3], f64> = HashMap::new();
+    for (index, point) in points.iter().enumerate() {
+        let weight = weights.map_or(1.0, |w| w[index] as f64);
+        *counts.entry(point.map(f32::to_bits)).or_insert(0.0) += weight;
+    }
+    let mut colors: Vec<([u32; 3], f64)> = counts.into_iter().collect();
+    colors.sort_unstable_by_key(|(bits, _)| *bits);
+    colors
+        .into_iter()
+        .map(|(bits, count)| (bits.map(f32::from_bits), count))
+        .collect()
+}
+
+fn widest_axis(colors: &[([f32; 3], f64)]) -> (usize, f32) {
+    (0..3)
+        .map(|axis| {
+            let (min, max) = colors.iter().fold((f32::MAX, f32::MIN), |(min, max), c| {
+                (min.min(c.0[axis]), max.max(c.0[axis]))
+            });
+            (axis, max - min)
+        })
+        .fold((0, f32::MIN), |best, candidate| {
+            if candidate.1 > best.1 {
+                candidate
+            } else {
+                best
+            }
+        })
+}
+
+fn weighted_mean(colors: &[([f32; 3], f64)]) -> [f32; 3] {
+    let mut sum = [0.0f64; 3];
+    let mut total = 0.0;
+    for (color, count) in colors {
+        for (s, value) in sum.iter_mut().zip(color) {
+            *s += *value as f64 * count;
+        }
+        total += count;
+    }
+    sum.map(|s| (s / total) as f32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Three tight clusters with very different sizes, like a sprite with a small accent.
+    fn clustered_points() -> Vec<[f32; 3]> {
+        let mut points = Vec::new();
+        for i in 0..600 {
+            points.push([20.0 + (i % 5) as f32, 40.0, 60.0]);
+        }
+        for i in 0..300 {
+            points.push([200.0, 180.0 + (i % 3) as f32, 40.0]);
+        }
+        for _ in 0..12 {
+            points.push([230.0, 20.0, 30.0]);
+        }
+        points
+    }
+
+    fn quantizers() -> [(&'static str, Box<dyn Quantizer>); 4] {
+        [
+            ("kmeans", Box::new(KMeans::default())),
+            ("median-cut", Box::new(MedianCut)),
+            ("octree", Box::new(Octree)),
+            ("wu", Box::new(Wu)),
+        ]
+    }
+
+    #[test]
+    fn quantizers_find_every_cluster() {
+        let points = clustered_points();
+        for (name, quantizer) in quantizers() {
+            let palette = quantizer.palette(&points, 3).unwrap();
+            assert!(palette.len() <= 3, "{}", name);
+            for center in [
+                [22.0, 40.0, 60.0],
+                [200.0, 181.0, 40.0],
+                [230.0, 20.0, 30.0],
+            ] {
+                let nearest = palette
+                    .iter()
+                    .map(|c| dist_sq(c, &center))
+                    .fold(f32::MAX, f32::min);
+                assert!(
+                    nearest < 25.0,
+                    "{} missed {:?}: {:?}",
+                    name,
+                    center,
+                    palette
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn auto_color_count_stops_at_the_clusters() {
+        let points = clustered_points();
+        let k = auto_color_count(&points, None, &KMeans::default(), 16, 4.0, 0.1).unwrap();
+        assert_eq!(k, 3);
+        let capped = auto_color_count(&points, None, &KMeans::default(), 2, 4.0, 0.1).unwrap();
+        assert_eq!(capped, 2);
+    }
+
+    #[test]
+    fn merging_makes_room_for_accents() {
+        let colors = vec![
+            ([10.0, 10.0, 10.0], 100.0),
+            ([12.0, 10.0, 10.0], 5.0),
+            ([200.0, 200.0, 200.0], 50.0),
+        ];
+        let accents = [[250.0, 0.0, 0.0]];
+        let palette = merge_with_accents(colors, &accents, 3);
+        assert_eq!(palette.len(), 3);
+        assert_eq!(palette[1], [200.0, 200.0, 200.0]);
+        assert_eq!(palette[2], accents[0]);
+        assert!((palette[0][0] - 1060.0 / 105.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn weights_count_like_repeated_points() {
+        let points = [[0.0, 0.0, 0.0], [100.0, 0.0, 0.0]];
+        for (name, quantizer) in quantizers() {
+            let palette = quantizer
+                .weighted_palette(&points, Some(&[3.0, 1.0]), 1)
+                .unwrap();
+            assert_eq!(palette, vec![[25.0, 0.0, 0.0]], "{}", name);
+        }
+    }
+
+    #[test]
+    fn quantizers_are_deterministic() {
+        let points = clustered_points();
+        for (name, quantizer) in quantizers() {
+            assert_eq!(
+                quantizer.palette(&points, 2).unwrap(),
+                quantizer.palette(&points, 2).unwrap(),
+                "{}",
+                name
+            );
+        }
+    }
+}